@@ -13,10 +13,12 @@ use crate::common::{common_bootstrap, parse_find_multiple_collections};
 use crate::dav::{check_status, DavError, FoundCollection};
 use crate::dns::DiscoverableService;
 use crate::names::{
-    self, CALENDAR, CALENDAR_COLOUR, CALENDAR_DATA, CALENDAR_HOME_SET, GETETAG, RESOURCETYPE,
+    self, CALENDAR, CALENDAR_DATA, CALENDAR_HOME_SET, GETCTAG, GETETAG, RESOURCETYPE,
     SUPPORTED_REPORT_SET,
 };
-use crate::xmlutils::{check_multistatus, quote_href};
+use crate::property::CalendarColour;
+use crate::query::{CalendarQuery, QueryResult};
+use crate::xmlutils::quote_href;
 use crate::{dav::WebDavClient, BootstrapError, FindHomeSetError};
 use crate::{CheckSupportError, FetchedResource};
 
@@ -144,7 +146,11 @@ impl CalDavClient {
     ) -> Result<Vec<FoundCollection>, DavError> {
         let url = url.unwrap_or(self.calendar_home_set.as_ref().unwrap_or(&self.base_url));
         let (head, body) = self
-            .propfind(url, &[&RESOURCETYPE, &GETETAG, &SUPPORTED_REPORT_SET], 1)
+            .propfind(
+                url,
+                &[&RESOURCETYPE, &GETETAG, &GETCTAG, &SUPPORTED_REPORT_SET],
+                1,
+            )
             .await?;
         check_status(head.status)?;
 
@@ -153,45 +159,13 @@ impl CalDavClient {
 
     /// Returns the colour for the calendar at path `href`.
     ///
-    /// This is not a formally standardised property, but is relatively widespread.
-    ///
-    /// # Quirks
-    ///
-    /// The namespace of the value in the response from the server is ignored. This is a workaround
-    /// for an [issue in the `cyrus-imapd` implemenetation][cyrus-issue].
-    ///
-    /// [cyrus-issue]: https://github.com/cyrusimap/cyrus-imapd/issues/4489
+    /// See [`CalendarColour`] for details.
     ///
     /// # Errors
     ///
     /// If the network request fails, or if the response cannot be parsed.
     pub async fn get_calendar_colour(&self, href: &str) -> Result<Option<String>, DavError> {
-        let url = self.relative_uri(href)?;
-
-        let (head, body) = self.propfind(&url, &[&CALENDAR_COLOUR], 0).await?;
-        check_status(head.status)?;
-
-        let body = std::str::from_utf8(body.as_ref())?;
-        let doc = roxmltree::Document::parse(body)?;
-        let root = doc.root_element();
-
-        let props = root
-            .descendants()
-            .filter(|node| {
-                // Ignoring namespace as workaround for https://github.com/cyrusimap/cyrus-imapd/issues/4489
-                node.tag_name().name() == "calendar-color"
-            })
-            .collect::<Vec<_>>();
-
-        if props.len() == 1 {
-            return Ok(props[0].text().map(str::to_string));
-        }
-
-        check_multistatus(root)?;
-
-        Err(DavError::InvalidResponse(
-            "missing property in response with no error".into(),
-        ))
+        self.get_property::<CalendarColour>(href).await
     }
 
     /// Sets the `colour` for a collection
@@ -207,15 +181,10 @@ impl CalDavClient {
         href: &str,
         colour: Option<&str>,
     ) -> Result<(), DavError> {
-        let url = self.relative_uri(href)?;
-        self.propupdate(&url, &CALENDAR_COLOUR, colour).await
+        self.set_property::<CalendarColour>(href, colour.map(String::from).as_ref())
+            .await
     }
 
-    // TODO: get_calendar_description ("calendar-description", "urn:ietf:params:xml:ns:caldav")
-    // TODO: get_calendar_order ("calendar-order", "http://apple.com/ns/ical/")
-    // TODO: DRY: the above methods are super repetitive.
-    //       Maybe all these props impl a single trait, so the API could be `get_prop<T>(url)`?
-
     /// Fetches existing icalendar resources.
     ///
     /// # Errors
@@ -250,6 +219,24 @@ impl CalDavClient {
             .await
     }
 
+    /// Queries resources in a calendar using a `calendar-query` report.
+    ///
+    /// Returns the `href` and etag of each matching resource, along with its data if requested
+    /// via [`CalendarQuery::with_data`]. Servers may truncate results; see
+    /// [`QueryResult::truncated`].
+    ///
+    /// # Errors
+    ///
+    /// If there are any network errors or the response could not be parsed.
+    pub async fn calendar_query(
+        &self,
+        calendar_href: &str,
+        query: &CalendarQuery,
+    ) -> Result<QueryResult, DavError> {
+        self.query(calendar_href, query.render(), &CALENDAR_DATA)
+            .await
+    }
+
     /// Checks that the given URI advertises caldav support.
     ///
     /// See: <https://www.rfc-editor.org/rfc/rfc4791#section-5.1>
@@ -13,9 +13,10 @@ use crate::common::{common_bootstrap, parse_find_multiple_collections};
 use crate::dav::{check_status, DavError, FoundCollection};
 use crate::dns::DiscoverableService;
 use crate::names::{
-    self, ADDRESSBOOK, ADDRESSBOOK_HOME_SET, ADDRESS_DATA, GETETAG, RESOURCETYPE,
+    self, ADDRESSBOOK, ADDRESSBOOK_HOME_SET, ADDRESS_DATA, GETCTAG, GETETAG, RESOURCETYPE,
     SUPPORTED_REPORT_SET,
 };
+use crate::query::{AddressBookQuery, QueryResult};
 use crate::xmlutils::quote_href;
 use crate::{dav::WebDavClient, BootstrapError, FindHomeSetError};
 use crate::{CheckSupportError, FetchedResource};
@@ -138,7 +139,11 @@ impl CardDavClient {
         let url = url.unwrap_or(self.addressbook_home_set.as_ref().unwrap_or(&self.base_url));
         // FIXME: DRY: This is almost a copy-paste of the same method from CalDavClient
         let (head, body) = self
-            .propfind(url, &[&RESOURCETYPE, &GETETAG, &SUPPORTED_REPORT_SET], 1)
+            .propfind(
+                url,
+                &[&RESOURCETYPE, &GETETAG, &GETCTAG, &SUPPORTED_REPORT_SET],
+                1,
+            )
             .await?;
         check_status(head.status)?;
 
@@ -183,6 +188,24 @@ impl CardDavClient {
             .await
     }
 
+    /// Queries resources in an address book using an `addressbook-query` report.
+    ///
+    /// Returns the `href` and etag of each matching resource, along with its data if requested
+    /// via [`AddressBookQuery::with_data`] or [`AddressBookQuery::with_properties`]. Servers may
+    /// truncate results; see [`QueryResult::truncated`].
+    ///
+    /// # Errors
+    ///
+    /// If there are any network errors or the response could not be parsed.
+    pub async fn addressbook_query(
+        &self,
+        addressbook_href: &str,
+        query: &AddressBookQuery,
+    ) -> Result<QueryResult, DavError> {
+        self.query(addressbook_href, query.render(), &ADDRESS_DATA)
+            .await
+    }
+
     /// Checks that the given URI advertises carddav support.
     ///
     /// See: <https://www.rfc-editor.org/rfc/rfc6352#section-6.1>
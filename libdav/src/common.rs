@@ -100,6 +100,10 @@ pub(crate) fn parse_find_multiple_collections<B: AsRef<[u8]>>(
             .descendants()
             .find(|node| node.tag_name() == names::GETETAG)
             .and_then(|node| node.text().map(str::to_string));
+        let ctag = response
+            .descendants()
+            .find(|node| node.tag_name() == names::GETCTAG)
+            .and_then(|node| node.text().map(str::to_string));
         let supports_sync = response
             .descendants()
             .find(|node| node.tag_name() == names::SUPPORTED_REPORT_SET)
@@ -111,9 +115,53 @@ pub(crate) fn parse_find_multiple_collections<B: AsRef<[u8]>>(
         items.push(FoundCollection {
             href,
             etag,
+            ctag,
             supports_sync,
         });
     }
 
     Ok(items)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{common::parse_find_multiple_collections, names};
+
+    #[test]
+    fn test_parse_find_multiple_collections() {
+        let raw = br#"
+<multistatus xmlns="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav" xmlns:CS="http://calendarserver.org/ns/">
+  <response>
+    <href>/calendars/user/</href>
+    <propstat>
+      <prop>
+        <resourcetype><collection/></resourcetype>
+      </prop>
+      <status>HTTP/1.1 200 OK</status>
+    </propstat>
+  </response>
+  <response>
+    <href>/calendars/user/work/</href>
+    <propstat>
+      <prop>
+        <resourcetype><collection/><C:calendar/></resourcetype>
+        <getetag>"etag-1"</getetag>
+        <CS:getctag>ctag-1</CS:getctag>
+        <supported-report-set>
+          <supported-report><report><sync-collection/></report></supported-report>
+        </supported-report-set>
+      </prop>
+      <status>HTTP/1.1 200 OK</status>
+    </propstat>
+  </response>
+</multistatus>"#;
+
+        let found = parse_find_multiple_collections(raw, &names::CALENDAR).unwrap();
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].href, "/calendars/user/work/");
+        assert_eq!(found[0].etag.as_deref(), Some("\"etag-1\""));
+        assert_eq!(found[0].ctag.as_deref(), Some("ctag-1"));
+        assert!(found[0].supports_sync);
+    }
+}
@@ -20,12 +20,17 @@ use crate::{
     auth::AuthExt,
     dns::DiscoverableService,
     names::{
-        ADDRESSBOOK, CALENDAR, COLLECTION, CURRENT_USER_PRINCIPAL, DISPLAY_NAME, GETCONTENTTYPE,
-        GETETAG, HREF, PROPSTAT, RESOURCETYPE, RESPONSE,
+        ADDRESSBOOK, CALENDAR, COLLECTION, CURRENT_USER_PRINCIPAL, GETCONTENTTYPE, GETETAG, HREF,
+        PROPSTAT, RESOURCETYPE, RESPONSE, STATUS, SYNC_TOKEN,
     },
+    property::{
+        parse_property_results, render_propertyupdate, DisplayName, Property, PropertyResults,
+        PropertyUpdate,
+    },
+    query::{query_parse, QueryResult},
     xmlutils::{
-        check_multistatus, get_newline_corrected_text, get_unquoted_href, quote_href, render_xml,
-        render_xml_with_text,
+        check_multistatus, get_newline_corrected_text, get_unquoted_href, parse_statusline,
+        quote_href, render_xml, render_xml_with_text,
     },
     Auth, AuthError, FetchedResource, FetchedResourceContent, ItemDetails, ResourceType,
 };
@@ -275,66 +280,126 @@ impl WebDavClient {
     ///
     /// If there are any network errors or the response could not be parsed.
     pub async fn get_collection_displayname(&self, href: &str) -> Result<Option<String>, DavError> {
+        self.get_property::<DisplayName>(href).await
+    }
+
+    /// Returns the value of a single property of the resource at path `href`.
+    ///
+    /// Returns `None` if the property is not defined for the resource.
+    ///
+    /// # Errors
+    ///
+    /// If there are any network errors, if the response could not be parsed, or if the server
+    /// returns an error status for the property.
+    pub async fn get_property<P: Property>(
+        &self,
+        href: &str,
+    ) -> Result<Option<P::Value>, DavError> {
+        self.get_properties(href, &[&P::NAME]).await?.value::<P>()
+    }
+
+    /// Fetches multiple properties of the resource at path `href` with a single `PROPFIND`.
+    ///
+    /// Use [`PropertyResults::value`] to obtain typed values from the result.
+    ///
+    /// # Errors
+    ///
+    /// If there are any network errors or the response could not be parsed. Errors for individual
+    /// properties are included in the result.
+    pub async fn get_properties(
+        &self,
+        href: &str,
+        properties: &[&ExpandedName<'_, '_>],
+    ) -> Result<PropertyResults, DavError> {
         let url = self.relative_uri(href)?;
 
-        let (head, body) = self.propfind(&url, &[&DISPLAY_NAME], 0).await?;
+        let (head, body) = self.propfind(&url, properties, 0).await?;
         check_status(head.status)?;
 
-        parse_prop(body, &DISPLAY_NAME)
+        parse_property_results(body)
     }
 
     /// Sends a `PROPUPDATE` query to the server.
     ///
     /// # Errors
     ///
-    /// If there are any network errors or the response could not be parsed.
+    /// If there are any network errors, the response could not be parsed, or the server does not
+    /// return a success status for the property.
     pub async fn propupdate(
         &self,
         url: &Uri,
         property: &ExpandedName<'_, '_>,
         value: Option<&str>,
     ) -> Result<(), DavError> {
-        let action = match value {
-            Some(_) => "set",
-            None => "remove",
+        let results = self
+            .proppatch(url, render_propertyupdate([(property, value)]))
+            .await?;
+
+        if !results.iter().any(|s| s.is(property)) {
+            return Err(DavError::InvalidResponse(
+                "missing property in response but no error".into(),
+            ));
+        }
+        results.check()
+    }
+
+    /// Sets or removes (if `value` is `None`) a single property of the resource at path `href`.
+    ///
+    /// # Errors
+    ///
+    /// If there are any network errors, the response could not be parsed, or the server does not
+    /// return a success status for the property.
+    pub async fn set_property<P: Property>(
+        &self,
+        href: &str,
+        value: Option<&P::Value>,
+    ) -> Result<(), DavError> {
+        let update = match value {
+            Some(value) => PropertyUpdate::set::<P>(value),
+            None => PropertyUpdate::remove::<P>(),
         };
-        let inner = render_xml_with_text(property, value);
+        let results = self.update_properties(href, &[update]).await?;
+
+        if results.get::<P>().is_none() {
+            return Err(DavError::InvalidResponse(
+                "missing property in response but no error".into(),
+            ));
+        }
+        results.check()
+    }
+
+    /// Sets or removes multiple properties of the resource at path `href` with a single
+    /// `PROPPATCH`.
+    ///
+    /// Updates are applied in order. Servers apply either all updates or none of them; use
+    /// [`PropertyResults::check`] to determine whether the update succeeded.
+    ///
+    /// # Errors
+    ///
+    /// If there are any network errors or the response could not be parsed. Errors for individual
+    /// properties are included in the result.
+    pub async fn update_properties(
+        &self,
+        href: &str,
+        updates: &[PropertyUpdate],
+    ) -> Result<PropertyResults, DavError> {
+        let url = self.relative_uri(href)?;
+        let body = render_propertyupdate(updates.iter().map(PropertyUpdate::parts));
+        self.proppatch(&url, body).await
+    }
+
+    async fn proppatch(&self, url: &Uri, body: String) -> Result<PropertyResults, DavError> {
         let request = self
             .request_builder()?
             .method(Method::from_bytes(b"PROPPATCH").expect("ugh"))
             .uri(url)
             .header("Content-Type", "application/xml; charset=utf-8")
-            .body(Body::from(format!(
-                r#"<propertyupdate xmlns="DAV:">
-                <{action}>
-                    <prop>
-                        {inner}
-                    </prop>
-                </{action}>
-            </propertyupdate>"#
-            )))?;
+            .body(Body::from(body))?;
 
         let (head, body) = self.request(request).await?;
         check_status(head.status)?;
 
-        let body = std::str::from_utf8(body.as_ref())?;
-        let doc = roxmltree::Document::parse(body)?;
-        let root = doc.root_element();
-
-        let props = root
-            .descendants()
-            .filter(|node| node.tag_name() == *property)
-            .collect::<Vec<_>>();
-
-        if props.len() == 1 {
-            return Ok(());
-        }
-
-        check_multistatus(root)?;
-
-        Err(DavError::InvalidResponse(
-            "missing property in response but no error".into(),
-        ))
+        parse_property_results(body)
     }
 
     /// Sets the `displayname` for a collection
@@ -349,8 +414,8 @@ impl WebDavClient {
         href: &str,
         displayname: Option<&str>,
     ) -> Result<(), DavError> {
-        let url = self.relative_uri(href)?;
-        self.propupdate(&url, &DISPLAY_NAME, displayname).await
+        self.set_property::<DisplayName>(href, displayname.map(String::from).as_ref())
+            .await
     }
 
     /// Resolve the default context path using a well-known path.
@@ -436,6 +501,52 @@ impl WebDavClient {
         list_resources_parse(body, collection_href)
     }
 
+    /// Enumerates resources in a collection which changed since a previous synchronisation.
+    ///
+    /// Executes a `sync-collection` report. If `sync_token` is `None`, all resources in the
+    /// collection are returned as changed. Otherwise, only resources created, modified or deleted
+    /// since the token was returned are included.
+    ///
+    /// Servers may truncate results; see [`SyncCollectionResult::truncated`].
+    ///
+    /// # Errors
+    ///
+    /// If there are any network errors or the response could not be parsed. If the server does
+    /// not recognise the token (e.g.: because it has expired), it returns an error status
+    /// (usually `403 Forbidden`), in which case a full listing is required.
+    ///
+    /// # See also
+    ///
+    /// - <https://www.rfc-editor.org/rfc/rfc6578#section-3.2>
+    pub async fn sync_collection(
+        &self,
+        collection_href: &str,
+        sync_token: Option<&str>,
+    ) -> Result<SyncCollectionResult, DavError> {
+        let token = render_xml_with_text(&SYNC_TOKEN, Some(sync_token.unwrap_or_default()));
+        let request = self
+            .request_builder()?
+            .method("REPORT")
+            .uri(self.relative_uri(collection_href)?)
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(Body::from(format!(
+                r#"<sync-collection xmlns="DAV:">
+                    {token}
+                    <sync-level>1</sync-level>
+                    <prop>
+                        <resourcetype/>
+                        <getcontenttype/>
+                        <getetag/>
+                    </prop>
+                </sync-collection>"#
+            )))?;
+
+        let (head, body) = self.request(request).await?;
+        check_status(head.status)?;
+
+        sync_collection_parse(body, collection_href)
+    }
+
     /// Inner helper with common logic between `create` and `update`.
     async fn put<Href, Etag, MimeType>(
         &self,
@@ -648,6 +759,27 @@ impl WebDavClient {
 
         multi_get_parse(body, property)
     }
+
+    /// Sends a `REPORT` request which queries resources in a collection.
+    pub(crate) async fn query(
+        &self,
+        collection_href: &str,
+        body: String,
+        property: &ExpandedName<'_, '_>,
+    ) -> Result<QueryResult, DavError> {
+        let request = self
+            .request_builder()?
+            .method("REPORT")
+            .uri(self.relative_uri(collection_href)?)
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .header("Depth", "1")
+            .body(Body::from(body))?;
+
+        let (head, body) = self.request(request).await?;
+        check_status(head.status)?;
+
+        query_parse(body, collection_href, property)
+    }
 }
 
 #[inline]
@@ -675,15 +807,36 @@ pub struct ListedResource {
     pub href: String,
 }
 
+/// Changes to a collection returned by [`WebDavClient::sync_collection`].
+#[derive(Debug, PartialEq)]
+pub struct SyncCollectionResult {
+    /// Resources that were created or modified. Collections inside the collection are omitted.
+    pub changed: Vec<ListedResource>,
+    /// Hrefs of resources that were deleted. These values are not URL-encoded.
+    pub deleted: Vec<String>,
+    /// Token to be used to request further changes.
+    pub sync_token: String,
+    /// Whether the server truncated results.
+    ///
+    /// If true, further changes should be requested using the new `sync_token`.
+    pub truncated: bool,
+}
+
 /// Metadata for a collection.
 ///
 /// This type is returned when listing collections. It contains metadata on
 /// collection itself, but not the entires themselves.
 #[derive(Debug)]
+#[non_exhaustive]
 pub struct FoundCollection {
     /// This value is not URL-encoded.
     pub href: String,
     pub etag: Option<String>,
+    /// The `getctag` property, which changes whenever the collection or its members change.
+    ///
+    /// This is not standardised but widely supported. Note that the collection's `etag` is not
+    /// required to change when its members change, so it cannot be used in its place.
+    pub ctag: Option<String>,
     pub supports_sync: bool,
     // TODO: query displayname by default too.
 }
@@ -727,30 +880,6 @@ pub(crate) fn parse_prop_href<B: AsRef<[u8]>>(
     ))
 }
 
-fn parse_prop<B: AsRef<[u8]>>(
-    body: B,
-    property: &ExpandedName<'_, '_>,
-) -> Result<Option<String>, DavError> {
-    let body = std::str::from_utf8(body.as_ref())?;
-    let doc = roxmltree::Document::parse(body)?;
-    let root = doc.root_element();
-
-    let props = root
-        .descendants()
-        .filter(|node| node.tag_name() == *property)
-        .collect::<Vec<_>>();
-
-    if props.len() == 1 {
-        return Ok(props[0].text().map(str::to_string));
-    }
-
-    check_multistatus(root)?;
-
-    Err(DavError::InvalidResponse(
-        "missing property in response but no error".into(),
-    ))
-}
-
 fn list_resources_parse<B: AsRef<[u8]>>(
     body: B,
     collection_href: &str,
@@ -808,6 +937,86 @@ fn list_resources_parse<B: AsRef<[u8]>>(
     Ok(items)
 }
 
+fn sync_collection_parse<B: AsRef<[u8]>>(
+    body: B,
+    collection_href: &str,
+) -> Result<SyncCollectionResult, DavError> {
+    let body = std::str::from_utf8(body.as_ref())?;
+    let doc = roxmltree::Document::parse(body)?;
+    let root = doc.root_element();
+
+    let mut result = SyncCollectionResult {
+        changed: Vec::new(),
+        deleted: Vec::new(),
+        sync_token: root
+            .children()
+            .find(|node| node.tag_name() == SYNC_TOKEN)
+            .and_then(|node| node.text())
+            .ok_or(DavError::MissingData("sync-token"))?
+            .trim()
+            .to_string(),
+        truncated: false,
+    };
+
+    let responses = root.children().filter(|node| node.tag_name() == RESPONSE);
+    for response in responses {
+        let href = get_unquoted_href(&response)?.to_string();
+
+        // Responses with a status outside of a `propstat` refer to the resource itself.
+        let status = response
+            .children()
+            .find(|node| node.tag_name() == STATUS)
+            .map(|node| {
+                let text = node.text().ok_or(DavError::InvalidResponse(
+                    "missing text inside 'DAV:status'".into(),
+                ))?;
+                parse_statusline(text).map_err(DavError::from)
+            })
+            .transpose()?;
+        match status {
+            Some(StatusCode::NOT_FOUND) => result.deleted.push(href),
+            Some(StatusCode::INSUFFICIENT_STORAGE) => result.truncated = true,
+            Some(status) if !status.is_success() => return Err(DavError::BadStatusCode(status)),
+            _ if href == collection_href => {}
+            _ => {
+                let etag = response
+                    .descendants()
+                    .find(|node| node.tag_name() == GETETAG)
+                    .and_then(|node| node.text().map(str::to_string));
+                let content_type = response
+                    .descendants()
+                    .find(|node| node.tag_name() == GETCONTENTTYPE)
+                    .and_then(|node| node.text().map(str::to_string));
+                let resource_type = response
+                    .descendants()
+                    .find(|node| node.tag_name() == RESOURCETYPE)
+                    .map(|r| ResourceType {
+                        is_calendar: r.descendants().any(|n| n.tag_name() == CALENDAR),
+                        is_collection: r.descendants().any(|n| n.tag_name() == COLLECTION),
+                        is_address_book: r.descendants().any(|n| n.tag_name() == ADDRESSBOOK),
+                    })
+                    .unwrap_or_default();
+                // Sub-collections are not items.
+                if resource_type.is_collection {
+                    continue;
+                }
+
+                result.changed.push(ListedResource {
+                    details: ItemDetails {
+                        content_type,
+                        etag,
+                        resource_type,
+                        supports_sync: false,
+                    },
+                    href,
+                });
+            }
+        }
+    }
+
+    Ok(result)
+}
+
 fn multi_get_parse<B: AsRef<[u8]>>(
     body: B,
     property: &ExpandedName<'_, '_>,
@@ -887,8 +1096,12 @@ mod more_tests {
     use http::{StatusCode, Uri};
 
     use crate::{
-        dav::{list_resources_parse, multi_get_parse, parse_prop, parse_prop_href, ListedResource},
-        names::{CALENDAR_COLOUR, CALENDAR_DATA, CURRENT_USER_PRINCIPAL, DISPLAY_NAME},
+        dav::{
+            list_resources_parse, multi_get_parse, parse_prop_href, sync_collection_parse,
+            ListedResource, SyncCollectionResult,
+        },
+        names::{CALENDAR_DATA, CURRENT_USER_PRINCIPAL},
+        property::{parse_property_results, CalendarColour, DisplayName},
         FetchedResource, FetchedResourceContent, ItemDetails, ResourceType,
     };
 
@@ -1069,9 +1282,12 @@ mod more_tests {
             </multistatus>
             "#;
 
-        let results = parse_prop(raw, &DISPLAY_NAME).unwrap();
+        let results = parse_property_results(raw).unwrap();
 
-        assert_eq!(results, Some("test calendar".into()));
+        assert_eq!(
+            results.value::<DisplayName>().unwrap(),
+            Some("test calendar".into())
+        );
     }
 
     #[test]
@@ -1089,9 +1305,67 @@ mod more_tests {
   </ns0:response>
 </ns0:multistatus>"#;
 
-        let results = parse_prop(raw, &CALENDAR_COLOUR).unwrap();
-        assert_eq!(results, Some("#ff00ff".into()));
+        let results = parse_property_results(raw).unwrap();
+        assert_eq!(
+            results.value::<CalendarColour>().unwrap(),
+            Some("#ff00ff".into())
+        );
+
+        results.value::<DisplayName>().unwrap_err();
+    }
+
+    #[test]
+    fn test_sync_collection_parse() {
+        let raw = br#"
+<multistatus xmlns="DAV:">
+  <response>
+    <href>/calendars/user/work/new.ics</href>
+    <propstat>
+      <prop>
+        <getetag>"00001-abcd1"</getetag>
+      </prop>
+      <status>HTTP/1.1 200 OK</status>
+    </propstat>
+  </response>
+  <response>
+    <href>/calendars/user/work/old%20event.ics</href>
+    <status>HTTP/1.1 404 Not Found</status>
+  </response>
+  <response>
+    <href>/calendars/user/work/nested/</href>
+    <propstat>
+      <prop>
+        <resourcetype><collection/></resourcetype>
+        <getetag>"00001-abcd2"</getetag>
+      </prop>
+      <status>HTTP/1.1 200 OK</status>
+    </propstat>
+  </response>
+  <response>
+    <href>/calendars/user/work/</href>
+    <status>HTTP/1.1 507 Insufficient Storage</status>
+  </response>
+  <sync-token>http://example.com/ns/sync/1235</sync-token>
+</multistatus>"#;
+
+        let result = sync_collection_parse(raw, "/calendars/user/work/").unwrap();
 
-        parse_prop(raw, &DISPLAY_NAME).unwrap_err();
+        assert_eq!(
+            result,
+            SyncCollectionResult {
+                changed: vec![ListedResource {
+                    details: ItemDetails {
+                        content_type: None,
+                        etag: Some("\"00001-abcd1\"".into()),
+                        resource_type: ResourceType::default(),
+                        supports_sync: false,
+                    },
+                    href: "/calendars/user/work/new.ics".into(),
+                }],
+                deleted: vec!["/calendars/user/work/old event.ics".into()],
+                sync_token: "http://example.com/ns/sync/1235".into(),
+                truncated: true,
+            }
+        );
     }
 }
@@ -32,6 +32,8 @@ mod common;
 pub mod dav;
 pub mod dns;
 pub mod names;
+pub mod property;
+pub mod query;
 pub mod xmlutils;
 
 pub use caldav::CalDavClient;
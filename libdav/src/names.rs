@@ -18,6 +18,7 @@ pub const DISPLAY_NAME: ExpandedName = ExpandedName::from_static(DAV, "displayna
 pub const GETCONTENTTYPE: ExpandedName = ExpandedName::from_static(DAV, "getcontenttype");
 pub const GETETAG: ExpandedName = ExpandedName::from_static(DAV, "getetag");
 pub const HREF: ExpandedName = ExpandedName::from_static(DAV, "href");
+pub const PROP: ExpandedName = ExpandedName::from_static(DAV, "prop");
 pub const RESOURCETYPE: ExpandedName = ExpandedName::from_static(DAV, "resourcetype");
 pub const RESPONSE: ExpandedName = ExpandedName::from_static(DAV, "response");
 pub const STATUS: ExpandedName = ExpandedName::from_static(DAV, "status");
@@ -25,6 +26,7 @@ pub const PROPSTAT: ExpandedName = ExpandedName::from_static(DAV, "propstat");
 pub const SUPPORTED_REPORT_SET: ExpandedName =
     ExpandedName::from_static(DAV, "supported-report-set");
 pub const SYNC_COLLECTION: ExpandedName = ExpandedName::from_static(DAV, "sync-collection");
+pub const SYNC_TOKEN: ExpandedName = ExpandedName::from_static(DAV, "sync-token");
 pub const CURRENT_USER_PRINCIPAL: ExpandedName =
     ExpandedName::from_static(DAV, "current-user-principal");
 
@@ -34,8 +36,24 @@ pub const CALENDAR_HOME_SET: ExpandedName = ExpandedName::from_static(CALDAV, "c
 pub const CALENDAR_COLOUR: ExpandedName =
     ExpandedName::from_static("http://apple.com/ns/ical/", "calendar-color");
 pub const CALENDAR_DATA: ExpandedName = ExpandedName::from_static(CALDAV, "calendar-data");
+/// Defined in <https://www.rfc-editor.org/rfc/rfc4791#section-5.2.1>
+pub const CALENDAR_DESCRIPTION: ExpandedName =
+    ExpandedName::from_static(CALDAV, "calendar-description");
+pub const CALENDAR_ORDER: ExpandedName =
+    ExpandedName::from_static("http://apple.com/ns/ical/", "calendar-order");
+
+/// Namespace for properties defined by Apple's calendar server (and used by many others).
+pub const CALENDARSERVER: &str = "http://calendarserver.org/ns/";
+
+/// A tag which changes whenever a collection or its members change.
+///
+/// See: <https://github.com/apple/ccs-calendarserver/blob/master/doc/Extensions/caldav-ctag.txt>
+pub const GETCTAG: ExpandedName = ExpandedName::from_static(CALENDARSERVER, "getctag");
 
 pub const ADDRESSBOOK: ExpandedName = ExpandedName::from_static(CARDDAV, "addressbook");
 pub const ADDRESSBOOK_HOME_SET: ExpandedName =
     ExpandedName::from_static("urn:ietf:params:xml:ns:carddav", "addressbook-home-set");
+/// Defined in <https://www.rfc-editor.org/rfc/rfc6352#section-6.2.1>
+pub const ADDRESSBOOK_DESCRIPTION: ExpandedName =
+    ExpandedName::from_static(CARDDAV, "addressbook-description");
 pub const ADDRESS_DATA: ExpandedName = ExpandedName::from_static(CARDDAV, "address-data");
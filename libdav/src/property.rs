@@ -0,0 +1,440 @@
+// Copyright 2023 Hugo Osvaldo Barrera
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+//! Typed WebDav properties.
+//!
+//! Each [`Property`] has a name and knows how to encode and decode its value. Properties can be
+//! read and written one at a time with [`WebDavClient::get_property`] and
+//! [`WebDavClient::set_property`], or many at a time with [`WebDavClient::get_properties`] and
+//! [`WebDavClient::update_properties`].
+//!
+//! [`WebDavClient::get_property`]: crate::dav::WebDavClient::get_property
+//! [`WebDavClient::set_property`]: crate::dav::WebDavClient::set_property
+//! [`WebDavClient::get_properties`]: crate::dav::WebDavClient::get_properties
+//! [`WebDavClient::update_properties`]: crate::dav::WebDavClient::update_properties
+
+use http::StatusCode;
+use roxmltree::ExpandedName;
+
+use crate::dav::{check_status, DavError};
+use crate::names::{
+    ADDRESSBOOK_DESCRIPTION, CALENDAR_COLOUR, CALENDAR_DESCRIPTION, CALENDAR_ORDER, DISPLAY_NAME,
+    PROP, PROPSTAT, RESPONSE, STATUS,
+};
+use crate::xmlutils::{parse_statusline, render_xml_with_text};
+
+/// A WebDav property with a typed value.
+pub trait Property {
+    /// The type of this property's value.
+    type Value;
+
+    /// The name of this property.
+    const NAME: ExpandedName<'static, 'static>;
+
+    /// Whether a property in a response is this property.
+    ///
+    /// Implementations may override this to work around servers which reply with the wrong name.
+    #[must_use]
+    fn matches(namespace: Option<&str>, name: &str) -> bool {
+        namespace == Self::NAME.namespace() && name == Self::NAME.name()
+    }
+
+    /// Parses the value of this property from the text in a response.
+    ///
+    /// # Errors
+    ///
+    /// If the text is not a valid value for this property.
+    fn decode(text: &str) -> Result<Self::Value, DavError>;
+
+    /// Renders a value into text for a request. The returned text should not be escaped.
+    fn encode(value: &Self::Value) -> String;
+}
+
+/// The name of a collection. See [`DISPLAY_NAME`].
+pub struct DisplayName;
+
+impl Property for DisplayName {
+    type Value = String;
+    const NAME: ExpandedName<'static, 'static> = DISPLAY_NAME;
+
+    fn decode(text: &str) -> Result<String, DavError> {
+        Ok(text.to_string())
+    }
+
+    fn encode(value: &String) -> String {
+        value.clone()
+    }
+}
+
+/// The colour of a calendar, as a hex value with a leading pound sign (e.g. `#ff0000`).
+///
+/// This is not a formally standardised property, but is relatively widespread.
+///
+/// # Quirks
+///
+/// The namespace of the value in the response from the server is ignored. This is a workaround
+/// for an [issue in the `cyrus-imapd` implemenetation][cyrus-issue].
+///
+/// [cyrus-issue]: https://github.com/cyrusimap/cyrus-imapd/issues/4489
+pub struct CalendarColour;
+
+impl Property for CalendarColour {
+    type Value = String;
+    const NAME: ExpandedName<'static, 'static> = CALENDAR_COLOUR;
+
+    fn matches(_: Option<&str>, name: &str) -> bool {
+        name == Self::NAME.name()
+    }
+
+    fn decode(text: &str) -> Result<String, DavError> {
+        Ok(text.to_string())
+    }
+
+    fn encode(value: &String) -> String {
+        value.clone()
+    }
+}
+
+/// A human-readable description of a calendar.
+///
+/// See: <https://www.rfc-editor.org/rfc/rfc4791#section-5.2.1>
+pub struct CalendarDescription;
+
+impl Property for CalendarDescription {
+    type Value = String;
+    const NAME: ExpandedName<'static, 'static> = CALENDAR_DESCRIPTION;
+
+    fn decode(text: &str) -> Result<String, DavError> {
+        Ok(text.to_string())
+    }
+
+    fn encode(value: &String) -> String {
+        value.clone()
+    }
+}
+
+/// The position of a calendar when sorting calendars for display.
+///
+/// This is not a formally standardised property, but is relatively widespread.
+pub struct CalendarOrder;
+
+impl Property for CalendarOrder {
+    type Value = i64;
+    const NAME: ExpandedName<'static, 'static> = CALENDAR_ORDER;
+
+    fn decode(text: &str) -> Result<i64, DavError> {
+        text.trim()
+            .parse()
+            .map_err(|e| DavError::InvalidResponse(Box::from(e)))
+    }
+
+    fn encode(value: &i64) -> String {
+        value.to_string()
+    }
+}
+
+/// A human-readable description of an address book.
+///
+/// See: <https://www.rfc-editor.org/rfc/rfc6352#section-6.2.1>
+pub struct AddressBookDescription;
+
+impl Property for AddressBookDescription {
+    type Value = String;
+    const NAME: ExpandedName<'static, 'static> = ADDRESSBOOK_DESCRIPTION;
+
+    fn decode(text: &str) -> Result<String, DavError> {
+        Ok(text.to_string())
+    }
+
+    fn encode(value: &String) -> String {
+        value.clone()
+    }
+}
+
+/// A change to a single property. See [`WebDavClient::update_properties`].
+///
+/// [`WebDavClient::update_properties`]: crate::dav::WebDavClient::update_properties
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PropertyUpdate {
+    name: ExpandedName<'static, 'static>,
+    value: Option<String>,
+}
+
+impl PropertyUpdate {
+    /// Set a property to a given value.
+    #[must_use]
+    pub fn set<P: Property>(value: &P::Value) -> Self {
+        PropertyUpdate {
+            name: P::NAME,
+            value: Some(P::encode(value)),
+        }
+    }
+
+    /// Remove a property.
+    #[must_use]
+    pub fn remove<P: Property>() -> Self {
+        PropertyUpdate {
+            name: P::NAME,
+            value: None,
+        }
+    }
+
+    pub(crate) fn parts(&self) -> (&ExpandedName<'static, 'static>, Option<&str>) {
+        (&self.name, self.value.as_deref())
+    }
+}
+
+/// The status of a single property in a `PROPFIND` or `PROPPATCH` response.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PropertyStatus {
+    pub namespace: Option<String>,
+    pub name: String,
+    pub status: StatusCode,
+    /// The text of the property. Always `None` for `PROPPATCH` responses.
+    pub value: Option<String>,
+}
+
+impl PropertyStatus {
+    /// Whether this is the status for a property named `name`.
+    #[must_use]
+    pub fn is(&self, name: &ExpandedName<'_, '_>) -> bool {
+        self.namespace.as_deref() == name.namespace() && self.name == name.name()
+    }
+}
+
+/// The status of each property in a `PROPFIND` or `PROPPATCH` response.
+#[derive(Debug, PartialEq, Eq, Default)]
+pub struct PropertyResults(Vec<PropertyStatus>);
+
+impl PropertyResults {
+    /// Iterate over the status for each property.
+    pub fn iter(&self) -> impl Iterator<Item = &PropertyStatus> {
+        self.0.iter()
+    }
+
+    /// Returns the status for a property, if present in the response.
+    #[must_use]
+    pub fn get<P: Property>(&self) -> Option<&PropertyStatus> {
+        self.0
+            .iter()
+            .find(|s| P::matches(s.namespace.as_deref(), &s.name))
+    }
+
+    /// Returns the decoded value of a property.
+    ///
+    /// Returns `None` if the property is not defined for the resource.
+    ///
+    /// # Errors
+    ///
+    /// - If the property is missing from the response.
+    /// - If the server returned an error status for the property.
+    /// - If the value cannot be decoded.
+    pub fn value<P: Property>(&self) -> Result<Option<P::Value>, DavError> {
+        let status = self.get::<P>().ok_or(DavError::InvalidResponse(
+            "missing property in response but no error".into(),
+        ))?;
+        match status.status {
+            StatusCode::NOT_FOUND => Ok(None),
+            code => {
+                check_status(code)?;
+                status.value.as_deref().map(P::decode).transpose()
+            }
+        }
+    }
+
+    /// Checks that all properties have a success status.
+    ///
+    /// # Errors
+    ///
+    /// If any property has a non-success status. When a `PROPPATCH` fails, all properties but
+    /// the ones that caused the failure have a `424 Failed Dependency` status; the status of the
+    /// latter is returned.
+    pub fn check(&self) -> Result<(), DavError> {
+        let failed = self
+            .0
+            .iter()
+            .filter(|s| !s.status.is_success())
+            .min_by_key(|s| s.status == StatusCode::FAILED_DEPENDENCY);
+        match failed {
+            Some(s) => Err(DavError::BadStatusCode(s.status)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Render the body for a `PROPPATCH` request.
+///
+/// Each update is applied in order. Properties with a value of `None` are removed.
+pub(crate) fn render_propertyupdate<'a, 'ns, 'n, I>(updates: I) -> String
+where
+    'ns: 'a,
+    'n: 'a,
+    I: IntoIterator<Item = (&'a ExpandedName<'ns, 'n>, Option<&'a str>)>,
+{
+    let mut body = String::from(r#"<propertyupdate xmlns="DAV:">"#);
+    for (name, value) in updates {
+        let action = if value.is_some() { "set" } else { "remove" };
+        body.push('<');
+        body.push_str(action);
+        body.push_str("><prop>");
+        body.push_str(&render_xml_with_text(name, value));
+        body.push_str("</prop></");
+        body.push_str(action);
+        body.push('>');
+    }
+    body.push_str("</propertyupdate>");
+    body
+}
+
+/// Parses the status of each property in a `PROPFIND` or `PROPPATCH` response.
+pub(crate) fn parse_property_results<B: AsRef<[u8]>>(body: B) -> Result<PropertyResults, DavError> {
+    let body = std::str::from_utf8(body.as_ref())?;
+    let doc = roxmltree::Document::parse(body)?;
+    let responses = doc
+        .root_element()
+        .children()
+        .filter(|node| node.tag_name() == RESPONSE);
+
+    let mut results = Vec::new();
+    for response in responses {
+        for node in response.children() {
+            // Responses with a status outside of a `propstat` refer to the resource itself.
+            if node.tag_name() == STATUS {
+                check_status(parse_status(node)?)?;
+            } else if node.tag_name() == PROPSTAT {
+                let status = node
+                    .children()
+                    .find(|node| node.tag_name() == STATUS)
+                    .ok_or(DavError::InvalidResponse(
+                        "missing status in propstat".into(),
+                    ))
+                    .and_then(parse_status)?;
+                let props = node
+                    .children()
+                    .filter(|node| node.tag_name() == PROP)
+                    .flat_map(|prop| prop.children().filter(roxmltree::Node::is_element));
+                for prop in props {
+                    results.push(PropertyStatus {
+                        namespace: prop.tag_name().namespace().map(str::to_string),
+                        name: prop.tag_name().name().to_string(),
+                        status,
+                        value: prop.text().map(str::to_string),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(PropertyResults(results))
+}
+
+fn parse_status(node: roxmltree::Node) -> Result<StatusCode, DavError> {
+    let text = node.text().ok_or(DavError::InvalidResponse(
+        "missing text inside 'DAV:status'".into(),
+    ))?;
+    Ok(parse_statusline(text)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+
+    use crate::dav::DavError;
+    use crate::names::{CALENDAR_COLOUR, DISPLAY_NAME};
+    use crate::property::{
+        parse_property_results, render_propertyupdate, CalendarColour, CalendarDescription,
+        CalendarOrder, DisplayName, PropertyUpdate,
+    };
+
+    #[test]
+    fn test_parse_many_properties() {
+        let raw = br#"
+<multistatus xmlns="DAV:" xmlns:A="http://apple.com/ns/ical/" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <response>
+    <href>/calendars/work/</href>
+    <propstat>
+      <prop>
+        <displayname>Work &amp; such</displayname>
+        <A:calendar-order>3</A:calendar-order>
+      </prop>
+      <status>HTTP/1.1 200 OK</status>
+    </propstat>
+    <propstat>
+      <prop>
+        <C:calendar-description/>
+      </prop>
+      <status>HTTP/1.1 404 Not Found</status>
+    </propstat>
+  </response>
+</multistatus>"#;
+
+        let results = parse_property_results(raw).unwrap();
+        assert_eq!(results.iter().count(), 3);
+        assert_eq!(
+            results.value::<DisplayName>().unwrap().as_deref(),
+            Some("Work & such")
+        );
+        assert_eq!(results.value::<CalendarOrder>().unwrap(), Some(3));
+        assert_eq!(results.value::<CalendarDescription>().unwrap(), None);
+        results.value::<CalendarColour>().unwrap_err();
+        assert!(matches!(
+            results.check(),
+            Err(DavError::BadStatusCode(StatusCode::NOT_FOUND))
+        ));
+    }
+
+    #[test]
+    fn test_parse_proppatch_failure() {
+        let raw = br#"
+<multistatus xmlns="DAV:">
+  <response>
+    <href>/calendars/work/</href>
+    <propstat>
+      <prop><displayname/></prop>
+      <status>HTTP/1.1 424 Failed Dependency</status>
+    </propstat>
+    <propstat>
+      <prop><getetag/></prop>
+      <status>HTTP/1.1 403 Forbidden</status>
+    </propstat>
+  </response>
+</multistatus>"#;
+
+        let results = parse_property_results(raw).unwrap();
+        assert_eq!(
+            results.get::<DisplayName>().unwrap().status,
+            StatusCode::FAILED_DEPENDENCY
+        );
+        assert!(matches!(
+            results.check(),
+            Err(DavError::BadStatusCode(StatusCode::FORBIDDEN))
+        ));
+    }
+
+    #[test]
+    fn test_render_propertyupdate() {
+        let updates = [
+            PropertyUpdate::set::<DisplayName>(&"<Work>".to_string()),
+            PropertyUpdate::remove::<CalendarColour>(),
+        ];
+        assert_eq!(
+            render_propertyupdate(updates.iter().map(PropertyUpdate::parts)),
+            concat!(
+                r#"<propertyupdate xmlns="DAV:">"#,
+                r#"<set><prop><displayname xmlns="DAV:">&lt;Work&gt;</displayname></prop></set>"#,
+                r#"<remove><prop><calendar-color xmlns="http://apple.com/ns/ical/"/></prop></remove>"#,
+                r#"</propertyupdate>"#,
+            )
+        );
+        assert_eq!(
+            render_propertyupdate([(&DISPLAY_NAME, None), (&CALENDAR_COLOUR, Some("#ff0000"))]),
+            concat!(
+                r#"<propertyupdate xmlns="DAV:">"#,
+                r#"<remove><prop><displayname xmlns="DAV:"/></prop></remove>"#,
+                r#"<set><prop><calendar-color xmlns="http://apple.com/ns/ical/">#ff0000</calendar-color></prop></set>"#,
+                r#"</propertyupdate>"#,
+            )
+        );
+    }
+}
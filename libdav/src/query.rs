@@ -0,0 +1,654 @@
+// Copyright 2023 Hugo Osvaldo Barrera
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+//! Builders for `REPORT` queries which filter resources on the server.
+//!
+//! Queries are executed via [`CalDavClient::calendar_query`](crate::CalDavClient::calendar_query)
+//! and [`CardDavClient::addressbook_query`](crate::CardDavClient::addressbook_query).
+//!
+//! ```rust
+//! use std::time::{Duration, SystemTime};
+//! use libdav::query::{
+//!     AddressBookQuery, CalendarComponent, CalendarQuery, MatchType, PropFilter, TextMatch,
+//! };
+//!
+//! let now = SystemTime::now();
+//! let query = CalendarQuery::new(CalendarComponent::Event)
+//!     .time_range(Some(now), Some(now + Duration::from_secs(7 * 24 * 60 * 60)))
+//!     .prop_filter(PropFilter::text_match("SUMMARY", TextMatch::new("meeting")))
+//!     .with_data();
+//!
+//! let query = AddressBookQuery::new()
+//!     .prop_filter(PropFilter::text_match(
+//!         "EMAIL",
+//!         TextMatch::new("hugo@").match_type(MatchType::StartsWith),
+//!     ))
+//!     .limit(10)
+//!     .with_properties(["FN", "EMAIL"]);
+//! ```
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::StatusCode;
+use roxmltree::ExpandedName;
+
+use crate::dav::{check_status, DavError};
+use crate::names::{GETETAG, RESPONSE, STATUS};
+use crate::xmlutils::{
+    escape_text, get_newline_corrected_text, get_unquoted_href, parse_statusline,
+};
+
+/// A type of calendar component.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalendarComponent {
+    Event,
+    Todo,
+    Journal,
+}
+
+impl CalendarComponent {
+    fn name(self) -> &'static str {
+        match self {
+            CalendarComponent::Event => "VEVENT",
+            CalendarComponent::Todo => "VTODO",
+            CalendarComponent::Journal => "VJOURNAL",
+        }
+    }
+}
+
+/// A `calendar-query` report.
+///
+/// Matches calendar resources containing a component of a given type. Further filters may be
+/// applied to that component, in which case all of them must match.
+///
+/// See: <https://www.rfc-editor.org/rfc/rfc4791#section-7.8>
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CalendarQuery {
+    component: CalendarComponent,
+    start: Option<SystemTime>,
+    end: Option<SystemTime>,
+    prop_filters: Vec<PropFilter>,
+    with_data: bool,
+}
+
+impl CalendarQuery {
+    /// Create a new query matching all resources which contain a `component`.
+    #[must_use]
+    pub fn new(component: CalendarComponent) -> Self {
+        CalendarQuery {
+            component,
+            start: None,
+            end: None,
+            prop_filters: Vec::new(),
+            with_data: false,
+        }
+    }
+
+    /// Only match components which overlap a time range.
+    ///
+    /// Either end of the range may be left open. Recurring components match if any of their
+    /// occurrences overlap the range.
+    ///
+    /// See: <https://www.rfc-editor.org/rfc/rfc4791#section-9.9>
+    #[must_use]
+    pub fn time_range(mut self, start: Option<SystemTime>, end: Option<SystemTime>) -> Self {
+        self.start = start;
+        self.end = end;
+        self
+    }
+
+    /// Only match components for which a property filter matches.
+    #[must_use]
+    pub fn prop_filter(mut self, filter: PropFilter) -> Self {
+        self.prop_filters.push(filter);
+        self
+    }
+
+    /// Also return the data of each matching resource.
+    #[must_use]
+    pub fn with_data(mut self) -> Self {
+        self.with_data = true;
+        self
+    }
+
+    /// Render the body for the `REPORT` request.
+    pub(crate) fn render(&self) -> String {
+        let data = if self.with_data {
+            "<C:calendar-data/>"
+        } else {
+            ""
+        };
+        let time_range = if self.start.is_some() || self.end.is_some() {
+            let start = self
+                .start
+                .map(|start| format!(r#" start="{}""#, format_utc(start)))
+                .unwrap_or_default();
+            let end = self
+                .end
+                .map(|end| format!(r#" end="{}""#, format_utc(end)))
+                .unwrap_or_default();
+            format!("<C:time-range{start}{end}/>")
+        } else {
+            String::new()
+        };
+        let filters = self
+            .prop_filters
+            .iter()
+            .map(|filter| filter.render(false))
+            .collect::<String>();
+
+        format!(
+            r#"<C:calendar-query xmlns="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav"><prop><getetag/>{data}</prop><C:filter><C:comp-filter name="VCALENDAR"><C:comp-filter name="{}">{time_range}{filters}</C:comp-filter></C:comp-filter></C:filter></C:calendar-query>"#,
+            self.component.name()
+        )
+    }
+}
+
+/// An `addressbook-query` report.
+///
+/// By default, matches address book resources for which ANY of the property filters match.
+///
+/// See: <https://www.rfc-editor.org/rfc/rfc6352#section-8.6>
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AddressBookQuery {
+    prop_filters: Vec<PropFilter>,
+    match_all: bool,
+    limit: Option<u32>,
+    data: AddressData,
+}
+
+/// The data requested for each resource in an [`AddressBookQuery`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+enum AddressData {
+    #[default]
+    None,
+    Full,
+    Partial(Vec<String>),
+}
+
+impl AddressBookQuery {
+    /// Create a new query matching all resources.
+    #[must_use]
+    pub fn new() -> Self {
+        AddressBookQuery::default()
+    }
+
+    /// Only match resources for which a property filter matches.
+    #[must_use]
+    pub fn prop_filter(mut self, filter: PropFilter) -> Self {
+        self.prop_filters.push(filter);
+        self
+    }
+
+    /// Only match resources for which ALL property filters match.
+    #[must_use]
+    pub fn match_all(mut self) -> Self {
+        self.match_all = true;
+        self
+    }
+
+    /// Request that the server returns at most `limit` results.
+    ///
+    /// Servers may return fewer results than matched, whether or not a limit is requested.
+    #[must_use]
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Also return the data of each matching resource.
+    #[must_use]
+    pub fn with_data(mut self) -> Self {
+        self.data = AddressData::Full;
+        self
+    }
+
+    /// Also return the data of each matching resource, but only with the given properties.
+    ///
+    /// Servers may ignore this and return all properties.
+    #[must_use]
+    pub fn with_properties<S: Into<String>>(
+        mut self,
+        properties: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.data = AddressData::Partial(properties.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Render the body for the `REPORT` request.
+    pub(crate) fn render(&self) -> String {
+        let data = match &self.data {
+            AddressData::None => String::new(),
+            AddressData::Full => "<C:address-data/>".to_string(),
+            AddressData::Partial(properties) => {
+                let mut data = String::from("<C:address-data>");
+                for name in properties {
+                    data.push_str(r#"<C:prop name=""#);
+                    data.push_str(&escape_attribute(name));
+                    data.push_str(r#""/>"#);
+                }
+                data.push_str("</C:address-data>");
+                data
+            }
+        };
+        let test = if self.match_all { "allof" } else { "anyof" };
+        let filters = self
+            .prop_filters
+            .iter()
+            .map(|filter| filter.render(true))
+            .collect::<String>();
+        let limit = self
+            .limit
+            .map(|limit| format!("<C:limit><C:nresults>{limit}</C:nresults></C:limit>"))
+            .unwrap_or_default();
+
+        format!(
+            r#"<C:addressbook-query xmlns="DAV:" xmlns:C="urn:ietf:params:xml:ns:carddav"><prop><getetag/>{data}</prop><C:filter test="{test}">{filters}</C:filter>{limit}</C:addressbook-query>"#
+        )
+    }
+}
+
+/// A filter on a property of a calendar component or an address book resource.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PropFilter {
+    name: String,
+    condition: PropCondition,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PropCondition {
+    Defined,
+    NotDefined,
+    TextMatch(TextMatch),
+}
+
+impl PropFilter {
+    /// Matches if the property `name` is present.
+    #[must_use]
+    pub fn defined(name: impl Into<String>) -> Self {
+        PropFilter {
+            name: name.into(),
+            condition: PropCondition::Defined,
+        }
+    }
+
+    /// Matches if the property `name` is absent.
+    #[must_use]
+    pub fn not_defined(name: impl Into<String>) -> Self {
+        PropFilter {
+            name: name.into(),
+            condition: PropCondition::NotDefined,
+        }
+    }
+
+    /// Matches if the value of the property `name` matches some text.
+    #[must_use]
+    pub fn text_match(name: impl Into<String>, text_match: TextMatch) -> Self {
+        PropFilter {
+            name: name.into(),
+            condition: PropCondition::TextMatch(text_match),
+        }
+    }
+
+    /// Render this filter. `match_type` is only valid inside addressbook queries.
+    fn render(&self, match_type: bool) -> String {
+        let name = escape_attribute(&self.name);
+        match &self.condition {
+            PropCondition::Defined => format!(r#"<C:prop-filter name="{name}"/>"#),
+            PropCondition::NotDefined => {
+                format!(r#"<C:prop-filter name="{name}"><C:is-not-defined/></C:prop-filter>"#)
+            }
+            PropCondition::TextMatch(text_match) => format!(
+                r#"<C:prop-filter name="{name}">{}</C:prop-filter>"#,
+                text_match.render(match_type)
+            ),
+        }
+    }
+}
+
+/// Matches a substring of a property's value.
+///
+/// By default, matching is case-insensitive for ASCII characters (the `i;ascii-casemap`
+/// collation).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextMatch {
+    text: String,
+    negate: bool,
+    collation: Option<String>,
+    match_type: Option<MatchType>,
+}
+
+/// How text is matched against a property's value. See [`TextMatch::match_type`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchType {
+    Equals,
+    Contains,
+    StartsWith,
+    EndsWith,
+}
+
+impl MatchType {
+    fn name(self) -> &'static str {
+        match self {
+            MatchType::Equals => "equals",
+            MatchType::Contains => "contains",
+            MatchType::StartsWith => "starts-with",
+            MatchType::EndsWith => "ends-with",
+        }
+    }
+}
+
+impl TextMatch {
+    /// Create a new text match for `text`.
+    #[must_use]
+    pub fn new(text: impl Into<String>) -> Self {
+        TextMatch {
+            text: text.into(),
+            negate: false,
+            collation: None,
+            match_type: None,
+        }
+    }
+
+    /// Match values which do NOT contain the text.
+    #[must_use]
+    pub fn negate(mut self) -> Self {
+        self.negate = true;
+        self
+    }
+
+    /// Compare text using a specific collation (e.g.: `i;octet` for case-sensitive matching).
+    #[must_use]
+    pub fn collation(mut self, collation: impl Into<String>) -> Self {
+        self.collation = Some(collation.into());
+        self
+    }
+
+    /// Match the text against the whole value, or its start or end (rather than any substring).
+    ///
+    /// Only supported in an [`AddressBookQuery`]; calendar queries always match substrings.
+    #[must_use]
+    pub fn match_type(mut self, match_type: MatchType) -> Self {
+        self.match_type = Some(match_type);
+        self
+    }
+
+    fn render(&self, with_match_type: bool) -> String {
+        let collation = self
+            .collation
+            .as_ref()
+            .map(|collation| format!(r#" collation="{}""#, escape_attribute(collation)))
+            .unwrap_or_default();
+        let negate = if self.negate {
+            r#" negate-condition="yes""#
+        } else {
+            ""
+        };
+        let match_type = match self.match_type {
+            Some(match_type) if with_match_type => {
+                format!(r#" match-type="{}""#, match_type.name())
+            }
+            _ => String::new(),
+        };
+        format!(
+            "<C:text-match{collation}{negate}{match_type}>{}</C:text-match>",
+            escape_text(&self.text)
+        )
+    }
+}
+
+/// A resource matched by a query.
+#[derive(Debug, PartialEq, Eq)]
+pub struct QueriedResource {
+    /// The absolute path to the resource in the server. This value is not URL-encoded.
+    pub href: String,
+    pub etag: String,
+    /// The contents of the resource, if they were requested.
+    pub data: Option<String>,
+}
+
+/// Resources returned by [`CalDavClient::calendar_query`] or
+/// [`CardDavClient::addressbook_query`].
+///
+/// [`CalDavClient::calendar_query`]: crate::CalDavClient::calendar_query
+/// [`CardDavClient::addressbook_query`]: crate::CardDavClient::addressbook_query
+#[derive(Debug, PartialEq, Eq)]
+pub struct QueryResult {
+    /// Resources which matched the query.
+    pub resources: Vec<QueriedResource>,
+    /// Whether the server truncated results.
+    ///
+    /// If true, `resources` only contains some of the resources which matched the query.
+    pub truncated: bool,
+}
+
+fn escape_attribute(raw: &str) -> String {
+    escape_text(raw).replace('"', "&quot;")
+}
+
+/// Formats a time as a UTC date-time, as used in `time-range` filters.
+///
+/// Times before the Unix epoch are clamped to it.
+fn format_utc(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+
+    // Converts days since the epoch into a civil date.
+    // See: <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Parses the response to a query.
+///
+/// The collection itself is skipped. Servers list it with a `507` status when results have been
+/// truncated, in which case [`QueryResult::truncated`] is set.
+pub(crate) fn query_parse<B: AsRef<[u8]>>(
+    body: B,
+    collection_href: &str,
+    property: &ExpandedName<'_, '_>,
+) -> Result<QueryResult, DavError> {
+    let body = std::str::from_utf8(body.as_ref())?;
+    let doc = roxmltree::Document::parse(body)?;
+    let responses = doc
+        .root_element()
+        .children()
+        .filter(|node| node.tag_name() == RESPONSE);
+
+    let mut result = QueryResult {
+        resources: Vec::new(),
+        truncated: false,
+    };
+    for response in responses {
+        let href = get_unquoted_href(&response)?.to_string();
+
+        // Responses with a status outside of a `propstat` refer to the resource itself.
+        let status = response
+            .children()
+            .find(|node| node.tag_name() == STATUS)
+            .map(|node| {
+                let text = node.text().ok_or(DavError::InvalidResponse(
+                    "missing text inside 'DAV:status'".into(),
+                ))?;
+                parse_statusline(text).map_err(DavError::from)
+            })
+            .transpose()?;
+        if status == Some(StatusCode::INSUFFICIENT_STORAGE) {
+            result.truncated = true;
+            continue;
+        }
+        if href == collection_href {
+            continue;
+        }
+        if let Some(status) = status {
+            check_status(status)?;
+        }
+
+        let etag = response
+            .descendants()
+            .find(|node| node.tag_name() == GETETAG)
+            .and_then(|node| node.text())
+            .ok_or(DavError::InvalidResponse("missing etag in response".into()))?
+            .to_string();
+        let has_data = response
+            .descendants()
+            .any(|node| node.tag_name() == *property && node.text().is_some());
+        let data = if has_data {
+            Some(get_newline_corrected_text(&response, property)?)
+        } else {
+            None
+        };
+
+        result.resources.push(QueriedResource { href, etag, data });
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::names::CALENDAR_DATA;
+    use crate::query::{
+        format_utc, query_parse, AddressBookQuery, CalendarComponent, CalendarQuery, MatchType,
+        PropFilter, QueriedResource, QueryResult, TextMatch,
+    };
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(UNIX_EPOCH), "19700101T000000Z");
+        assert_eq!(
+            format_utc(UNIX_EPOCH + Duration::from_secs(951_782_400 + 3_723)),
+            "20000229T010203Z"
+        );
+        assert_eq!(
+            format_utc(UNIX_EPOCH + Duration::from_secs(1_704_067_199)),
+            "20231231T235959Z"
+        );
+    }
+
+    #[test]
+    fn test_render_calendar_query() {
+        let query = CalendarQuery::new(CalendarComponent::Todo)
+            .time_range(Some(UNIX_EPOCH), None)
+            .prop_filter(PropFilter::not_defined("COMPLETED"))
+            .prop_filter(PropFilter::text_match(
+                "SUMMARY",
+                TextMatch::new("<b>\"x\"")
+                    .negate()
+                    .collation("i;octet")
+                    // Not valid for calendar queries, so omitted.
+                    .match_type(MatchType::Equals),
+            ))
+            .with_data();
+
+        assert_eq!(
+            query.render(),
+            concat!(
+                r#"<C:calendar-query xmlns="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">"#,
+                r#"<prop><getetag/><C:calendar-data/></prop>"#,
+                r#"<C:filter><C:comp-filter name="VCALENDAR"><C:comp-filter name="VTODO">"#,
+                r#"<C:time-range start="19700101T000000Z"/>"#,
+                r#"<C:prop-filter name="COMPLETED"><C:is-not-defined/></C:prop-filter>"#,
+                r#"<C:prop-filter name="SUMMARY">"#,
+                r#"<C:text-match collation="i;octet" negate-condition="yes">&lt;b&gt;"x"</C:text-match>"#,
+                r#"</C:prop-filter>"#,
+                r#"</C:comp-filter></C:comp-filter></C:filter></C:calendar-query>"#,
+            )
+        );
+    }
+
+    #[test]
+    fn test_render_addressbook_query() {
+        let query = AddressBookQuery::new()
+            .prop_filter(PropFilter::text_match(
+                "EMAIL",
+                TextMatch::new("hugo@").match_type(MatchType::StartsWith),
+            ))
+            .prop_filter(PropFilter::defined("NICKNAME"))
+            .match_all()
+            .limit(5)
+            .with_properties(["FN", "EMAIL"]);
+
+        assert_eq!(
+            query.render(),
+            concat!(
+                r#"<C:addressbook-query xmlns="DAV:" xmlns:C="urn:ietf:params:xml:ns:carddav">"#,
+                r#"<prop><getetag/><C:address-data><C:prop name="FN"/><C:prop name="EMAIL"/>"#,
+                r#"</C:address-data></prop>"#,
+                r#"<C:filter test="allof"><C:prop-filter name="EMAIL">"#,
+                r#"<C:text-match match-type="starts-with">hugo@</C:text-match></C:prop-filter>"#,
+                r#"<C:prop-filter name="NICKNAME"/></C:filter>"#,
+                r#"<C:limit><C:nresults>5</C:nresults></C:limit></C:addressbook-query>"#,
+            )
+        );
+    }
+
+    #[test]
+    fn test_query_parse() {
+        let raw = br#"
+<multistatus xmlns="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">
+  <response>
+    <href>/calendars/work/</href>
+    <status>HTTP/1.1 507 Insufficient Storage</status>
+  </response>
+  <response>
+    <href>/calendars/work/one%20two.ics</href>
+    <propstat>
+      <prop>
+        <getetag>"1"</getetag>
+        <C:calendar-data>BEGIN:VCALENDAR
+END:VCALENDAR
+</C:calendar-data>
+      </prop>
+      <status>HTTP/1.1 200 OK</status>
+    </propstat>
+  </response>
+  <response>
+    <href>/calendars/work/three.ics</href>
+    <propstat>
+      <prop>
+        <getetag>"2"</getetag>
+      </prop>
+      <status>HTTP/1.1 200 OK</status>
+    </propstat>
+  </response>
+</multistatus>"#;
+
+        let result = query_parse(raw, "/calendars/work/", &CALENDAR_DATA).unwrap();
+        assert_eq!(
+            result,
+            QueryResult {
+                resources: vec![
+                    QueriedResource {
+                        href: "/calendars/work/one two.ics".into(),
+                        etag: "\"1\"".into(),
+                        data: Some("BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n".into()),
+                    },
+                    QueriedResource {
+                        href: "/calendars/work/three.ics".into(),
+                        etag: "\"2\"".into(),
+                        data: None,
+                    },
+                ],
+                truncated: true,
+            }
+        );
+    }
+}
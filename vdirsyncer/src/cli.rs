@@ -0,0 +1,121 @@
+// Copyright 2023 Hugo Osvaldo Barrera
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::{config::Config, sync::Mode};
+
+#[derive(Clone, ValueEnum)]
+enum Verbosity {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Synchronise pairs of storages.
+    Sync {
+        /// Pairs to synchronise. If none are specified, all pairs are synchronised.
+        pairs: Vec<String>,
+
+        /// Print the operations that would be executed without modifying any storage.
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Print operations as JSON (one object per pair) when using `--dry-run`.
+        #[arg(long, requires = "dry_run")]
+        json: bool,
+
+        /// Proceed even if a large share of items would be deleted.
+        ///
+        /// By default, synchronising a collection is aborted if it would delete too many items
+        /// (see the `max_deletion_ratio` and `abort_when_emptied` pair options).
+        #[arg(long)]
+        force_deletions: bool,
+    },
+}
+
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    pub(crate) command: Command,
+
+    /// Path to the configuration file.
+    ///
+    /// Defaults to `$XDG_CONFIG_HOME/vdirsyncer/config.toml`.
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+
+    /// Change logging verbosity
+    ///
+    /// Logging is always directed to `stderr`.
+    #[clap(short, long)]
+    verbose: Option<Verbosity>,
+}
+
+impl Cli {
+    pub(crate) fn execute(self) -> anyhow::Result<()> {
+        let path = match self.config {
+            Some(path) => path,
+            None => default_config_path()?,
+        };
+        let config = Config::load(&path)
+            .with_context(|| format!("failed to load configuration from {}", path.display()))?;
+
+        match self.command {
+            Command::Sync {
+                pairs,
+                dry_run,
+                json,
+                force_deletions,
+            } => {
+                let mode = match (dry_run, json) {
+                    (false, _) => Mode::Execute,
+                    (true, false) => Mode::DryRun,
+                    (true, true) => Mode::DryRunJson,
+                };
+                crate::sync::execute(&config, &pairs, mode, force_deletions)
+            }
+        }
+    }
+
+    /// Returns the log level selected via the `--verbose` option.
+    /// The default log level is WARN.
+    pub(crate) fn log_level(&self) -> log::Level {
+        match self.verbose {
+            Some(Verbosity::Error) => log::Level::Error,
+            Some(Verbosity::Warn) | None => log::Level::Warn,
+            Some(Verbosity::Info) => log::Level::Info,
+            Some(Verbosity::Debug) => log::Level::Debug,
+            Some(Verbosity::Trace) => log::Level::Trace,
+        }
+    }
+}
+
+/// Returns the default location for the configuration file.
+///
+/// Follows the XDG base directory specification.
+fn default_config_path() -> anyhow::Result<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".config"))
+            .context("neither $XDG_CONFIG_HOME nor $HOME are defined")?,
+    };
+
+    Ok(config_home.join("vdirsyncer").join("config.toml"))
+}
+
+#[test]
+fn verify_cli() {
+    use clap::CommandFactory;
+    Cli::command().debug_assert();
+}
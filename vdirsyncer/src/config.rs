@@ -0,0 +1,661 @@
+// Copyright 2023 Hugo Osvaldo Barrera
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+//! Configuration file format.
+//!
+//! The configuration file is a TOML file which declares storages and pairs of storages which are
+//! to be synchronised. For example:
+//!
+//! ```toml
+//! status_path = "~/.local/share/vdirsyncer/status"
+//!
+//! [storages.local_calendars]
+//! type = "filesystem"
+//! path = "~/calendars"
+//! extension = "ics"
+//!
+//! [storages.remote_calendars]
+//! type = "caldav"
+//! url = "https://example.com/"
+//! username = "hugo"
+//! password_command = ["pass", "show", "calendars"]
+//!
+//! [pairs.calendars]
+//! a = "local_calendars"
+//! b = "remote_calendars"
+//! collections = ["personal", { name = "work", a = "work", b = "work-calendar" }]
+//! conflict_resolution = "a wins"
+//! max_deletion_ratio = 0.3
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use http::Uri;
+use libdav::auth::{Auth, Password};
+use serde::{Deserialize, Deserializer};
+use vstorage::{
+    base::{Definition, IcsItem, Storage, VcardItem},
+    caldav::{CalDavDefinition, TimeWindow},
+    carddav::CardDavDefinition,
+    filesystem::{EtagMode, FilenamePolicy, FilesystemDefinition},
+    httpvcard::HttpVcardDefinition,
+    singlefile::SinglefileDefinition,
+    sync::{
+        plan::{ConflictResolution, DeletionGuard},
+        CollectionMapping,
+    },
+    webcal::WebCalDefinition,
+};
+
+/// The entire configuration file.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Directory where the state of each storage is saved between synchronisations.
+    pub(crate) status_path: PathBuf,
+    #[serde(default)]
+    pub(crate) storages: BTreeMap<String, StorageConfig>,
+    #[serde(default)]
+    pub(crate) pairs: BTreeMap<String, PairConfig>,
+}
+
+impl Config {
+    /// Load the configuration from a given path.
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut file = File::open(path).context("could not open configuration file")?;
+        // toml crate won't allow reading from a file.
+        // See: https://github.com/toml-rs/toml/pull/349
+        let mut raw = Vec::new();
+        file.read_to_end(&mut raw)?;
+
+        Config::parse(std::str::from_utf8(&raw)?)
+    }
+
+    /// Parse and validate the configuration from a string.
+    fn parse(raw: &str) -> anyhow::Result<Self> {
+        let mut config: Config = toml::de::from_str(raw)?;
+        config.status_path = expand_home(&config.status_path)?;
+
+        for (name, pair) in &config.pairs {
+            for storage in [&pair.a, &pair.b] {
+                if !config.storages.contains_key(storage) {
+                    bail!("pair '{name}' references undefined storage '{storage}'");
+                }
+            }
+            config
+                .item_kind(pair)
+                .and_then(|_| pair.conflict_resolution())
+                .and_then(|_| pair.deletion_guard())
+                .with_context(|| format!("invalid pair '{name}'"))?;
+        }
+
+        Ok(config)
+    }
+
+    /// Returns the storage with a given name.
+    ///
+    /// # Panics
+    ///
+    /// If the storage is not defined. Storages referenced by pairs are validated when loading.
+    pub(crate) fn storage(&self, name: &str) -> &StorageConfig {
+        self.storages
+            .get(name)
+            .expect("storages referenced by pairs are validated when loading")
+    }
+
+    /// Determine which type of items a pair synchronises.
+    pub(crate) fn item_kind(&self, pair: &PairConfig) -> anyhow::Result<ItemKind> {
+        let kind_a = self.storage(&pair.a).item_kind();
+        let kind_b = self.storage(&pair.b).item_kind();
+        match (kind_a, kind_b) {
+            (Some(a), Some(b)) if a != b => {
+                bail!("cannot synchronise {a} with {b}")
+            }
+            (Some(kind), _) | (None, Some(kind)) => Ok(kind),
+            (None, None) => bail!(
+                "cannot determine whether storages contain calendars or address books; \
+                use 'ics' or 'vcf' as an extension"
+            ),
+        }
+    }
+}
+
+/// Type of items held by a storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ItemKind {
+    Calendar,
+    AddressBook,
+}
+
+impl std::fmt::Display for ItemKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ItemKind::Calendar => f.write_str("calendars"),
+            ItemKind::AddressBook => f.write_str("address books"),
+        }
+    }
+}
+
+/// A single storage.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum StorageConfig {
+    /// A local directory where each subdirectory is a collection. See [`FilesystemDefinition`].
+    Filesystem {
+        path: PathBuf,
+        extension: String,
+        #[serde(default)]
+        etag_mode: EtagMode,
+        #[serde(default)]
+        filename_policy: FilenamePolicy,
+        ignore: Option<String>,
+    },
+    /// A single local file, or one file per collection. See [`SinglefileDefinition`].
+    Singlefile { path: PathBuf },
+    /// A remote `CalDav` server.
+    Caldav {
+        #[serde(deserialize_with = "deserialize_uri")]
+        url: Uri,
+        #[serde(flatten)]
+        credentials: Credentials,
+        /// Only synchronise items which overlap this window. See [`TimeWindow`].
+        time_window: Option<TimeWindowConfig>,
+    },
+    /// A remote `CardDav` server.
+    Carddav {
+        #[serde(deserialize_with = "deserialize_uri")]
+        url: Uri,
+        #[serde(flatten)]
+        credentials: Credentials,
+    },
+    /// A remote icalendar file. See [`WebCalDefinition`].
+    Webcal {
+        #[serde(deserialize_with = "deserialize_uri")]
+        url: Uri,
+        collection_name: String,
+    },
+    /// A remote vcard file. See [`HttpVcardDefinition`].
+    Httpvcard {
+        #[serde(deserialize_with = "deserialize_uri")]
+        url: Uri,
+        collection_name: String,
+    },
+}
+
+impl StorageConfig {
+    /// Type of items held by this storage.
+    ///
+    /// Returns `None` for storages that can contain either kind.
+    fn item_kind(&self) -> Option<ItemKind> {
+        match self {
+            StorageConfig::Filesystem { extension, .. } => match extension.as_str() {
+                "ics" => Some(ItemKind::Calendar),
+                "vcf" => Some(ItemKind::AddressBook),
+                _ => None,
+            },
+            StorageConfig::Singlefile { path } => {
+                match path.extension().and_then(std::ffi::OsStr::to_str) {
+                    Some("ics") => Some(ItemKind::Calendar),
+                    Some("vcf") => Some(ItemKind::AddressBook),
+                    _ => None,
+                }
+            }
+            StorageConfig::Caldav { .. } | StorageConfig::Webcal { .. } => Some(ItemKind::Calendar),
+            StorageConfig::Carddav { .. } | StorageConfig::Httpvcard { .. } => {
+                Some(ItemKind::AddressBook)
+            }
+        }
+    }
+
+    /// Create a storage instance for calendars.
+    pub(crate) async fn calendar_storage(&self) -> anyhow::Result<Box<dyn Storage<IcsItem>>> {
+        let storage = match self {
+            StorageConfig::Filesystem {
+                path,
+                extension,
+                etag_mode,
+                filename_policy,
+                ignore,
+            } => {
+                let mut definition =
+                    FilesystemDefinition::<IcsItem>::new(expand_home(path)?, extension.clone())
+                        .with_etag_mode(*etag_mode)
+                        .with_filename_policy(*filename_policy);
+                if let Some(pattern) = ignore {
+                    definition = definition.with_ignore_pattern(pattern.clone());
+                }
+                definition.storage().await?
+            }
+            StorageConfig::Singlefile { path } => {
+                SinglefileDefinition::<IcsItem>::new(expand_home(path)?)
+                    .storage()
+                    .await?
+            }
+            StorageConfig::Caldav {
+                url,
+                credentials,
+                time_window,
+            } => {
+                CalDavDefinition {
+                    url: url.clone(),
+                    auth: credentials.auth()?,
+                    time_window: time_window.map(TimeWindow::from),
+                }
+                .storage()
+                .await?
+            }
+            StorageConfig::Webcal {
+                url,
+                collection_name,
+            } => {
+                WebCalDefinition {
+                    url: url.clone(),
+                    collection_name: collection_name.clone(),
+                }
+                .storage()
+                .await?
+            }
+            StorageConfig::Carddav { .. } | StorageConfig::Httpvcard { .. } => {
+                bail!("carddav and httpvcard storages contain address books")
+            }
+        };
+
+        Ok(storage)
+    }
+
+    /// Create a storage instance for address books.
+    pub(crate) async fn address_book_storage(&self) -> anyhow::Result<Box<dyn Storage<VcardItem>>> {
+        let storage = match self {
+            StorageConfig::Filesystem {
+                path,
+                extension,
+                etag_mode,
+                filename_policy,
+                ignore,
+            } => {
+                let mut definition =
+                    FilesystemDefinition::<VcardItem>::new(expand_home(path)?, extension.clone())
+                        .with_etag_mode(*etag_mode)
+                        .with_filename_policy(*filename_policy);
+                if let Some(pattern) = ignore {
+                    definition = definition.with_ignore_pattern(pattern.clone());
+                }
+                definition.storage().await?
+            }
+            StorageConfig::Singlefile { path } => {
+                SinglefileDefinition::<VcardItem>::new(expand_home(path)?)
+                    .storage()
+                    .await?
+            }
+            StorageConfig::Carddav { url, credentials } => {
+                CardDavDefinition {
+                    url: url.clone(),
+                    auth: credentials.auth()?,
+                }
+                .storage()
+                .await?
+            }
+            StorageConfig::Httpvcard {
+                url,
+                collection_name,
+            } => {
+                HttpVcardDefinition {
+                    url: url.clone(),
+                    collection_name: collection_name.clone(),
+                }
+                .storage()
+                .await?
+            }
+            StorageConfig::Caldav { .. } | StorageConfig::Webcal { .. } => {
+                bail!("caldav and webcal storages contain calendars")
+            }
+        };
+
+        Ok(storage)
+    }
+}
+
+/// A window of time around the current time, in days.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TimeWindowConfig {
+    past_days: Option<u64>,
+    future_days: Option<u64>,
+}
+
+impl From<TimeWindowConfig> for TimeWindow {
+    fn from(value: TimeWindowConfig) -> Self {
+        let days = |days: u64| Duration::from_secs(days * 24 * 60 * 60);
+        TimeWindow {
+            past: value.past_days.map(days),
+            future: value.future_days.map(days),
+        }
+    }
+}
+
+/// Credentials for authenticating with a remote server.
+#[derive(Deserialize, Debug)]
+pub(crate) struct Credentials {
+    username: Option<String>,
+    password: Option<String>,
+    /// A command which prints the password to standard output.
+    password_command: Option<Vec<String>>,
+}
+
+impl Credentials {
+    fn auth(&self) -> anyhow::Result<Auth> {
+        let Some(username) = &self.username else {
+            return Ok(Auth::None);
+        };
+        let password = match (&self.password, &self.password_command) {
+            (Some(_), Some(_)) => bail!("only one of password and password_command may be set"),
+            (Some(password), None) => Some(Password::from(password.as_str())),
+            (None, Some(command)) => Some(Password::from(run_password_command(command)?)),
+            (None, None) => None,
+        };
+
+        Ok(Auth::Basic {
+            username: username.clone(),
+            password,
+        })
+    }
+}
+
+/// Run a command and return the first line of its output.
+fn run_password_command(command: &[String]) -> anyhow::Result<String> {
+    let (program, args) = command
+        .split_first()
+        .context("password_command must not be empty")?;
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("failed to execute password command '{program}'"))?;
+    if !output.status.success() {
+        bail!("password command '{program}' failed: {}", output.status);
+    }
+
+    let stdout = String::from_utf8(output.stdout).context("password is not valid utf-8")?;
+    Ok(stdout.lines().next().unwrap_or_default().to_string())
+}
+
+/// A pair of storages which are synchronised with each other.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PairConfig {
+    /// Name of the first storage.
+    pub(crate) a: String,
+    /// Name of the second storage.
+    pub(crate) b: String,
+    /// Collections to synchronise.
+    pub(crate) collections: Vec<CollectionConfig>,
+    /// How to resolve conflicts. See [`ConflictResolutionConfig`].
+    #[serde(default)]
+    conflict_resolution: Option<ConflictResolutionConfig>,
+    /// Maximum share of a collection's items that may be deleted in a single synchronisation.
+    ///
+    /// Only applies to collections which previously had at least ten items.
+    #[serde(default)]
+    max_deletion_ratio: Option<f64>,
+    /// Whether to abort if a collection that had items is now empty on one side.
+    #[serde(default)]
+    abort_when_emptied: Option<bool>,
+    /// Assign new UIDs to items which share a UID with another item in the same collection.
+    #[serde(default)]
+    pub(crate) repair_duplicate_uids: bool,
+}
+
+impl PairConfig {
+    /// Strategy used to resolve conflicts for this pair.
+    pub(crate) fn conflict_resolution(&self) -> anyhow::Result<ConflictResolution> {
+        match &self.conflict_resolution {
+            None => Ok(ConflictResolution::default()),
+            Some(ConflictResolutionConfig::Named(name)) => match name.as_str() {
+                "abort" => Ok(ConflictResolution::Abort),
+                "a wins" => Ok(ConflictResolution::KeepA),
+                "b wins" => Ok(ConflictResolution::KeepB),
+                "newest" => Ok(ConflictResolution::Newest),
+                _ => bail!("unknown conflict resolution strategy '{name}'"),
+            },
+            Some(ConflictResolutionConfig::Command(args)) => match args.split_first() {
+                Some((first, command)) if first == "command" && !command.is_empty() => {
+                    Ok(ConflictResolution::Command(command.to_vec()))
+                }
+                _ => bail!("conflict resolution command must be [\"command\", PROGRAM, ARGS...]"),
+            },
+        }
+    }
+
+    /// Limits used to prevent mass deletions for this pair.
+    pub(crate) fn deletion_guard(&self) -> anyhow::Result<DeletionGuard> {
+        let mut guard = DeletionGuard::default();
+        if let Some(ratio) = self.max_deletion_ratio {
+            if !(0.0..=1.0).contains(&ratio) {
+                bail!("max_deletion_ratio must be between 0.0 and 1.0");
+            }
+            guard.max_deletion_ratio = Some(ratio);
+        }
+        if let Some(abort) = self.abort_when_emptied {
+            guard.abort_when_emptied = abort;
+        }
+        Ok(guard)
+    }
+}
+
+/// Strategy to resolve conflicts.
+///
+/// Either one of `"abort"` (the default), `"a wins"`, `"b wins"`, `"newest"`, or an external
+/// command to merge both versions, e.g.: `["command", "vimdiff"]`.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ConflictResolutionConfig {
+    Named(String),
+    Command(Vec<String>),
+}
+
+/// A collection to be synchronised between both storages of a pair.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub(crate) enum CollectionConfig {
+    /// A collection with the same name on both sides.
+    Direct(String),
+    /// A collection with a different name on each side.
+    Mapped { name: String, a: String, b: String },
+}
+
+impl From<&CollectionConfig> for CollectionMapping {
+    fn from(value: &CollectionConfig) -> Self {
+        match value {
+            CollectionConfig::Direct(name) => CollectionMapping::Direct(name.clone()),
+            CollectionConfig::Mapped { name, a, b } => CollectionMapping::Mapped {
+                name: name.clone(),
+                a: a.clone(),
+                b: b.clone(),
+            },
+        }
+    }
+}
+
+fn deserialize_uri<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Uri, D::Error> {
+    let raw = String::deserialize(deserializer)?;
+    Uri::try_from(raw).map_err(serde::de::Error::custom)
+}
+
+/// Expand a leading `~` into the user's home directory.
+fn expand_home(path: &Path) -> anyhow::Result<PathBuf> {
+    match path.strip_prefix("~") {
+        Ok(rest) => {
+            let home = std::env::var_os("HOME").context("$HOME is not defined")?;
+            Ok(PathBuf::from(home).join(rest))
+        }
+        Err(_) => Ok(path.to_path_buf()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vstorage::filesystem::{EtagMode, FilenamePolicy};
+    use vstorage::sync::plan::{ConflictResolution, DeletionGuard};
+
+    use super::{CollectionConfig, Config, ItemKind, StorageConfig, TimeWindowConfig};
+
+    const EXAMPLE: &str = r#"
+        status_path = "/tmp/status"
+
+        [storages.local]
+        type = "filesystem"
+        path = "/tmp/calendars"
+        extension = "ics"
+        etag_mode = "content_hash"
+        filename_policy = "hash_uid"
+
+        [storages.remote]
+        type = "caldav"
+        url = "https://example.com/"
+        username = "hugo"
+        password = "secret"
+        time_window = { past_days = 365, future_days = 730 }
+
+        [storages.holidays]
+        type = "webcal"
+        url = "https://example.com/holidays.ics"
+        collection_name = "holidays"
+
+        [storages.exported]
+        type = "singlefile"
+        path = "~/exports/*.ics"
+
+        [pairs.calendars]
+        a = "local"
+        b = "remote"
+        collections = ["personal", { name = "work", a = "work", b = "work-calendar" }]
+        conflict_resolution = ["command", "vimdiff"]
+        max_deletion_ratio = 0.25
+        abort_when_emptied = false
+        repair_duplicate_uids = true
+
+        [pairs.holidays]
+        a = "holidays"
+        b = "local"
+        collections = ["holidays"]
+    "#;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(EXAMPLE).unwrap();
+
+        assert_eq!(config.storages.len(), 4);
+        assert!(matches!(
+            config.storage("local"),
+            StorageConfig::Filesystem {
+                etag_mode: EtagMode::ContentHash,
+                filename_policy: FilenamePolicy::HashUid,
+                ..
+            }
+        ));
+        assert!(matches!(
+            config.storage("remote"),
+            StorageConfig::Caldav {
+                time_window: Some(TimeWindowConfig {
+                    past_days: Some(365),
+                    future_days: Some(730)
+                }),
+                ..
+            }
+        ));
+        assert_eq!(
+            config.storage("exported").item_kind(),
+            Some(ItemKind::Calendar)
+        );
+
+        let pair = &config.pairs["calendars"];
+        assert_eq!(config.item_kind(pair).unwrap(), ItemKind::Calendar);
+        assert!(matches!(
+            &pair.collections[1],
+            CollectionConfig::Mapped { name, a, b } if name == "work" && a == "work" && b == "work-calendar"
+        ));
+        assert_eq!(
+            pair.conflict_resolution().unwrap(),
+            ConflictResolution::Command(vec!["vimdiff".into()])
+        );
+        assert_eq!(
+            config.pairs["holidays"].conflict_resolution().unwrap(),
+            ConflictResolution::Abort
+        );
+        assert_eq!(
+            pair.deletion_guard().unwrap(),
+            DeletionGuard {
+                max_deletion_ratio: Some(0.25),
+                min_items: 10,
+                abort_when_emptied: false,
+            }
+        );
+        assert!(pair.repair_duplicate_uids);
+        assert_eq!(
+            config.pairs["holidays"].deletion_guard().unwrap(),
+            DeletionGuard::default()
+        );
+    }
+
+    #[test]
+    fn test_invalid_conflict_resolution() {
+        let raw = r#"
+            status_path = "/tmp/status"
+
+            [storages.local]
+            type = "filesystem"
+            path = "/tmp/calendars"
+            extension = "ics"
+
+            [pairs.calendars]
+            a = "local"
+            b = "local"
+            collections = []
+            conflict_resolution = "c wins"
+        "#;
+        assert!(Config::parse(raw).is_err());
+    }
+
+    #[test]
+    fn test_undefined_storage() {
+        let raw = r#"
+            status_path = "/tmp/status"
+
+            [pairs.calendars]
+            a = "local"
+            b = "remote"
+            collections = []
+        "#;
+        assert!(Config::parse(raw).is_err());
+    }
+
+    #[test]
+    fn test_mismatched_item_kinds() {
+        let raw = r#"
+            status_path = "/tmp/status"
+
+            [storages.calendars]
+            type = "filesystem"
+            path = "/tmp/calendars"
+            extension = "ics"
+
+            [storages.contacts]
+            type = "carddav"
+            url = "https://example.com/"
+
+            [pairs.broken]
+            a = "calendars"
+            b = "contacts"
+            collections = []
+        "#;
+        assert!(Config::parse(raw).is_err());
+    }
+}
@@ -1,7 +1,18 @@
+#![deny(clippy::pedantic)]
+
 // Copyright 2023 Hugo Osvaldo Barrera
 //
 // SPDX-License-Identifier: EUPL-1.2
 
-fn main() {
-    todo!();
+use clap::Parser;
+
+mod cli;
+mod config;
+mod sync;
+
+fn main() -> anyhow::Result<()> {
+    let cli = cli::Cli::parse();
+    simple_logger::init_with_level(cli.log_level()).expect("logger configuration is valid");
+
+    cli.execute()
 }
@@ -0,0 +1,294 @@
+// Copyright 2023 Hugo Osvaldo Barrera
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+//! Implementation of the `sync` command.
+
+use anyhow::{bail, Context};
+use log::{error, info, warn};
+use vstorage::{
+    base::{Item, Storage},
+    sync::{
+        plan::{DeletionGuard, Plan},
+        CollectionMapping, Side, StatusStore, StoragePair,
+    },
+    ErrorKind,
+};
+
+use crate::config::{Config, ItemKind, PairConfig};
+
+/// Whether to execute synchronisation or merely print what would be done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Mode {
+    Execute,
+    /// Print planned operations in a human-readable format.
+    DryRun,
+    /// Print planned operations as JSON.
+    DryRunJson,
+}
+
+/// Synchronise the given pairs, or all pairs if none are specified.
+///
+/// If `force_deletions` is true, the limits on deletions configured for each pair are ignored.
+#[tokio::main(flavor = "current_thread")]
+pub(crate) async fn execute(
+    config: &Config,
+    pairs: &[String],
+    mode: Mode,
+    force_deletions: bool,
+) -> anyhow::Result<()> {
+    let selected = if pairs.is_empty() {
+        config.pairs.iter().collect::<Vec<_>>()
+    } else {
+        pairs
+            .iter()
+            .map(|name| {
+                config
+                    .pairs
+                    .get_key_value(name)
+                    .with_context(|| format!("pair '{name}' is not defined"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?
+    };
+
+    let status = StatusStore::new(config.status_path.clone());
+    let mut failed = 0;
+    for (name, pair) in selected {
+        info!("Synchronising pair {name}.");
+        let options = SyncOptions {
+            mode,
+            force_deletions,
+        };
+        if let Err(err) = sync_pair(config, &status, name, pair, options).await {
+            error!("Failed to synchronise pair {name}: {err:#}");
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        bail!("{failed} pair(s) failed to synchronise");
+    }
+    Ok(())
+}
+
+/// Options which apply to all pairs being synchronised.
+#[derive(Clone, Copy)]
+struct SyncOptions {
+    mode: Mode,
+    force_deletions: bool,
+}
+
+async fn sync_pair(
+    config: &Config,
+    status: &StatusStore,
+    name: &str,
+    pair: &PairConfig,
+    options: SyncOptions,
+) -> anyhow::Result<()> {
+    match config.item_kind(pair)? {
+        ItemKind::Calendar => {
+            let storage_a = config
+                .storage(&pair.a)
+                .calendar_storage()
+                .await
+                .with_context(|| format!("failed to initialise storage '{}'", pair.a))?;
+            let storage_b = config
+                .storage(&pair.b)
+                .calendar_storage()
+                .await
+                .with_context(|| format!("failed to initialise storage '{}'", pair.b))?;
+            sync_storages(status, name, pair, options, storage_a, storage_b).await
+        }
+        ItemKind::AddressBook => {
+            let storage_a = config
+                .storage(&pair.a)
+                .address_book_storage()
+                .await
+                .with_context(|| format!("failed to initialise storage '{}'", pair.a))?;
+            let storage_b = config
+                .storage(&pair.b)
+                .address_book_storage()
+                .await
+                .with_context(|| format!("failed to initialise storage '{}'", pair.b))?;
+            sync_storages(status, name, pair, options, storage_a, storage_b).await
+        }
+    }
+}
+
+async fn sync_storages<I: Item>(
+    status: &StatusStore,
+    name: &str,
+    pair: &PairConfig,
+    options: SyncOptions,
+    mut storage_a: Box<dyn Storage<I>>,
+    mut storage_b: Box<dyn Storage<I>>,
+) -> anyhow::Result<()> {
+    let previous_a = status
+        .load(name, Side::A)
+        .await
+        .context("failed to load previous state")?;
+    let previous_b = status
+        .load(name, Side::B)
+        .await
+        .context("failed to load previous state")?;
+
+    let collections = pair
+        .collections
+        .iter()
+        .map(CollectionMapping::from)
+        .collect();
+    let mut storage_pair = StoragePair::new(
+        storage_a.as_mut(),
+        storage_b.as_mut(),
+        &previous_a,
+        &previous_b,
+        collections,
+    )
+    .await
+    .context("failed to determine current state of storages")?;
+
+    if pair.repair_duplicate_uids && options.mode == Mode::Execute {
+        let repaired = storage_pair
+            .repair_duplicate_uids()
+            .await
+            .context("failed to repair duplicate UIDs")?;
+        if repaired > 0 {
+            info!("Assigned new UIDs to {repaired} item(s) with duplicate UIDs.");
+        }
+    }
+
+    let deletion_guard = if options.force_deletions {
+        DeletionGuard::disabled()
+    } else {
+        pair.deletion_guard()?
+    };
+    let plan = Plan::for_storage_pair(&storage_pair)
+        .with_conflict_resolution(pair.conflict_resolution()?)
+        .with_deletion_guard(deletion_guard);
+    match options.mode {
+        Mode::Execute => {}
+        Mode::DryRun => {
+            println!("Pair {name}:");
+            if plan.operations().next().is_none() {
+                println!("Nothing to do.");
+            }
+            print!("{plan}");
+            return Ok(());
+        }
+        Mode::DryRunJson => {
+            let output = serde_json::json!({ "pair": name, "operations": plan });
+            println!("{output}");
+            return Ok(());
+        }
+    }
+    let result = plan.execute(&mut storage_pair).await;
+
+    // States are saved even if there were errors; storages may have been partially modified.
+    status
+        .save(name, Side::A, &result.state_a)
+        .await
+        .context("failed to save state")?;
+    status
+        .save(name, Side::B, &result.state_b)
+        .await
+        .context("failed to save state")?;
+
+    for err in &result.errors {
+        error!("{err}");
+    }
+    if result
+        .errors
+        .iter()
+        .any(|err| err.kind() == Some(ErrorKind::TooManyDeletions))
+    {
+        warn!("Some collections were not synchronised; use --force-deletions to proceed anyway.");
+    }
+    if !result.synchronised_ok() {
+        bail!(
+            "{} error(s) occurred during synchronisation",
+            result.errors.len()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, read_dir, remove_file, write};
+
+    use crate::config::Config;
+
+    use super::Mode;
+
+    #[test]
+    fn test_sync_filesystem_pair() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        write(
+            &config_path,
+            format!(
+                r#"
+                status_path = "{root}/status"
+
+                [storages.one]
+                type = "filesystem"
+                path = "{root}/one"
+                extension = "ics"
+
+                [storages.two]
+                type = "filesystem"
+                path = "{root}/two"
+                extension = "ics"
+
+                [pairs.calendars]
+                a = "one"
+                b = "two"
+                collections = ["personal"]
+                "#,
+                root = dir.path().display()
+            ),
+        )
+        .unwrap();
+
+        create_dir_all(dir.path().join("one/personal")).unwrap();
+        create_dir_all(dir.path().join("two")).unwrap();
+        let event = [
+            "BEGIN:VCALENDAR",
+            "BEGIN:VEVENT",
+            "UID:test-event",
+            "SUMMARY:Test event",
+            "END:VEVENT",
+            "END:VCALENDAR",
+        ]
+        .join("\r\n");
+        write(dir.path().join("one/personal/test.ics"), event).unwrap();
+
+        let config = Config::load(&config_path).unwrap();
+        super::execute(&config, &[], Mode::DryRun, false).unwrap();
+        assert!(!dir.path().join("two/personal").exists());
+        assert!(!dir.path().join("status").exists());
+
+        super::execute(&config, &[], Mode::Execute, false).unwrap();
+
+        let copied = read_dir(dir.path().join("two/personal")).unwrap().count();
+        assert_eq!(copied, 1);
+        assert!(dir.path().join("status/calendars.a.json").exists());
+        assert!(dir.path().join("status/calendars.b.json").exists());
+
+        // A second run should find nothing to do.
+        super::execute(&config, &[], Mode::Execute, false).unwrap();
+        let copied = read_dir(dir.path().join("two/personal")).unwrap().count();
+        assert_eq!(copied, 1);
+
+        // Emptying a collection must not propagate unless explicitly forced.
+        remove_file(dir.path().join("one/personal/test.ics")).unwrap();
+        super::execute(&config, &[], Mode::Execute, false).unwrap_err();
+        let remaining = read_dir(dir.path().join("two/personal")).unwrap().count();
+        assert_eq!(remaining, 1);
+
+        super::execute(&config, &[], Mode::Execute, true).unwrap();
+        let remaining = read_dir(dir.path().join("two/personal")).unwrap().count();
+        assert_eq!(remaining, 0);
+    }
+}
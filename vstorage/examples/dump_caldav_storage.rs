@@ -20,6 +20,7 @@ async fn create_caldav_from_env() -> Box<dyn Storage<IcsItem>> {
             username,
             password: Some(password),
         },
+        time_window: None,
     }
     .storage()
     .await
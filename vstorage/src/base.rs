@@ -84,6 +84,39 @@ pub trait Storage<I: Item>: Sync + Send {
     /// Enumerates items in a given collection.
     async fn list_items(&self, collection: &Collection) -> Result<Vec<ItemRef>>;
 
+    /// Enumerates items in a given collection which changed since a previous call.
+    ///
+    /// `sync_token` must be a value returned by a previous call for the same collection. If it is
+    /// `None`, all items in the collection are returned as changed.
+    ///
+    /// The default implementation always returns an error.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ErrorKind::Unsupported`] if the storage or collection do not support this
+    /// operation. Callers should fall back to [`Storage::list_items`] on any error, since tokens
+    /// may expire.
+    ///
+    /// [`ErrorKind::Unsupported`]: crate::ErrorKind::Unsupported
+    async fn changed_items(
+        &self,
+        _collection: &Collection,
+        _sync_token: Option<&str>,
+    ) -> Result<ItemChanges> {
+        Err(crate::ErrorKind::Unsupported.into())
+    }
+
+    /// Enumerates items which exist in a given collection but are omitted by
+    /// [`Storage::list_items`] on purpose (e.g.: because they are outside of a time window).
+    ///
+    /// When synchronising, these items are neither considered deleted nor changed. The result may
+    /// also include items which are listed normally.
+    ///
+    /// The default implementation returns no items.
+    async fn unlisted_items(&self, _collection: &Collection) -> Result<Vec<ItemRef>> {
+        Ok(Vec::new())
+    }
+
     /// Fetch a single item from given collection.
     async fn get_item(&self, collection: &Collection, href: &str) -> Result<(I, Etag)>;
 
@@ -138,6 +171,8 @@ pub trait Storage<I: Item>: Sync + Send {
 /// [`Storage::get_many_items`].
 pub struct Collection {
     href: String,
+    supports_sync: bool,
+    tag: Option<String>,
 }
 
 impl Collection {
@@ -157,7 +192,37 @@ impl Collection {
     }
 
     pub(crate) fn new(href: String) -> Collection {
-        Collection { href }
+        Collection {
+            href,
+            supports_sync: false,
+            tag: None,
+        }
+    }
+
+    /// Sets whether this collection supports [`Storage::changed_items`].
+    pub(crate) fn with_sync_support(mut self, supports_sync: bool) -> Collection {
+        self.supports_sync = supports_sync;
+        self
+    }
+
+    #[inline]
+    pub(crate) fn supports_sync(&self) -> bool {
+        self.supports_sync
+    }
+
+    /// Sets a tag which changes whenever this collection or any of its items change.
+    pub(crate) fn with_tag(mut self, tag: Option<String>) -> Collection {
+        self.tag = tag;
+        self
+    }
+
+    /// A tag which changes whenever this collection or any of its items change.
+    ///
+    /// Only some storages (and only when discovering collections) provide this value. If two
+    /// values are equal, the collection has not changed.
+    #[must_use]
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
     }
 }
 
@@ -167,6 +232,16 @@ pub struct ItemRef {
     pub etag: Etag,
 }
 
+/// Changes to the items in a collection. See [`Storage::changed_items`].
+pub struct ItemChanges {
+    /// Items which were created or modified.
+    pub changed: Vec<ItemRef>,
+    /// Items which were deleted.
+    pub deleted: Vec<Href>,
+    /// Opaque token used to request further changes.
+    pub sync_token: String,
+}
+
 /// Types of items that can be held in collections.
 ///
 /// Storages can contain items of a concrete type implementing this trait. This trait defines how
@@ -216,7 +291,7 @@ where
 /// Note that this is not a proper validating parser for icalendar or vcard; it's a very simple
 /// one with the sole purpose of extracing a UID. Proper parsing of components is out of scope,
 /// since we want to enable operating on potentially invalid items too.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct IcsItem {
     // TODO: make this Vec<u8> instead?
     raw: String,
@@ -225,6 +300,7 @@ pub struct IcsItem {
 /// Properties supported for calendars.
 ///
 /// This is strongly based on the properties supported by `CalDav`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[non_exhaustive]
 pub enum CalendarProperty {
     /// A colour to be used when displaying this collection.
@@ -248,39 +324,23 @@ impl Item for IcsItem {
     /// item is copied across storages and storage types.
     #[must_use]
     fn uid(&self) -> Option<String> {
-        let mut lines = self.raw.split_terminator("\r\n");
-        let mut uid = lines
-            .find_map(|line| line.strip_prefix("UID:"))
-            .map(String::from)?;
-
-        // If the following lines start with a space or tab, they're a continuation of the UID.
+        // Lines may be folded and the property may have parameters.
         // See: https://www.rfc-editor.org/rfc/rfc5545#section-3.1
-        lines
-            .map_while(|line| line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')))
-            .for_each(|part| uid.push_str(part));
-
-        Some(uid)
+        crate::util::property_value(&self.raw, "UID")
     }
 
-    /// Returns the hash of the raw content.
-    ///
-    /// This does some minimal normalisations of the items before hashing:
+    /// Returns the hash of the normalised content.
     ///
-    /// - Ignores the `PROPID` field. Two item where only this field varies are
-    ///   considered equivalent.
+    /// Items are normalised before hashing so that two equivalent items return the same hash.
+    /// Differences in line folding, property order, timezone names and properties that servers
+    /// commonly rewrite (`PRODID`, `DTSTAMP`, `X-` properties) are ignored.
     ///
     /// This is used as a fallback when a storage backend doesn't provide [`Etag`] values, or when
     /// an item is missing its `UID`.
     ///
-    /// [`util::hash`]: crate::util::hash
     /// [`Etag`]: crate::Etag
     #[must_use]
     fn hash(&self) -> String {
-        // TODO: Need to keep in mind that:
-        //  - Timezones may be renamed and that has no meaning.
-        //  - Some props may be re-sorted, but the Item is still the same.
-        //
-        //  See vdirsyncer's vobject.py for details on this.
         crate::util::hash(&self.raw)
     }
 
@@ -292,14 +352,18 @@ impl Item for IcsItem {
 
     /// Returns a new copy of this Item with the supplied UID.
     ///
-    /// # Panics
-    ///
-    /// This function is not yet implemented.
+    /// All `VEVENT`, `VTODO` and `VJOURNAL` components sharing this item's UID (including
+    /// recurrence overrides) are updated. If this item has no UID, one is added to each of these
+    /// components. The UIDs of other components (e.g.: `VALARM`) are left untouched.
     #[must_use]
-    fn with_uid(&self, _new_uid: &str) -> Self {
-        // The logic in vdirsyncer/vobject.py::Item.with_uid seems pretty solid.
-        // TODO: this really needs to be done, although its absence only blocks syncing broken items.
-        todo!()
+    fn with_uid(&self, new_uid: &str) -> Self {
+        let raw = crate::util::replace_uid(
+            &self.raw,
+            &["VEVENT", "VTODO", "VJOURNAL"],
+            self.uid().as_deref(),
+            new_uid,
+        );
+        IcsItem { raw }
     }
 
     #[inline]
@@ -322,7 +386,7 @@ mod tests {
     // vdirsyncer is expected to handle invalid input gracefully and sync it as-is,
     // so this is not really a problem.
 
-    use crate::base::{IcsItem, Item, Storage};
+    use crate::base::{IcsItem, Item, Storage, VcardItem};
 
     fn item_from_raw(raw: String) -> IcsItem {
         IcsItem { raw }
@@ -386,10 +450,76 @@ mod tests {
         assert_eq!(item.uid(), None);
         assert_eq!(
             item.ident(),
-            "23A1B4246052E5BBB7AED65EDD759EBB03EF314DB055C109716D0301F9AC8E19"
+            "9C3878FA02431EDA00B1B7E6ECC7B41BE1DE9252B1F306D63DAA09E67D2A51BF"
         );
     }
 
+    #[test]
+    fn test_with_uid() {
+        let raw = [
+            "BEGIN:VCALENDAR",
+            "BEGIN:VEVENT",
+            "UID;X-TEST=yes:old-",
+            " uid",
+            "SUMMARY:Weekly",
+            "BEGIN:VALARM",
+            "UID:alarm",
+            "END:VALARM",
+            "END:VEVENT",
+            "BEGIN:VEVENT",
+            "UID:old-uid",
+            "RECURRENCE-ID:20230110T100000Z",
+            "END:VEVENT",
+            "END:VCALENDAR",
+            "",
+        ]
+        .join("\r\n");
+        let item = item_from_raw(raw).with_uid("new-uid");
+        let expected = [
+            "BEGIN:VCALENDAR",
+            "BEGIN:VEVENT",
+            "UID:new-uid",
+            "SUMMARY:Weekly",
+            "BEGIN:VALARM",
+            "UID:alarm",
+            "END:VALARM",
+            "END:VEVENT",
+            "BEGIN:VEVENT",
+            "UID:new-uid",
+            "RECURRENCE-ID:20230110T100000Z",
+            "END:VEVENT",
+            "END:VCALENDAR",
+            "",
+        ]
+        .join("\r\n");
+        assert_eq!(item.as_str(), expected);
+        assert_eq!(item.uid().as_deref(), Some("new-uid"));
+
+        let raw = [
+            "BEGIN:VCALENDAR",
+            "BEGIN:VTODO",
+            "END:VTODO",
+            "END:VCALENDAR",
+        ]
+        .join("\r\n");
+        let item = item_from_raw(raw).with_uid("added");
+        assert_eq!(item.uid().as_deref(), Some("added"));
+    }
+
+    #[test]
+    fn test_vcard_with_uid() {
+        let raw = ["BEGIN:VCARD", "UID:hello", "FN:Someone", "END:VCARD"].join("\r\n");
+        let item = VcardItem::from(raw).with_uid("bye");
+        assert_eq!(
+            item.as_str(),
+            ["BEGIN:VCARD", "UID:bye", "FN:Someone", "END:VCARD"].join("\r\n")
+        );
+
+        let raw = ["BEGIN:VCARD", "FN:Someone", "END:VCARD"].join("\r\n");
+        let item = VcardItem::from(raw).with_uid("new");
+        assert_eq!(item.uid().as_deref(), Some("new"));
+    }
+
     #[test]
     fn test_storage_is_object_safe() {
         #[allow(dead_code)]
@@ -402,7 +532,7 @@ mod tests {
 /// Note that this is not a proper validating parser for vcard; it's a very simple one with the
 /// sole purpose of extracing a UID. Proper parsing of components is out of scope, since we want to
 /// enable operating on potentially invalid items too.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct VcardItem {
     // TODO: make this Vec<u8> instead?
     raw: String,
@@ -411,6 +541,7 @@ pub struct VcardItem {
 /// Properties supported for address books.
 ///
 /// This is strongly based on the properties supported by `CardDav`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[non_exhaustive]
 pub enum AddressBookProperty {
     DisplayName,
@@ -426,26 +557,20 @@ impl Item for VcardItem {
     /// item is copied across storages and storage types.
     #[must_use]
     fn uid(&self) -> Option<String> {
-        let mut lines = self.raw.split_terminator("\r\n");
-        let mut uid = lines
-            .find_map(|line| line.strip_prefix("UID:"))
-            .map(String::from)?;
-
-        // If the following lines start with a space or tab, they're a continuation of the UID.
+        // Lines may be folded and the property may have parameters.
         // See: https://www.rfc-editor.org/rfc/rfc6350#section-3.2
-        lines
-            .map_while(|line| line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')))
-            .for_each(|part| uid.push_str(part));
-
-        Some(uid)
+        crate::util::property_value(&self.raw, "UID")
     }
 
-    /// Returns the hash of the raw content.
+    /// Returns the hash of the normalised content.
+    ///
+    /// Items are normalised before hashing so that two equivalent items return the same hash.
+    /// Differences in line folding, property order and properties that servers commonly rewrite
+    /// (`PRODID`, `X-` properties) are ignored.
     ///
     /// This is used as a fallback when a storage backend doesn't provide [`Etag`] values, or when
     /// an item is missing its `UID`.
     ///
-    /// [`util::hash`]: crate::util::hash
     /// [`Etag`]: crate::Etag
     #[must_use]
     fn hash(&self) -> String {
@@ -460,14 +585,11 @@ impl Item for VcardItem {
 
     /// Returns a new copy of this Item with the supplied UID.
     ///
-    /// # Panics
-    ///
-    /// This function is not yet implemented.
+    /// If this item has no UID, one is added.
     #[must_use]
-    fn with_uid(&self, _new_uid: &str) -> Self {
-        // The logic in vdirsyncer/vobject.py::Item.with_uid seems pretty solid.
-        // TODO: this really needs to be done, although its absence only blocks syncing broken items.
-        todo!()
+    fn with_uid(&self, new_uid: &str) -> Self {
+        let raw = crate::util::replace_uid(&self.raw, &["VCARD"], self.uid().as_deref(), new_uid);
+        VcardItem { raw }
     }
 
     #[inline]
@@ -4,19 +4,44 @@
 
 //! A [`CalDavStorage`] is a single caldav repository, as specified in rfc4791.
 
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use async_trait::async_trait;
 use http::Uri;
 use libdav::auth::Auth;
 use libdav::dav::mime_types;
+use libdav::property::{CalendarColour, CalendarDescription, CalendarOrder, DisplayName};
+use libdav::query::{CalendarComponent, CalendarQuery, QueriedResource};
 use libdav::CalDavClient;
 
-use crate::base::{CalendarProperty, Collection, Definition, IcsItem, Item, ItemRef, Storage};
+use crate::base::{
+    CalendarProperty, Collection, Definition, IcsItem, Item, ItemChanges, ItemRef, Storage,
+};
 use crate::{Error, ErrorKind, Etag, Href, Result};
 
 #[derive(Debug)]
 pub struct CalDavDefinition {
     pub url: Uri,
     pub auth: Auth,
+    /// Only expose items which overlap this window of time.
+    ///
+    /// Items are filtered by the server using `calendar-query` reports. Items which move out of
+    /// the window (including due to the passage of time) are no longer listed, but are reported
+    /// via [`Storage::unlisted_items`], so synchronisation does not consider them deleted.
+    /// Listing fails if the server truncates the results of these reports.
+    pub time_window: Option<TimeWindow>,
+}
+
+/// A window of time relative to the current time.
+///
+/// Either end may be `None`, in which case the window is unbounded in that direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    /// How far into the past the window extends.
+    pub past: Option<Duration>,
+    /// How far into the future the window extends.
+    pub future: Option<Duration>,
 }
 
 impl From<libdav::BootstrapError> for Error {
@@ -43,7 +68,10 @@ impl Definition<IcsItem> for CalDavDefinition {
             .auto_bootstrap()
             .await?;
 
-        Ok(Box::from(CalDavStorage { client }))
+        Ok(Box::from(CalDavStorage {
+            client,
+            time_window: self.time_window,
+        }))
     }
 }
 
@@ -52,6 +80,7 @@ impl Definition<IcsItem> for CalDavDefinition {
 /// A single storage represents a single server with a specific set of credentials.
 pub struct CalDavStorage {
     client: CalDavClient,
+    time_window: Option<TimeWindow>,
 }
 
 #[async_trait]
@@ -82,7 +111,11 @@ impl Storage<IcsItem> for CalDavStorage {
             .find_calendars(None)
             .await?
             .into_iter()
-            .map(|collection| Collection::new(collection.href))
+            .map(|collection| {
+                Collection::new(collection.href)
+                    .with_sync_support(collection.supports_sync)
+                    .with_tag(collection.ctag)
+            })
             .collect::<Vec<_>>();
         Ok(x)
     }
@@ -130,11 +163,11 @@ impl Storage<IcsItem> for CalDavStorage {
         // TODO: specific error kind type for MissingEtag?
 
         // TODO: if no etag -> use force deletion (and warn)
-        let collection = Collection::new(href.to_string());
 
         // TODO: verify that the collection is actually a calendar collection?
         // This could be done by using discover above.
-        let items = self.list_items(&collection).await?;
+        // Not using `list_items`, which may only list items within the time window.
+        let items = self.client.list_resources(href).await?;
         if !items.is_empty() {
             return Err(ErrorKind::CollectionNotEmpty.into());
         }
@@ -150,20 +183,77 @@ impl Storage<IcsItem> for CalDavStorage {
         Ok(Collection::new(href.to_string()))
     }
 
+    /// Enumerates items in a collection.
+    ///
+    /// If a [`TimeWindow`] has been configured, only items which overlap it are listed.
     async fn list_items(&self, collection: &Collection) -> Result<Vec<ItemRef>> {
-        let response = self.client.list_resources(collection.href()).await?;
-        let mut items = Vec::with_capacity(response.len());
-        for r in response {
-            items.push(ItemRef {
-                href: r.href,
-                etag: r
-                    .details
-                    .etag
-                    .ok_or(Error::from(ErrorKind::InvalidData))?
-                    .into(),
-            });
+        if let Some(window) = &self.time_window {
+            let mut items = Vec::new();
+            for r in self.query_window(collection, window, false).await? {
+                items.push(ItemRef {
+                    href: r.href,
+                    etag: r.etag.into(),
+                });
+            }
+            return Ok(items);
+        }
+
+        self.list_all_items(collection).await
+    }
+
+    /// Enumerates all items in a collection if a [`TimeWindow`] has been configured.
+    ///
+    /// This includes items which are listed normally, since only their `href` and etag are
+    /// requested.
+    async fn unlisted_items(&self, collection: &Collection) -> Result<Vec<ItemRef>> {
+        if self.time_window.is_none() {
+            return Ok(Vec::new());
+        }
+        self.list_all_items(collection).await
+    }
+
+    /// Enumerates changed items using a `sync-collection` report (RFC 6578).
+    ///
+    /// Only supported for collections which advertise support for this report when discovered,
+    /// and only if no [`TimeWindow`] has been configured.
+    async fn changed_items(
+        &self,
+        collection: &Collection,
+        sync_token: Option<&str>,
+    ) -> Result<ItemChanges> {
+        if !collection.supports_sync() || self.time_window.is_some() {
+            return Err(ErrorKind::Unsupported.into());
+        }
+
+        let mut changed = Vec::new();
+        let mut deleted = Vec::new();
+        let mut token = sync_token.map(String::from);
+        loop {
+            let result = self
+                .client
+                .sync_collection(collection.href(), token.as_deref())
+                .await?;
+            for r in result.changed {
+                changed.push(ItemRef {
+                    href: r.href,
+                    etag: r
+                        .details
+                        .etag
+                        .ok_or(Error::from(ErrorKind::InvalidData))?
+                        .into(),
+                });
+            }
+            deleted.extend(result.deleted);
+            if !result.truncated {
+                return Ok(ItemChanges {
+                    changed,
+                    deleted,
+                    sync_token: result.sync_token,
+                });
+            }
+            // Results were truncated; request the remaining changes.
+            token = Some(result.sync_token);
         }
-        Ok(items)
     }
 
     async fn get_item(&self, collection: &Collection, href: &str) -> Result<(IcsItem, Etag)> {
@@ -211,6 +301,18 @@ impl Storage<IcsItem> for CalDavStorage {
     }
 
     async fn get_all_items(&self, collection: &Collection) -> Result<Vec<(Href, IcsItem, Etag)>> {
+        if let Some(window) = &self.time_window {
+            let mut items = Vec::new();
+            for r in self.query_window(collection, window, true).await? {
+                let data = r.data.ok_or(Error::new(
+                    ErrorKind::InvalidData,
+                    "missing calendar data in response",
+                ))?;
+                items.push((r.href, IcsItem::from(data), r.etag.into()));
+            }
+            return Ok(items);
+        }
+
         let list = self.list_items(collection).await?;
         let hrefs = list.iter().map(|i| i.href.as_str()).collect::<Vec<_>>();
         self.get_many_items(collection, &hrefs).await
@@ -257,27 +359,44 @@ impl Storage<IcsItem> for CalDavStorage {
             .map(Etag::from)
     }
 
-    /// # Panics
+    /// Write metadata to a collection.
+    ///
+    /// # Errors
     ///
-    /// Only `DisplayName` and `Colour` are implemented.
+    /// If the underlying HTTP connection fails, or if the server rejects the value. For
+    /// [`CalendarProperty::Order`], if the value is not an integer.
     async fn set_collection_property(
         &mut self,
         collection: &Collection,
         meta: CalendarProperty,
         value: &str,
     ) -> Result<()> {
+        let href = collection.href();
+        let value = value.to_string();
         match meta {
             CalendarProperty::DisplayName => {
                 self.client
-                    .set_collection_displayname(collection.href(), Some(value))
+                    .set_property::<DisplayName>(href, Some(&value))
                     .await
             }
             CalendarProperty::Colour => {
                 self.client
-                    .set_calendar_colour(collection.href(), Some(value))
+                    .set_property::<CalendarColour>(href, Some(&value))
+                    .await
+            }
+            CalendarProperty::Description => {
+                self.client
+                    .set_property::<CalendarDescription>(href, Some(&value))
+                    .await
+            }
+            CalendarProperty::Order => {
+                let order = value
+                    .parse()
+                    .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+                self.client
+                    .set_property::<CalendarOrder>(href, Some(&order))
                     .await
             }
-            _ => todo!(), // TODO FIXME
         }
         .map_err(Error::from)
     }
@@ -290,23 +409,23 @@ impl Storage<IcsItem> for CalDavStorage {
     /// # Errors
     ///
     /// If the underlying HTTP connection fails or if the server returns invalid data.
-    ///
-    /// # Panics
-    ///
-    /// Only `DisplayName` and `Colour` are implemented.
     async fn get_collection_property(
         &self,
         collection: &Collection,
         meta: CalendarProperty,
     ) -> Result<Option<String>> {
+        let href = collection.href();
         let result = match meta {
-            CalendarProperty::DisplayName => {
-                self.client
-                    .get_collection_displayname(collection.href())
-                    .await
+            CalendarProperty::DisplayName => self.client.get_property::<DisplayName>(href).await,
+            CalendarProperty::Colour => self.client.get_property::<CalendarColour>(href).await,
+            CalendarProperty::Description => {
+                self.client.get_property::<CalendarDescription>(href).await
             }
-            CalendarProperty::Colour => self.client.get_calendar_colour(collection.href()).await,
-            _ => todo!(), // TODO FIXME
+            CalendarProperty::Order => self
+                .client
+                .get_property::<CalendarOrder>(href)
+                .await
+                .map(|order| order.map(|o| o.to_string())),
         };
 
         result.map_err(Error::from)
@@ -336,6 +455,76 @@ impl Storage<IcsItem> for CalDavStorage {
     }
 }
 
+impl CalDavStorage {
+    /// Enumerates all items in a collection, regardless of any [`TimeWindow`].
+    async fn list_all_items(&self, collection: &Collection) -> Result<Vec<ItemRef>> {
+        let response = self.client.list_resources(collection.href()).await?;
+        let mut items = Vec::with_capacity(response.len());
+        for r in response {
+            items.push(ItemRef {
+                href: r.href,
+                etag: r
+                    .details
+                    .etag
+                    .ok_or(Error::from(ErrorKind::InvalidData))?
+                    .into(),
+            });
+        }
+        Ok(items)
+    }
+
+    /// Queries all items in a collection which overlap a time window.
+    ///
+    /// A separate query is required for each type of component. Results are merged.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ErrorKind::Unsupported`] if the server truncates results, since items which were
+    /// omitted would otherwise appear to be outside of the window.
+    async fn query_window(
+        &self,
+        collection: &Collection,
+        window: &TimeWindow,
+        with_data: bool,
+    ) -> Result<Vec<QueriedResource>> {
+        let now = SystemTime::now();
+        let start = window
+            .past
+            .map(|past| now.checked_sub(past).unwrap_or(UNIX_EPOCH));
+        let end = window.future.and_then(|future| now.checked_add(future));
+
+        let mut hrefs = HashSet::new();
+        let mut results = Vec::new();
+        for component in [
+            CalendarComponent::Event,
+            CalendarComponent::Todo,
+            CalendarComponent::Journal,
+        ] {
+            let mut query = CalendarQuery::new(component).time_range(start, end);
+            if with_data {
+                query = query.with_data();
+            }
+            let result = self
+                .client
+                .calendar_query(collection.href(), &query)
+                .await?;
+            if result.truncated {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "server truncated results for the time window; try a narrower window",
+                ));
+            }
+            for r in result.resources {
+                // A resource may contain more than one type of component.
+                if hrefs.insert(r.href.clone()) {
+                    results.push(r);
+                }
+            }
+        }
+        Ok(results)
+    }
+}
+
 fn join_hrefs(collection_href: &str, item_href: &str) -> String {
     if item_href.starts_with('/') {
         return item_href.to_string();
@@ -8,9 +8,12 @@ use async_trait::async_trait;
 use http::Uri;
 use libdav::auth::Auth;
 use libdav::dav::mime_types;
+use libdav::property::{AddressBookDescription, DisplayName};
 use libdav::CardDavClient;
 
-use crate::base::{AddressBookProperty, Collection, Definition, Item, ItemRef, Storage, VcardItem};
+use crate::base::{
+    AddressBookProperty, Collection, Definition, Item, ItemChanges, ItemRef, Storage, VcardItem,
+};
 use crate::{Error, ErrorKind, Etag, Href, Result};
 
 #[derive(Debug)]
@@ -68,7 +71,11 @@ impl Storage<VcardItem> for CardDavStorage {
             .find_addresbooks(None)
             .await?
             .into_iter()
-            .map(|collection| Collection::new(collection.href))
+            .map(|collection| {
+                Collection::new(collection.href)
+                    .with_sync_support(collection.supports_sync)
+                    .with_tag(collection.ctag)
+            })
             .collect::<Vec<_>>();
         Ok(x)
     }
@@ -152,6 +159,49 @@ impl Storage<VcardItem> for CardDavStorage {
         Ok(items)
     }
 
+    /// Enumerates changed items using a `sync-collection` report (RFC 6578).
+    ///
+    /// Only supported for collections which advertise support for this report when discovered.
+    async fn changed_items(
+        &self,
+        collection: &Collection,
+        sync_token: Option<&str>,
+    ) -> Result<ItemChanges> {
+        if !collection.supports_sync() {
+            return Err(ErrorKind::Unsupported.into());
+        }
+
+        let mut changed = Vec::new();
+        let mut deleted = Vec::new();
+        let mut token = sync_token.map(String::from);
+        loop {
+            let result = self
+                .client
+                .sync_collection(collection.href(), token.as_deref())
+                .await?;
+            for r in result.changed {
+                changed.push(ItemRef {
+                    href: r.href,
+                    etag: r
+                        .details
+                        .etag
+                        .ok_or(Error::from(ErrorKind::InvalidData))?
+                        .into(),
+                });
+            }
+            deleted.extend(result.deleted);
+            if !result.truncated {
+                return Ok(ItemChanges {
+                    changed,
+                    deleted,
+                    sync_token: result.sync_token,
+                });
+            }
+            // Results were truncated; request the remaining changes.
+            token = Some(result.sync_token);
+        }
+    }
+
     async fn get_item(&self, collection: &Collection, href: &str) -> Result<(VcardItem, Etag)> {
         let mut results = self
             .client
@@ -243,9 +293,11 @@ impl Storage<VcardItem> for CardDavStorage {
             .map(Etag::from)
     }
 
-    /// # Panics
+    /// Write metadata to a collection.
     ///
-    /// Only `DisplayName` is implemented.
+    /// # Errors
+    ///
+    /// If the underlying HTTP connection fails, or if the server rejects the value.
     async fn set_collection_property(
         &mut self,
         collection: &Collection,
@@ -253,14 +305,18 @@ impl Storage<VcardItem> for CardDavStorage {
         value: &str,
     ) -> Result<()> {
         // TODO: make MetaKind paramatrezed on the ItemKind
+        let href = collection.href();
+        let value = value.to_string();
         match meta {
             AddressBookProperty::DisplayName => {
                 self.client
-                    .set_collection_displayname(collection.href(), Some(value))
+                    .set_property::<DisplayName>(href, Some(&value))
                     .await
             }
             AddressBookProperty::Description => {
-                todo!(); // TODO FIXME
+                self.client
+                    .set_property::<AddressBookDescription>(href, Some(&value))
+                    .await
             }
         }
         .map_err(Error::from)
@@ -274,24 +330,19 @@ impl Storage<VcardItem> for CardDavStorage {
     /// # Errors
     ///
     /// If the underlying HTTP connection fails or if the server returns invalid data.
-    ///
-    /// # Panics
-    ///
-    /// Only `DisplayName` is implemented.
     async fn get_collection_property(
         &self,
         collection: &Collection,
         meta: AddressBookProperty,
     ) -> Result<Option<String>> {
+        let href = collection.href();
         let result = match meta {
-            AddressBookProperty::DisplayName => {
+            AddressBookProperty::DisplayName => self.client.get_property::<DisplayName>(href).await,
+            AddressBookProperty::Description => {
                 self.client
-                    .get_collection_displayname(collection.href())
+                    .get_property::<AddressBookDescription>(href)
                     .await
             }
-            AddressBookProperty::Description => {
-                todo!(); // TODO FIXME
-            }
         };
 
         result.map_err(Error::from)
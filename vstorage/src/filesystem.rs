@@ -7,26 +7,37 @@
 //! - The `href` for an items is its filename relative to its parent directory.
 //! - The `href` for a collection is its absolute path. This may change in future.
 //!
+//! Items are never written in place. New content is written into a hidden temporary file inside
+//! the collection which is then moved into place, so a crash never leaves a partially written
+//! item behind. Replaced items keep their permissions. Before replacing or deleting an item, it is
+//! first moved aside and its etag verified; if another client modified it in the meantime, it is
+//! restored and the operation fails with [`ErrorKind::PreconditionFailed`].
+//!
 //! [`vdir`]: https://vdirsyncer.pimutils.org/en/stable/vdir.html
 #![allow(clippy::module_name_repetitions)]
 
 use async_trait::async_trait;
+use log::warn;
+use std::ffi::OsStr;
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::{fs::Metadata, os::unix::prelude::MetadataExt};
 use tokio::fs::{
-    create_dir, metadata, read_dir, read_to_string, remove_dir, remove_file, File, OpenOptions,
+    create_dir, hard_link, metadata, read_dir, read_to_string, remove_dir, remove_file, rename,
+    set_permissions, symlink_metadata, OpenOptions,
 };
 use tokio::io::AsyncWriteExt;
-use tokio_stream::wrappers::ReadDirStream;
-use tokio_stream::StreamExt;
+use tokio::task::JoinSet;
+use uuid::Uuid;
 
 use crate::base::{
     AddressBookProperty, CalendarProperty, Collection, Definition, Item, ItemRef, Storage,
 };
+use crate::util::{digest, wildcard_match};
 use crate::{Error, ErrorKind, Etag, Href, Result};
 
-// TODO: atomic writes
+#[cfg(feature = "watch")]
+pub mod watch;
 
 /// A filesystem directory containing zero or more directories.
 ///
@@ -96,19 +107,11 @@ where
 
     async fn list_items(&self, collection: &Collection) -> Result<Vec<ItemRef>> {
         let path = self.collection_path(collection);
-        let mut read_dir = ReadDirStream::new(read_dir(path).await?);
 
         let mut items = Vec::new();
-        while let Some(entry) = read_dir.next().await {
-            let entry = entry?;
-            let href = entry
-                .file_name()
-                .to_str()
-                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Filename is not valid UTF-8"))?
-                .into();
-            let etag = etag_for_path(&entry.path()).await?;
-            let item = ItemRef { href, etag };
-            items.push(item);
+        for href in self.item_hrefs(collection).await? {
+            let etag = etag_for_path(path.join(&href), self.definition.etag_mode).await?;
+            items.push(ItemRef { href, etag });
         }
 
         Ok(items)
@@ -116,12 +119,9 @@ where
 
     async fn get_item(&self, collection: &Collection, href: &str) -> Result<(I, Etag)> {
         let path = self.collection_path(collection).join(href);
-        let meta = metadata(&path).await?;
-
-        let item = I::from(read_to_string(&path).await?);
-        let etag = etag_for_metadata(&meta);
+        let (raw, etag) = read_with_etag(&path, self.definition.etag_mode).await?;
 
-        Ok((item, etag))
+        Ok((I::from(raw), etag))
     }
 
     async fn get_many_items(
@@ -129,31 +129,13 @@ where
         collection: &Collection,
         hrefs: &[&str],
     ) -> Result<Vec<(Href, I, Etag)>> {
-        // No specialisation for this type; it's fast enough for now.
-        let mut items = Vec::with_capacity(hrefs.len());
-        for href in hrefs {
-            let (item, etag) = self.get_item(collection, href).await?;
-            items.push((String::from(*href), item, etag));
-        }
-        Ok(items)
+        let hrefs = hrefs.iter().map(|href| String::from(*href)).collect();
+        self.read_items(collection, hrefs).await
     }
 
     async fn get_all_items(&self, collection: &Collection) -> Result<Vec<(Href, I, Etag)>> {
-        let mut read_dir = read_dir(self.collection_path(collection)).await?;
-
-        let mut items = Vec::new();
-        while let Some(entry) = read_dir.next_entry().await? {
-            let href: String = entry
-                .file_name()
-                .to_str()
-                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Filename is not valid UTF-8"))?
-                .into();
-            let etag = etag_for_path(&entry.path()).await?;
-            let item = I::from(read_to_string(&href).await?);
-            items.push((href, item, etag));
-        }
-
-        Ok(items)
+        let hrefs = self.item_hrefs(collection).await?;
+        self.read_items(collection, hrefs).await
     }
 
     async fn set_collection_property(
@@ -164,10 +146,12 @@ where
     ) -> Result<()> {
         let filename = meta.filename();
 
-        let path = self.collection_path(collection).join(filename);
-        let mut file = File::create(path).await?;
-
-        file.write_all(value.as_bytes()).await?;
+        let dir = self.collection_path(collection);
+        let temp = write_temp_file(&dir, value.as_bytes()).await?;
+        if let Err(err) = rename(&temp, dir.join(filename)).await {
+            discard(&temp).await;
+            return Err(err.into());
+        }
         Ok(())
     }
 
@@ -189,25 +173,34 @@ where
     }
 
     async fn add_item(&mut self, collection: &Collection, item: &I) -> Result<ItemRef> {
-        // TODO: We only need to remove a few "illegal" characters, so this is a bit too strict.
-        let basename = item
-            .ident()
-            .chars()
-            .filter(char::is_ascii_alphanumeric)
-            .collect::<String>();
-        let href = format!("{}.{}", basename, self.definition.extension);
-
-        let filename = self.collection_path(collection).join(&href);
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(&filename)
-            .await?;
-        file.write_all(item.as_str().as_bytes()).await?;
+        let basename = self.definition.filename_policy.basename(&item.ident());
+        let extension = &self.definition.extension;
+        let mut href = format!("{basename}.{extension}");
 
+        let dir = self.collection_path(collection);
+        let temp = write_temp_file(&dir, item.as_str().as_bytes()).await?;
+        // An existing file is never overwritten.
+        let mut attempts = 0;
+        let moved = loop {
+            match move_no_clobber(&temp, &dir.join(&href)).await {
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempts < 3 => {
+                    let alternate = format!("{basename}-{}.{extension}", Uuid::new_v4().simple());
+                    warn!("File {href} already exists, using {alternate} instead.");
+                    href = alternate;
+                    attempts += 1;
+                }
+                result => break result,
+            }
+        };
+        if let Err(err) = moved {
+            discard(&temp).await;
+            return Err(err.into());
+        }
+
+        let filename = dir.join(&href);
         let item_ref = ItemRef {
             href,
-            etag: etag_for_path(&filename).await?,
+            etag: etag_for_path(&filename, self.definition.etag_mode).await?,
         };
         Ok(item_ref)
     }
@@ -219,25 +212,36 @@ where
         etag: &Etag,
         item: &I,
     ) -> Result<Etag> {
-        let filename = self.collection_path(collection).join(href);
+        let dir = self.collection_path(collection);
+        let filename = dir.join(href);
+        let temp = write_temp_file(&dir, item.as_str().as_bytes()).await?;
 
-        let actual_etag = etag_for_path(&filename).await?;
-        if *etag != actual_etag {
-            return Err(Error::new(ErrorKind::InvalidData, "wrong etag"));
+        let backup = match take_if_unchanged(&dir, &filename, etag, self.definition.etag_mode).await
+        {
+            Ok(backup) => backup,
+            Err(err) => {
+                discard(&temp).await;
+                return Err(err);
+            }
+        };
+        // The new file is created with default permissions; keep those of the previous one.
+        let moved = match copy_permissions(&backup, &temp).await {
+            Ok(()) => move_no_clobber(&temp, &filename).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = moved {
+            discard(&temp).await;
+            // If another client created a new file after the previous one was moved aside, that
+            // one is kept. Otherwise, the previous file is moved back into place.
+            restore(&backup, &filename).await;
+            if err.kind() == std::io::ErrorKind::AlreadyExists {
+                return Err(Error::new(ErrorKind::PreconditionFailed, err));
+            }
+            return Err(err.into());
         }
+        discard(&backup).await;
 
-        // FIXME: this is racey and the etag can change after checking.
-        // TODO: atomic writes.
-        let mut file = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(false)
-            .open(&filename)
-            .await?;
-        file.write_all(item.as_str().as_bytes()).await?;
-
-        let etag = etag_for_path(&filename).await?;
-        Ok(etag)
+        etag_for_path(&filename, self.definition.etag_mode).await
     }
 
     async fn delete_item(
@@ -246,15 +250,10 @@ where
         href: &str,
         etag: &Etag,
     ) -> Result<()> {
-        let filename = self.collection_path(collection).join(href);
-
-        let actual_etag = etag_for_path(&filename).await?;
-        if *etag != actual_etag {
-            return Err(Error::new(ErrorKind::InvalidData, "wrong etag"));
-        }
-
-        // FIXME: this is racey and the etag can change after checking.
-        remove_file(filename).await?;
+        let dir = self.collection_path(collection);
+        let backup =
+            take_if_unchanged(&dir, &dir.join(href), etag, self.definition.etag_mode).await?;
+        remove_file(backup).await?;
 
         Ok(())
     }
@@ -271,6 +270,80 @@ where
 }
 
 impl<I: Item> FilesystemStorage<I> {
+    /// Watch this storage for changes. See [`watch`] for details.
+    ///
+    /// # Errors
+    ///
+    /// If the storage's directory cannot be watched.
+    #[cfg(feature = "watch")]
+    pub async fn watch(&self) -> Result<watch::Watcher> {
+        self.definition.watch().await
+    }
+
+    /// Returns the hrefs for all items in a collection.
+    ///
+    /// Files which are not items (see [`FilesystemDefinition::is_item`]) are skipped.
+    async fn item_hrefs(&self, collection: &Collection) -> Result<Vec<Href>> {
+        let mut read_dir = read_dir(self.collection_path(collection)).await?;
+
+        let mut hrefs = Vec::new();
+        while let Some(entry) = read_dir.next_entry().await? {
+            let Some(href) = entry.file_name().to_str().map(String::from) else {
+                warn!(
+                    "Skipping file with non-UTF-8 name: {}",
+                    entry.path().display()
+                );
+                continue;
+            };
+            if self.definition.is_item(&href) && !entry.file_type().await?.is_dir() {
+                hrefs.push(href);
+            }
+        }
+
+        Ok(hrefs)
+    }
+
+    /// Reads multiple items concurrently, returning them in the same order as `hrefs`.
+    ///
+    /// At most [`MAX_CONCURRENT_READS`] files are read at any given time.
+    async fn read_items(
+        &self,
+        collection: &Collection,
+        hrefs: Vec<Href>,
+    ) -> Result<Vec<(Href, I, Etag)>> {
+        let dir = self.collection_path(collection);
+        let mode = self.definition.etag_mode;
+        let mut pending = hrefs.into_iter().enumerate();
+        let mut tasks = JoinSet::new();
+        let mut items = Vec::with_capacity(pending.len());
+
+        loop {
+            while tasks.len() < MAX_CONCURRENT_READS {
+                let Some((index, href)) = pending.next() else {
+                    break;
+                };
+                let path = dir.join(&href);
+                tasks.spawn(async move {
+                    let (raw, etag) = read_with_etag(&path, mode).await?;
+                    Ok::<_, Error>((index, href, raw, etag))
+                });
+            }
+
+            let Some(result) = tasks.join_next().await else {
+                break;
+            };
+            // Remaining tasks are aborted when `tasks` is dropped.
+            let (index, href, raw, etag) = result.map_err(|e| Error::new(ErrorKind::Io, e))??;
+            items.push((index, href, I::from(raw), etag));
+        }
+
+        items.sort_unstable_by_key(|(index, ..)| *index);
+        Ok(items
+            .into_iter()
+            .map(|(_, href, item, etag)| (href, item, etag))
+            .collect())
+    }
+
     fn collection_path(&self, collection: &Collection) -> PathBuf {
         self.definition.path.join(collection.href())
     }
@@ -304,6 +377,18 @@ pub struct FilesystemDefinition<I: Item> {
     /// Filename extension for items in a storage. Files with matching extension are treated a
     /// items for a collection, and all other files are ignored.
     pub extension: String,
+    /// Pattern for files which should be ignored, even if they have a matching extension.
+    ///
+    /// Matched against filenames; `*` matches any sequence of characters and `?` matches any
+    /// single character. Hidden files are always ignored.
+    #[serde(default)]
+    pub ignore: Option<String>,
+    /// How etags for items are determined.
+    #[serde(default)]
+    pub etag_mode: EtagMode,
+    /// How files for new items are named.
+    #[serde(default)]
+    pub filename_policy: FilenamePolicy,
     i: PhantomData<I>,
 }
 
@@ -313,9 +398,129 @@ impl<I: Item> FilesystemDefinition<I> {
         Self {
             path,
             extension,
+            ignore: None,
+            etag_mode: EtagMode::default(),
+            filename_policy: FilenamePolicy::default(),
             i: PhantomData::default(),
         }
     }
+
+    /// Watch the storage described by this definition for changes. See [`watch`] for details.
+    ///
+    /// # Errors
+    ///
+    /// If the storage's directory cannot be watched.
+    #[cfg(feature = "watch")]
+    pub async fn watch(&self) -> Result<watch::Watcher> {
+        watch::Watcher::new(
+            self.path.clone(),
+            self.extension.clone(),
+            self.ignore.clone(),
+        )
+        .await
+    }
+
+    /// Ignore files matching the given pattern. See [`FilesystemDefinition::ignore`].
+    #[must_use]
+    pub fn with_ignore_pattern(mut self, pattern: String) -> Self {
+        self.ignore = Some(pattern);
+        self
+    }
+
+    /// Whether a file inside a collection's directory is an item.
+    ///
+    /// Only files with the configured extension are items. Hidden files (which includes temporary
+    /// files written by this storage and swap files of most editors) and files matching the
+    /// ignore pattern never are.
+    #[must_use]
+    pub fn is_item(&self, filename: &str) -> bool {
+        is_item(filename, &self.extension, self.ignore.as_deref())
+    }
+
+    /// Use the given strategy for determining etags.
+    #[must_use]
+    pub fn with_etag_mode(mut self, etag_mode: EtagMode) -> Self {
+        self.etag_mode = etag_mode;
+        self
+    }
+
+    /// Use the given strategy for naming files for new items.
+    #[must_use]
+    pub fn with_filename_policy(mut self, filename_policy: FilenamePolicy) -> Self {
+        self.filename_policy = filename_policy;
+        self
+    }
+}
+
+/// How a [`FilesystemStorage`] names the file for a new item.
+///
+/// Regardless of the policy, if a file with the chosen name already exists, a random suffix is
+/// appended to it.
+#[derive(serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilenamePolicy {
+    /// Use the item's UID, escaping any characters which are not safe in filenames.
+    ///
+    /// ASCII letters, digits and `-_.@+` are kept as-is; any other bytes are percent-encoded
+    /// (e.g.: `/` becomes `%2F`), so different UIDs always result in different filenames. A
+    /// leading `.` is also escaped, since it would result in a hidden file. If the result is too
+    /// long for a filename, the UID is hashed instead.
+    #[default]
+    Escape,
+    /// Use a hash of the item's UID.
+    HashUid,
+    /// Use a random UUID.
+    RandomUuid,
+}
+
+/// Maximum length (in bytes) of the name for a new item, excluding its extension.
+///
+/// Most filesystems allow 255 bytes; this leaves room for the extension and a suffix.
+const MAX_BASENAME_LENGTH: usize = 200;
+
+impl FilenamePolicy {
+    /// Returns a filename (without extension) for an item with the given UID.
+    fn basename(self, uid: &str) -> String {
+        match self {
+            FilenamePolicy::Escape => {
+                let mut basename = String::with_capacity(uid.len());
+                for (i, byte) in uid.bytes().enumerate() {
+                    if byte.is_ascii_alphanumeric()
+                        || b"-_@+".contains(&byte)
+                        || (byte == b'.' && i > 0)
+                    {
+                        basename.push(char::from(byte));
+                    } else {
+                        basename.push_str(&format!("%{byte:02X}"));
+                    }
+                }
+                if basename.is_empty() || basename.len() > MAX_BASENAME_LENGTH {
+                    digest(uid)
+                } else {
+                    basename
+                }
+            }
+            FilenamePolicy::HashUid => digest(uid),
+            FilenamePolicy::RandomUuid => Uuid::new_v4().to_string(),
+        }
+    }
+}
+
+/// How a [`FilesystemStorage`] determines the etag for an item.
+#[derive(serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EtagMode {
+    /// Use the file's metadata: its modification and change times (with nanosecond precision),
+    /// inode number and size.
+    ///
+    /// This is cheap, but a change may go unnoticed on filesystems with coarse timestamps if a
+    /// file is rewritten in place with content of the same size.
+    #[default]
+    Metadata,
+    /// Use a hash of the file's content.
+    ///
+    /// This detects any change, but requires reading every item when listing a collection.
+    ContentHash,
 }
 
 #[async_trait]
@@ -328,13 +533,210 @@ where
     }
 }
 
-async fn etag_for_path<P: AsRef<Path>>(path: P) -> Result<Etag> {
-    let metadata = metadata(path).await?;
-    Ok(etag_for_metadata(&metadata))
+fn is_item(filename: &str, extension: &str, ignore: Option<&str>) -> bool {
+    !filename.starts_with('.')
+        && Path::new(filename).extension().and_then(OsStr::to_str) == Some(extension)
+        && !ignore.is_some_and(|pattern| wildcard_match(pattern, filename))
+}
+
+/// Maximum amount of files read concurrently when fetching multiple items.
+const MAX_CONCURRENT_READS: usize = 16;
+
+/// Reads the file at `path` and returns its contents and etag.
+async fn read_with_etag(path: &Path, mode: EtagMode) -> Result<(String, Etag)> {
+    match mode {
+        EtagMode::Metadata => {
+            let meta = metadata(path).await?;
+            Ok((read_to_string(path).await?, etag_for_metadata(&meta)))
+        }
+        EtagMode::ContentHash => {
+            let raw = read_to_string(path).await?;
+            let etag = Etag::from(digest(&raw));
+            Ok((raw, etag))
+        }
+    }
+}
+
+async fn etag_for_path<P: AsRef<Path>>(path: P, mode: EtagMode) -> Result<Etag> {
+    Ok(fingerprint(path.as_ref(), mode).await?.0)
+}
+
+pub(crate) fn etag_for_metadata(metadata: &Metadata) -> Etag {
+    format!(
+        "{};{}.{:09}",
+        stable_metadata(metadata),
+        metadata.ctime(),
+        metadata.ctime_nsec()
+    )
+    .into()
 }
 
-fn etag_for_metadata(metadata: &Metadata) -> Etag {
-    format!("{};{}", metadata.mtime(), metadata.ino()).into()
+/// Metadata which is preserved when a file is renamed or linked.
+///
+/// Both of these operations update the change time, so it is not included.
+fn stable_metadata(metadata: &Metadata) -> String {
+    format!(
+        "{}.{:09};{};{}",
+        metadata.mtime(),
+        metadata.mtime_nsec(),
+        metadata.ino(),
+        metadata.size()
+    )
+}
+
+/// Returns the etag for the file at `path`, along with a fingerprint which does not change when
+/// the file is renamed.
+async fn fingerprint(path: &Path, mode: EtagMode) -> Result<(Etag, String)> {
+    match mode {
+        EtagMode::Metadata => {
+            let metadata = metadata(path).await?;
+            Ok((etag_for_metadata(&metadata), stable_metadata(&metadata)))
+        }
+        EtagMode::ContentHash => {
+            let hash = digest(read_to_string(path).await?);
+            Ok((Etag::from(hash.clone()), hash))
+        }
+    }
+}
+
+/// Returns a path for a new hidden temporary file inside `dir`.
+///
+/// Names are random and of fixed length, so they are valid wherever the final filename is.
+fn temp_path(dir: &Path) -> PathBuf {
+    dir.join(format!(".{}.tmp", Uuid::new_v4().simple()))
+}
+
+/// Writes `data` into a new temporary file inside `dir` and returns its path.
+///
+/// The data is flushed to disk before returning, so the file can be moved into place safely.
+pub(crate) async fn write_temp_file(dir: &Path, data: &[u8]) -> Result<PathBuf> {
+    let path = temp_path(dir);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .await?;
+
+    let written = match file.write_all(data).await {
+        Ok(()) => file.sync_all().await,
+        Err(e) => Err(e),
+    };
+    if let Err(err) = written {
+        drop(file);
+        discard(&path).await;
+        return Err(err.into());
+    }
+
+    Ok(path)
+}
+
+/// Copies the permissions of the file at `from` onto the file at `to`.
+pub(crate) async fn copy_permissions(from: &Path, to: &Path) -> std::io::Result<()> {
+    let permissions = metadata(from).await?.permissions();
+    set_permissions(to, permissions).await
+}
+
+/// Moves the file at `path` aside if its etag matches `etag`, returning its new location.
+///
+/// The file is verified again after moving it, which guarantees that the verified file is the one
+/// that will be replaced or deleted; any concurrent writer which replaces the file afterwards
+/// creates a new one instead.
+///
+/// # Errors
+///
+/// If the file has been modified, an error of kind [`ErrorKind::PreconditionFailed`] is returned.
+/// If this is only noticed after moving it, the file is moved back into place first.
+async fn take_if_unchanged(
+    dir: &Path,
+    path: &Path,
+    etag: &Etag,
+    mode: EtagMode,
+) -> Result<PathBuf> {
+    if path.file_name().is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "href is not a valid filename",
+        ));
+    }
+    let (actual, before) = fingerprint(path, mode).await?;
+    if actual != *etag {
+        return Err(Error::new(ErrorKind::PreconditionFailed, "wrong etag"));
+    }
+
+    let backup = temp_path(dir);
+    rename(path, &backup).await?;
+
+    // Renaming changes the etag, so compare only what it preserves.
+    let after = match fingerprint(&backup, mode).await {
+        Ok((_, after)) => after,
+        Err(err) => {
+            restore(&backup, path).await;
+            return Err(err);
+        }
+    };
+    if after != before {
+        restore(&backup, path).await;
+        return Err(Error::new(ErrorKind::PreconditionFailed, "wrong etag"));
+    }
+
+    Ok(backup)
+}
+
+/// Moves a file set aside by [`take_if_unchanged`] back into place.
+///
+/// If another file has been created in its place in the meantime, that one is kept.
+async fn restore(backup: &Path, path: &Path) {
+    let moved = match move_no_clobber(backup, path).await {
+        // Renaming may still work where linking failed for some other reason.
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => {
+            rename_no_clobber(backup, path).await
+        }
+        result => result,
+    };
+    match moved {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => discard(backup).await,
+        Err(err) => warn!("Could not restore {}: {err}", path.display()),
+    }
+}
+
+/// Moves the file at `from` to `to`, failing with [`std::io::ErrorKind::AlreadyExists`] if `to`
+/// exists.
+///
+/// Hard links are used where possible, since they never replace an existing file. Some
+/// filesystems (e.g.: FAT or SMB) do not support them, in which case [`rename_no_clobber`] is used
+/// instead. On failure, the file at `from` is left untouched.
+async fn move_no_clobber(from: &Path, to: &Path) -> std::io::Result<()> {
+    let Err(err) = hard_link(from, to).await else {
+        discard(from).await;
+        return Ok(());
+    };
+    match err.kind() {
+        std::io::ErrorKind::Unsupported | std::io::ErrorKind::PermissionDenied => {
+            rename_no_clobber(from, to).await
+        }
+        _ => Err(err),
+    }
+}
+
+/// Renames `from` to `to` unless `to` already exists.
+///
+/// Unlike linking, this is not atomic: a file created at `to` by another process between the check
+/// and the rename is overwritten.
+async fn rename_no_clobber(from: &Path, to: &Path) -> std::io::Result<()> {
+    match symlink_metadata(to).await {
+        Ok(_) => return Err(std::io::ErrorKind::AlreadyExists.into()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    rename(from, to).await
+}
+
+/// Removes a temporary file, logging any failure.
+pub(crate) async fn discard(path: &Path) {
+    if let Err(err) = remove_file(path).await {
+        warn!("Could not remove temporary file {}: {err}", path.display());
+    }
 }
 
 /// Helper to synchronise collection properties into filesystem.
@@ -370,10 +772,344 @@ impl PropertyWithFilename for AddressBookProperty {
 
 #[cfg(test)]
 mod tests {
-    use super::FilesystemDefinition;
-    use crate::base::{Definition, IcsItem};
+    use super::{rename_no_clobber, EtagMode, FilenamePolicy, FilesystemDefinition};
+    use crate::base::{CalendarProperty, Definition, IcsItem, Item};
+    use crate::{ErrorKind, Etag};
     use tempfile::tempdir;
 
+    fn event(summary: &str) -> IcsItem {
+        event_with_uid("test-event", summary)
+    }
+
+    fn event_with_uid(uid: &str, summary: &str) -> IcsItem {
+        IcsItem::from(
+            [
+                "BEGIN:VCALENDAR",
+                "BEGIN:VEVENT",
+                &format!("UID:{uid}"),
+                &format!("SUMMARY:{summary}"),
+                "END:VEVENT",
+                "END:VCALENDAR",
+            ]
+            .join("\r\n"),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_item_round_trip() {
+        let dir = tempdir().unwrap();
+        let definition =
+            FilesystemDefinition::<IcsItem>::new(dir.path().to_path_buf(), "ics".to_string());
+
+        let mut storage = definition.storage().await.unwrap();
+        let collection = storage.create_collection("test").await.unwrap();
+        assert!(storage.list_items(&collection).await.unwrap().is_empty());
+
+        let item_ref = storage.add_item(&collection, &event("one")).await.unwrap();
+        let listed = storage.list_items(&collection).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].href, item_ref.href);
+        assert_eq!(listed[0].etag, item_ref.etag);
+
+        let (item, etag) = storage.get_item(&collection, &item_ref.href).await.unwrap();
+        assert_eq!(item.as_str(), event("one").as_str());
+        assert_eq!(etag, item_ref.etag);
+
+        let new_etag = storage
+            .update_item(&collection, &item_ref.href, &etag, &event("two"))
+            .await
+            .unwrap();
+        assert_ne!(new_etag, etag);
+        let (item, etag) = storage.get_item(&collection, &item_ref.href).await.unwrap();
+        assert_eq!(item.as_str(), event("two").as_str());
+        assert_eq!(etag, new_etag);
+
+        storage
+            .delete_item(&collection, &item_ref.href, &etag)
+            .await
+            .unwrap();
+        assert!(storage.list_items(&collection).await.unwrap().is_empty());
+        let err = storage
+            .get_item(&collection, &item_ref.href)
+            .await
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DoesNotExist);
+    }
+
+    #[tokio::test]
+    async fn test_list_only_items() {
+        let dir = tempdir().unwrap();
+        let definition =
+            FilesystemDefinition::<IcsItem>::new(dir.path().to_path_buf(), "ics".to_string())
+                .with_ignore_pattern("draft-*".to_string());
+
+        let mut storage = definition.storage().await.unwrap();
+        let collection = storage.create_collection("test").await.unwrap();
+        let item_ref = storage.add_item(&collection, &event("one")).await.unwrap();
+        storage
+            .set_collection_property(&collection, CalendarProperty::Colour, "#ff0000")
+            .await
+            .unwrap();
+
+        let path = dir.path().join("test");
+        std::fs::write(path.join(".test-event.ics.swp"), "swap").unwrap();
+        std::fs::write(path.join("test-event.ics~"), "backup").unwrap();
+        std::fs::write(path.join("draft-1.ics"), event("draft").as_str()).unwrap();
+        std::fs::create_dir(path.join("nested.ics")).unwrap();
+
+        let listed = storage.list_items(&collection).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].href, item_ref.href);
+
+        let all = storage.get_all_items(&collection).await.unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].0, item_ref.href);
+    }
+
+    #[tokio::test]
+    async fn test_get_many_and_all_items() {
+        let dir = tempdir().unwrap();
+        let definition =
+            FilesystemDefinition::<IcsItem>::new(dir.path().to_path_buf(), "ics".to_string());
+
+        let mut storage = definition.storage().await.unwrap();
+        let collection = storage.create_collection("test").await.unwrap();
+        // More items than are read concurrently.
+        let mut refs = Vec::new();
+        for i in 0..40 {
+            let item = event_with_uid(&format!("event{i}"), &format!("Event {i}"));
+            refs.push(storage.add_item(&collection, &item).await.unwrap());
+        }
+
+        let hrefs = refs
+            .iter()
+            .rev()
+            .map(|r| r.href.as_str())
+            .collect::<Vec<_>>();
+        let many = storage.get_many_items(&collection, &hrefs).await.unwrap();
+        assert_eq!(many.len(), refs.len());
+        for ((href, item, etag), item_ref) in many.iter().zip(refs.iter().rev()) {
+            assert_eq!(*href, item_ref.href);
+            assert_eq!(*etag, item_ref.etag);
+            assert!(item.as_str().contains("SUMMARY:Event "));
+        }
+
+        let mut all = storage.get_all_items(&collection).await.unwrap();
+        all.sort_by(|a, b| a.0.cmp(&b.0));
+        let first = hrefs[0].to_string();
+        refs.sort_by(|a, b| a.href.cmp(&b.href));
+        assert_eq!(all.len(), refs.len());
+        for ((href, _, etag), item_ref) in all.iter().zip(refs.iter()) {
+            assert_eq!(*href, item_ref.href);
+            assert_eq!(*etag, item_ref.etag);
+        }
+
+        let err = storage
+            .get_many_items(&collection, &[&first, "missing.ics"])
+            .await
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DoesNotExist);
+    }
+
+    #[tokio::test]
+    async fn test_etag_changes_on_same_size_edit() {
+        let dir = tempdir().unwrap();
+        let definition =
+            FilesystemDefinition::<IcsItem>::new(dir.path().to_path_buf(), "ics".to_string());
+
+        let mut storage = definition.storage().await.unwrap();
+        let collection = storage.create_collection("test").await.unwrap();
+        let item_ref = storage.add_item(&collection, &event("one")).await.unwrap();
+
+        // Rewrite the file in place, keeping its inode and size.
+        let path = dir.path().join("test").join(&item_ref.href);
+        std::fs::write(&path, event("two").as_str()).unwrap();
+
+        let items = storage.list_items(&collection).await.unwrap();
+        assert_eq!(items.len(), 1);
+        assert_ne!(items[0].etag, item_ref.etag);
+    }
+
+    #[tokio::test]
+    async fn test_content_hash_etags() {
+        let dir = tempdir().unwrap();
+        let definition =
+            FilesystemDefinition::<IcsItem>::new(dir.path().to_path_buf(), "ics".to_string())
+                .with_etag_mode(EtagMode::ContentHash);
+
+        let mut storage = definition.storage().await.unwrap();
+        let collection = storage.create_collection("test").await.unwrap();
+        let item_ref = storage.add_item(&collection, &event("one")).await.unwrap();
+
+        // Rewriting identical content does not change the etag.
+        let path = dir.path().join("test").join(&item_ref.href);
+        std::fs::write(&path, event("one").as_str()).unwrap();
+        let (_, etag) = storage.get_item(&collection, &item_ref.href).await.unwrap();
+        assert_eq!(etag, item_ref.etag);
+
+        std::fs::write(&path, event("two").as_str()).unwrap();
+        let (_, etag) = storage.get_item(&collection, &item_ref.href).await.unwrap();
+        assert_ne!(etag, item_ref.etag);
+
+        storage
+            .update_item(&collection, &item_ref.href, &etag, &event("three"))
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_filename_policy() {
+        let escape = FilenamePolicy::Escape;
+        assert_eq!(escape.basename("a-b"), "a-b");
+        assert_ne!(escape.basename("a-b"), escape.basename("ab"));
+        assert_eq!(
+            escape.basename("6BA7B810-9DAD-11D1-80B4-00C04FD430C8"),
+            "6BA7B810-9DAD-11D1-80B4-00C04FD430C8"
+        );
+        assert_eq!(escape.basename("event@example.com"), "event@example.com");
+        assert_eq!(escape.basename("../a/b"), "%2E.%2Fa%2Fb");
+        assert_eq!(escape.basename("100%"), "100%25");
+        assert_eq!(escape.basename("ü"), "%C3%BC");
+        assert_eq!(escape.basename(&"x".repeat(300)).len(), 64);
+
+        assert_eq!(FilenamePolicy::HashUid.basename("a/b").len(), 64);
+        assert_ne!(
+            FilenamePolicy::RandomUuid.basename("a"),
+            FilenamePolicy::RandomUuid.basename("a")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_item_name_collision() {
+        let dir = tempdir().unwrap();
+        let definition =
+            FilesystemDefinition::<IcsItem>::new(dir.path().to_path_buf(), "ics".to_string());
+
+        let mut storage = definition.storage().await.unwrap();
+        let collection = storage.create_collection("test").await.unwrap();
+        let first = storage.add_item(&collection, &event("one")).await.unwrap();
+        assert_eq!(first.href, "test-event.ics");
+
+        // Adding an item with the same name must not clobber the existing one.
+        let second = storage.add_item(&collection, &event("two")).await.unwrap();
+        assert_ne!(second.href, first.href);
+        assert!(second.href.starts_with("test-event-"));
+
+        let (item, _) = storage.get_item(&collection, &first.href).await.unwrap();
+        assert!(item.as_str().contains("SUMMARY:one"));
+        let (item, _) = storage.get_item(&collection, &second.href).await.unwrap();
+        assert!(item.as_str().contains("SUMMARY:two"));
+    }
+
+    #[tokio::test]
+    async fn test_rename_no_clobber() {
+        let dir = tempdir().unwrap();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        std::fs::write(&from, "new").unwrap();
+        std::fs::write(&to, "existing").unwrap();
+
+        let err = rename_no_clobber(&from, &to).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&to).unwrap(), "existing");
+        assert_eq!(std::fs::read_to_string(&from).unwrap(), "new");
+
+        std::fs::remove_file(&to).unwrap();
+        rename_no_clobber(&from, &to).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&to).unwrap(), "new");
+        assert!(!from.exists());
+    }
+
+    #[tokio::test]
+    async fn test_write_with_stale_etag() {
+        let dir = tempdir().unwrap();
+        let definition =
+            FilesystemDefinition::<IcsItem>::new(dir.path().to_path_buf(), "ics".to_string());
+
+        let mut storage = definition.storage().await.unwrap();
+        let collection = storage.create_collection("test").await.unwrap();
+        let item_ref = storage.add_item(&collection, &event("one")).await.unwrap();
+
+        let stale = Etag::from("0;0");
+        let err = storage
+            .update_item(&collection, &item_ref.href, &stale, &event("two"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PreconditionFailed);
+        let err = storage
+            .delete_item(&collection, &item_ref.href, &stale)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PreconditionFailed);
+
+        let (item, etag) = storage.get_item(&collection, &item_ref.href).await.unwrap();
+        assert!(item.as_str().contains("SUMMARY:one"));
+        assert_eq!(etag, item_ref.etag);
+
+        let etag = storage
+            .update_item(&collection, &item_ref.href, &item_ref.etag, &event("two"))
+            .await
+            .unwrap();
+        let (item, _) = storage.get_item(&collection, &item_ref.href).await.unwrap();
+        assert!(item.as_str().contains("SUMMARY:two"));
+
+        // No temporary files are left behind.
+        let files = std::fs::read_dir(dir.path().join("test")).unwrap().count();
+        assert_eq!(files, 1);
+
+        storage
+            .delete_item(&collection, &item_ref.href, &etag)
+            .await
+            .unwrap();
+        let files = std::fs::read_dir(dir.path().join("test")).unwrap().count();
+        assert_eq!(files, 0);
+    }
+
+    #[tokio::test]
+    async fn test_update_item_with_long_href() {
+        let dir = tempdir().unwrap();
+        let definition =
+            FilesystemDefinition::<IcsItem>::new(dir.path().to_path_buf(), "ics".to_string());
+
+        let mut storage = definition.storage().await.unwrap();
+        let collection = storage.create_collection("test").await.unwrap();
+        // Written by another client, with the longest filename allowed on most filesystems.
+        let href = format!("{}.ics", "a".repeat(251));
+        std::fs::write(dir.path().join("test").join(&href), event("one").as_str()).unwrap();
+
+        let (_, etag) = storage.get_item(&collection, &href).await.unwrap();
+        storage
+            .update_item(&collection, &href, &etag, &event("two"))
+            .await
+            .unwrap();
+        let (item, _) = storage.get_item(&collection, &href).await.unwrap();
+        assert!(item.as_str().contains("SUMMARY:two"));
+    }
+
+    #[tokio::test]
+    async fn test_update_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let definition =
+            FilesystemDefinition::<IcsItem>::new(dir.path().to_path_buf(), "ics".to_string());
+
+        let mut storage = definition.storage().await.unwrap();
+        let collection = storage.create_collection("test").await.unwrap();
+        let item_ref = storage.add_item(&collection, &event("one")).await.unwrap();
+        let path = dir.path().join("test").join(&item_ref.href);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        let (_, etag) = storage.get_item(&collection, &item_ref.href).await.unwrap();
+        storage
+            .update_item(&collection, &item_ref.href, &etag, &event("two"))
+            .await
+            .unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
     #[tokio::test]
     async fn test_missing_displayname() {
         let dir = tempdir().unwrap();
@@ -390,11 +1126,25 @@ mod tests {
         assert!(displayname.is_none())
     }
 
-    // #[test]
-    // fn test_write_read_meta_name() {
-    //     todo!();
-    // }
+    #[tokio::test]
+    async fn test_write_read_displayname() {
+        let dir = tempdir().unwrap();
+        let definition =
+            FilesystemDefinition::<IcsItem>::new(dir.path().to_path_buf(), "ics".to_string());
+
+        let mut storage = definition.storage().await.unwrap();
+        let collection = storage.create_collection("test").await.unwrap();
+        storage
+            .set_collection_property(&collection, CalendarProperty::DisplayName, "Work")
+            .await
+            .unwrap();
 
-    // TODO: test writing and then checking the file
-    // TODO: test writing a file and then getting
+        let raw = std::fs::read_to_string(dir.path().join("test/displayname")).unwrap();
+        assert_eq!(raw, "Work");
+        let displayname = storage
+            .get_collection_property(&collection, CalendarProperty::DisplayName)
+            .await
+            .unwrap();
+        assert_eq!(displayname.as_deref(), Some("Work"));
+    }
 }
@@ -0,0 +1,365 @@
+// Copyright 2023 Hugo Osvaldo Barrera
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+//! Watch a [`FilesystemStorage`](super::FilesystemStorage) for changes.
+//!
+//! Uses `inotify`, so is only available on Linux. Requires the `watch` feature.
+//!
+//! Events are hints that something has changed, not an exact log of operations. A single write
+//! may result in more than one event (e.g.: an item is first added and then modified), and events
+//! may be lost if the kernel's queue overflows (see [`WatchEvent::Overflow`]). Consumers should
+//! respond to events by synchronising the affected collection.
+
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::path::PathBuf;
+
+use inotify::{EventMask, EventStream, Inotify, WatchDescriptor, WatchMask, Watches};
+use log::warn;
+use tokio::fs::{metadata, read_dir};
+use tokio_stream::StreamExt;
+
+use crate::{Error, ErrorKind, Href, Result};
+
+/// A change to a filesystem storage.
+///
+/// Collections are identified by the name of their directory, and items by their filename.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    ItemAdded {
+        collection: Href,
+        item: Href,
+    },
+    ItemModified {
+        collection: Href,
+        item: Href,
+    },
+    ItemDeleted {
+        collection: Href,
+        item: Href,
+    },
+    CollectionCreated {
+        collection: Href,
+    },
+    CollectionRemoved {
+        collection: Href,
+    },
+    /// Some events have been lost; all collections should be synchronised.
+    Overflow,
+}
+
+/// Mask for the storage's directory itself.
+const STORAGE_MASK: WatchMask = WatchMask::CREATE
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::ONLYDIR);
+
+/// Mask for each collection directory.
+const COLLECTION_MASK: WatchMask = WatchMask::CREATE
+    .union(WatchMask::CLOSE_WRITE)
+    .union(WatchMask::DELETE)
+    .union(WatchMask::MOVED_FROM)
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::ONLYDIR);
+
+/// A collection being watched.
+struct WatchedCollection {
+    href: Href,
+    /// Items known to exist; used to tell apart added and modified items.
+    items: BTreeSet<Href>,
+}
+
+/// Streams changes to a filesystem storage.
+///
+/// Created via [`FilesystemStorage::watch`](super::FilesystemStorage::watch).
+pub struct Watcher {
+    path: PathBuf,
+    extension: String,
+    ignore: Option<String>,
+    stream: EventStream<[u8; 4096]>,
+    watches: Watches,
+    storage: WatchDescriptor,
+    collections: HashMap<WatchDescriptor, WatchedCollection>,
+}
+
+impl Watcher {
+    /// Start watching the storage at `path`.
+    ///
+    /// Files are considered items using the same rules as
+    /// [`FilesystemDefinition::is_item`](super::FilesystemDefinition::is_item). Must be called
+    /// from within a `tokio` runtime.
+    pub(super) async fn new(
+        path: PathBuf,
+        extension: String,
+        ignore: Option<String>,
+    ) -> Result<Watcher> {
+        let stream = Inotify::init()?.into_event_stream([0; 4096])?;
+        let mut watches = stream.watches();
+        let storage = watches.add(&path, STORAGE_MASK)?;
+
+        let mut watcher = Watcher {
+            path,
+            extension,
+            ignore,
+            stream,
+            watches,
+            storage,
+            collections: HashMap::new(),
+        };
+
+        let mut entries = read_dir(&watcher.path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if !metadata(entry.path()).await?.is_dir() {
+                continue;
+            }
+            match entry.file_name().to_str() {
+                Some(href) if !href.starts_with('.') => watcher.add_collection(href).await?,
+                _ => {}
+            }
+        }
+
+        Ok(watcher)
+    }
+
+    /// Wait for the next change.
+    ///
+    /// # Errors
+    ///
+    /// If reading events from the kernel fails.
+    pub async fn next_event(&mut self) -> Result<WatchEvent> {
+        loop {
+            let event = self
+                .stream
+                .next()
+                .await
+                .ok_or_else(|| Error::new(ErrorKind::Io, "inotify stream ended"))??;
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                return Ok(WatchEvent::Overflow);
+            }
+            let Some(name) = event.name.as_deref().and_then(OsStr::to_str) else {
+                // Events for a watched directory itself. Removed directories are reported via
+                // their parent, so it is enough to forget about them.
+                if event.mask.contains(EventMask::IGNORED) {
+                    self.collections.remove(&event.wd);
+                }
+                continue;
+            };
+
+            let change = if event.wd == self.storage {
+                self.handle_storage_event(event.mask, name).await?
+            } else {
+                self.handle_collection_event(&event.wd, event.mask, name)
+            };
+            if let Some(change) = change {
+                return Ok(change);
+            }
+        }
+    }
+
+    async fn handle_storage_event(
+        &mut self,
+        mask: EventMask,
+        name: &str,
+    ) -> Result<Option<WatchEvent>> {
+        if !mask.contains(EventMask::ISDIR) || name.starts_with('.') {
+            return Ok(None);
+        }
+
+        if mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+            match self.add_collection(name).await {
+                Ok(()) => {}
+                // Removed again before it could be watched.
+                Err(err) if err.kind() == ErrorKind::DoesNotExist => return Ok(None),
+                Err(err) => return Err(err),
+            }
+            Ok(Some(WatchEvent::CollectionCreated {
+                collection: name.to_string(),
+            }))
+        } else if mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+            let wd = self
+                .collections
+                .iter()
+                .find(|(_, c)| c.href == name)
+                .map(|(wd, _)| wd.clone());
+            if let Some(wd) = wd {
+                self.collections.remove(&wd);
+                // A moved directory is still watched. A deleted one already isn't.
+                if mask.contains(EventMask::MOVED_FROM) {
+                    if let Err(err) = self.watches.remove(wd) {
+                        warn!("Failed to stop watching {name}: {err}");
+                    }
+                }
+            }
+            Ok(Some(WatchEvent::CollectionRemoved {
+                collection: name.to_string(),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn handle_collection_event(
+        &mut self,
+        wd: &WatchDescriptor,
+        mask: EventMask,
+        name: &str,
+    ) -> Option<WatchEvent> {
+        if mask.contains(EventMask::ISDIR) || !self.is_item(name) {
+            return None;
+        }
+        let collection = self.collections.get_mut(wd)?;
+
+        let item = name.to_string();
+        let event = if mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+            if !collection.items.remove(name) {
+                return None;
+            }
+            WatchEvent::ItemDeleted {
+                collection: collection.href.clone(),
+                item,
+            }
+        } else if collection.items.insert(item.clone()) {
+            WatchEvent::ItemAdded {
+                collection: collection.href.clone(),
+                item,
+            }
+        } else {
+            WatchEvent::ItemModified {
+                collection: collection.href.clone(),
+                item,
+            }
+        };
+        Some(event)
+    }
+
+    /// Start watching a collection and record the items that it currently contains.
+    async fn add_collection(&mut self, href: &str) -> Result<()> {
+        let path = self.path.join(href);
+        let wd = self.watches.add(&path, COLLECTION_MASK)?;
+
+        let mut items = BTreeSet::new();
+        let mut entries = read_dir(&path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(name) = entry.file_name().to_str() {
+                if self.is_item(name) {
+                    items.insert(name.to_string());
+                }
+            }
+        }
+
+        self.collections.insert(
+            wd,
+            WatchedCollection {
+                href: href.to_string(),
+                items,
+            },
+        );
+        Ok(())
+    }
+
+    fn is_item(&self, name: &str) -> bool {
+        super::is_item(name, &self.extension, self.ignore.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tempfile::tempdir;
+    use tokio::time::timeout;
+
+    use super::{WatchEvent, Watcher};
+
+    async fn next(watcher: &mut Watcher) -> WatchEvent {
+        timeout(Duration::from_secs(5), watcher.next_event())
+            .await
+            .expect("an event is received")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_watch_events() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("work")).unwrap();
+        std::fs::write(dir.path().join("work/one.ics"), "one").unwrap();
+
+        let mut watcher = Watcher::new(dir.path().to_path_buf(), "ics".to_string(), None)
+            .await
+            .unwrap();
+
+        std::fs::write(dir.path().join("work/one.ics"), "changed").unwrap();
+        assert_eq!(
+            next(&mut watcher).await,
+            WatchEvent::ItemModified {
+                collection: "work".into(),
+                item: "one.ics".into()
+            }
+        );
+
+        // Temporary files are ignored, but renaming one into place is not.
+        std::fs::write(dir.path().join("work/.two.ics.tmp"), "two").unwrap();
+        std::fs::rename(
+            dir.path().join("work/.two.ics.tmp"),
+            dir.path().join("work/two.ics"),
+        )
+        .unwrap();
+        assert_eq!(
+            next(&mut watcher).await,
+            WatchEvent::ItemAdded {
+                collection: "work".into(),
+                item: "two.ics".into()
+            }
+        );
+
+        std::fs::remove_file(dir.path().join("work/one.ics")).unwrap();
+        assert_eq!(
+            next(&mut watcher).await,
+            WatchEvent::ItemDeleted {
+                collection: "work".into(),
+                item: "one.ics".into()
+            }
+        );
+
+        std::fs::create_dir(dir.path().join("home")).unwrap();
+        assert_eq!(
+            next(&mut watcher).await,
+            WatchEvent::CollectionCreated {
+                collection: "home".into()
+            }
+        );
+
+        std::fs::write(dir.path().join("home/three.ics"), "three").unwrap();
+        assert_eq!(
+            next(&mut watcher).await,
+            WatchEvent::ItemAdded {
+                collection: "home".into(),
+                item: "three.ics".into()
+            }
+        );
+        assert_eq!(
+            next(&mut watcher).await,
+            WatchEvent::ItemModified {
+                collection: "home".into(),
+                item: "three.ics".into()
+            }
+        );
+
+        std::fs::remove_dir_all(dir.path().join("home")).unwrap();
+        assert_eq!(
+            next(&mut watcher).await,
+            WatchEvent::ItemDeleted {
+                collection: "home".into(),
+                item: "three.ics".into()
+            }
+        );
+        assert_eq!(
+            next(&mut watcher).await,
+            WatchEvent::CollectionRemoved {
+                collection: "home".into()
+            }
+        );
+    }
+}
@@ -0,0 +1,337 @@
+// Copyright 2023 Hugo Osvaldo Barrera
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+//! Implements reading entries from a remote vcard resource.
+//!
+//! This is the address book equivalent of [`webcal`](crate::webcal): a single file with multiple
+//! `VCARD`s hosted via http(s), such as an exported directory.
+//!
+//! - The `href` for an item is its UID. Copies of an item are ignored.
+//! - Items without a UID are given one based on their [hash](Item::hash). If multiple items share
+//!   a UID, all but the first one are also given one based on their hash, so that each one is
+//!   synchronised as a separate item.
+//! - The etag for an item is a digest of its exact content.
+//!
+//! Like for webcal, the resource is only parsed again when it has changed.
+#![allow(clippy::module_name_repetitions)]
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use http::Uri;
+use hyper::{client::HttpConnector, Client};
+use hyper_rustls::HttpsConnector;
+
+use crate::{
+    base::{AddressBookProperty, Collection, Definition, Item, ItemRef, Storage, VcardItem},
+    simple_component::Component,
+    util::digest,
+    webcal::{fetch_raw, http_client, CachedResource},
+    Error, ErrorKind, Etag, Href, Result,
+};
+
+/// A storage which exposes items in a remote vcard resource.
+///
+/// Contains exactly one collection, which contains all the entries found in the remote resource.
+/// The name of this single collection must be specified via the
+/// [`HttpVcardDefinition::collection_name`] property.
+pub struct HttpVcardStorage {
+    definition: HttpVcardDefinition,
+    http_client: Client<HttpsConnector<HttpConnector>>,
+    resource: CachedResource<Vec<(Href, VcardItem, Etag)>>,
+}
+
+/// Definition for a [`HttpVcardStorage`].
+#[derive(Debug, PartialEq)]
+pub struct HttpVcardDefinition {
+    /// The URL of the remote vcard resource. Must be HTTP or HTTPS.
+    pub url: Uri,
+    /// The href and id to be given to the single collection available.
+    pub collection_name: String,
+}
+
+#[async_trait]
+impl Definition<VcardItem> for HttpVcardDefinition {
+    /// Create a new storage instance.
+    ///
+    /// Unlike other [`Storage`] implementations, this one allows only a single collection.
+    async fn storage(self) -> Result<Box<dyn Storage<VcardItem>>> {
+        let http_client = http_client(&self.url)?;
+        Ok(Box::from(HttpVcardStorage {
+            definition: self,
+            http_client,
+            resource: CachedResource::new(),
+        }))
+    }
+}
+
+#[async_trait]
+impl Storage<VcardItem> for HttpVcardStorage {
+    /// Checks that the remote resource exists and whether it looks like a vcard resource.
+    async fn check(&self) -> Result<()> {
+        let raw = fetch_raw(&self.http_client, &self.definition.url).await?;
+
+        if !raw.trim_start().is_empty() && !raw.trim_start().starts_with("BEGIN:VCARD") {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "response for URL doesn't look like an address book",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns a single collection with the name specified in the definition.
+    async fn discover_collections(&self) -> Result<Vec<Collection>> {
+        Ok(vec![Collection::new(
+            self.definition.collection_name.clone(),
+        )])
+    }
+
+    /// Unsupported for this storage type.
+    async fn create_collection(&mut self, _: &str) -> Result<Collection> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "creating collections via http is not supported",
+        ))
+    }
+
+    /// Unsupported for this storage type.
+    async fn destroy_collection(&mut self, _: &str) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "destroying collections via http is not supported",
+        ))
+    }
+
+    /// Usable only with the collection name specified in the definition. Any other name will
+    /// return [`ErrorKind::DoesNotExist`]
+    fn open_collection(&self, href: &str) -> Result<Collection> {
+        if href != self.definition.collection_name {
+            return Err(Error::new(
+                ErrorKind::DoesNotExist,
+                format!(
+                    "this storage only contains the '{}' collection",
+                    self.definition.collection_name
+                ),
+            ));
+        }
+        Ok(Collection::new(self.definition.collection_name.clone()))
+    }
+
+    /// Enumerates items in this collection.
+    ///
+    /// Note that the whole collection needs to be retrieved (unless it has not changed since it
+    /// was last retrieved).
+    async fn list_items(&self, _collection: &Collection) -> Result<Vec<ItemRef>> {
+        let refs = self
+            .items()
+            .await?
+            .iter()
+            .map(|(href, _, etag)| ItemRef {
+                href: href.clone(),
+                etag: etag.clone(),
+            })
+            .collect();
+
+        Ok(refs)
+    }
+
+    /// Returns a single item from the collection.
+    ///
+    /// Note that the whole collection needs to be retrieved (unless it has not changed since it
+    /// was last retrieved).
+    async fn get_item(&self, _collection: &Collection, href: &str) -> Result<(VcardItem, Etag)> {
+        self.items()
+            .await?
+            .iter()
+            .find(|(h, _, _)| h == href)
+            .map(|(_, item, etag)| (item.clone(), etag.clone()))
+            .ok_or_else(|| Error::from(ErrorKind::DoesNotExist))
+    }
+
+    /// Returns multiple items from the collection.
+    ///
+    /// Note that the whole collection needs to be retrieved (unless it has not changed since it
+    /// was last retrieved).
+    async fn get_many_items(
+        &self,
+        _collection: &Collection,
+        hrefs: &[&str],
+    ) -> Result<Vec<(Href, VcardItem, Etag)>> {
+        let items = self
+            .items()
+            .await?
+            .iter()
+            .filter(|(href, _, _)| hrefs.contains(&href.as_str()))
+            .cloned()
+            .collect();
+
+        Ok(items)
+    }
+
+    /// Fetch all items in the collection.
+    ///
+    /// Performs a single HTTP(s) request to fetch all items.
+    async fn get_all_items(
+        &self,
+        _collection: &Collection,
+    ) -> Result<Vec<(Href, VcardItem, Etag)>> {
+        Ok(self.items().await?.as_ref().clone())
+    }
+
+    /// Unsupported for this storage type.
+    async fn add_item(&mut self, _collection: &Collection, _: &VcardItem) -> Result<ItemRef> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "adding items via http is not supported",
+        ))
+    }
+
+    /// Unsupported for this storage type.
+    async fn update_item(
+        &mut self,
+        _collection: &Collection,
+        _: &str,
+        _: &Etag,
+        _: &VcardItem,
+    ) -> Result<Etag> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "updating items via http is not supported",
+        ))
+    }
+
+    /// Unsupported for this storage type.
+    async fn set_collection_property(
+        &mut self,
+        _collection: &Collection,
+        _: AddressBookProperty,
+        _: &str,
+    ) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "setting metadata via http is not supported",
+        ))
+    }
+
+    /// Always returns `None`; the remote resource has no metadata.
+    async fn get_collection_property(
+        &self,
+        _collection: &Collection,
+        _: AddressBookProperty,
+    ) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Unsupported for this storage type.
+    async fn delete_item(&mut self, _: &Collection, _: &str, _: &Etag) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "deleting items via http is not supported",
+        ))
+    }
+
+    fn collection_id(&self, collection: &Collection) -> Result<String> {
+        if collection.href() == self.definition.collection_name {
+            Ok(self.definition.collection_name.to_string())
+        } else {
+            Err(ErrorKind::DoesNotExist.into())
+        }
+    }
+}
+
+impl HttpVcardStorage {
+    /// Returns all items in the remote resource, fetching it only if it has changed.
+    async fn items(&self) -> Result<Arc<Vec<(Href, VcardItem, Etag)>>> {
+        self.resource
+            .fetch(&self.http_client, &self.definition.url, split)
+            .await
+    }
+}
+
+/// Splits a document with multiple `VCARD`s into individual items, along with their `href` and
+/// etag.
+fn split(raw: &str) -> Result<Vec<(Href, VcardItem, Etag)>> {
+    let components =
+        Component::split_collection(raw).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    let mut uids = HashSet::new();
+    let mut hashes = HashSet::new();
+    let mut items = Vec::with_capacity(components.len());
+    for component in components {
+        let mut item = VcardItem::from(component.to_string());
+        if item.uid().is_none() {
+            item = item.with_uid(&item.hash());
+        }
+        let hash = item.hash();
+        if !hashes.insert(hash.clone()) {
+            continue;
+        }
+        if !uids.insert(item.ident()) {
+            item = item.with_uid(&hash);
+            uids.insert(item.ident());
+        }
+        let href = item.ident();
+        let etag = Etag::from(digest(item.as_str()));
+        items.push((href, item, etag));
+    }
+
+    Ok(items)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use crate::base::Item;
+
+    use super::split;
+
+    #[test]
+    fn test_split_duplicate_uids() {
+        let card = |uid: &str, name: &str| {
+            [
+                "BEGIN:VCARD",
+                "VERSION:3.0",
+                &format!("UID:{uid}"),
+                &format!("FN:{name}"),
+                "END:VCARD",
+            ]
+            .join("\r\n")
+        };
+        let raw = [
+            card("alice", "Alice"),
+            card("bob", "Bob"),
+            card("alice", "Alice (work)"),
+            card("bob", "Bob"),
+        ]
+        .join("\r\n");
+
+        let items = split(&raw).unwrap();
+        let hrefs = items
+            .iter()
+            .map(|(href, _, _)| href.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(hrefs.len(), 3);
+        assert_eq!(hrefs[..2], ["alice", "bob"]);
+        assert!(items[2].1.as_str().contains("FN:Alice (work)"));
+        assert_eq!(items[2].1.uid().as_deref(), Some(hrefs[2]));
+
+        let uids = items
+            .iter()
+            .map(|(_, item, _)| item.uid().unwrap())
+            .collect::<HashSet<_>>();
+        assert_eq!(uids.len(), 3);
+    }
+
+    #[test]
+    fn test_etag_changes_with_any_edit() {
+        let card =
+            |extra: &str| format!("BEGIN:VCARD\r\nUID:alice\r\nFN:Alice\r\n{extra}END:VCARD");
+        let (_, _, before) = split(&card("")).unwrap().remove(0);
+        let (_, _, after) = split(&card("X-ABLABEL:work\r\n")).unwrap().remove(0);
+        assert_ne!(before, after);
+    }
+}
@@ -38,7 +38,10 @@ pub mod base;
 pub mod caldav;
 pub mod carddav;
 pub mod filesystem;
+pub mod httpvcard;
+pub mod memory;
 pub mod readonly;
+pub mod singlefile;
 mod simple_component;
 pub mod sync;
 mod util;
@@ -47,7 +50,7 @@ pub mod webcal;
 type Result<T> = std::result::Result<T, crate::Error>;
 
 /// Variants used to categorise [`Error`] instances.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorKind {
     DoesNotExist,
     NotACollection,
@@ -60,6 +63,14 @@ pub enum ErrorKind {
     CollectionNotEmpty,
     /// This storage implementation does not support a required feature.
     Unsupported,
+    /// The resource was modified since it was last read (e.g.: its etag no longer matches).
+    PreconditionFailed,
+    /// Synchronising a collection was aborted because it would delete too many items.
+    ///
+    /// See [`DeletionGuard`](crate::sync::plan::DeletionGuard).
+    TooManyDeletions,
+    /// Multiple items in the same collection share a UID.
+    DuplicateUid,
     // #[deprecated]
     Uncategorised,
 }
@@ -78,6 +89,9 @@ impl ErrorKind {
             ErrorKind::ReadOnly => "the resource is read-only",
             ErrorKind::CollectionNotEmpty => "the collection is not empty",
             ErrorKind::Unsupported => "the operation is not supported",
+            ErrorKind::PreconditionFailed => "the resource was modified concurrently",
+            ErrorKind::TooManyDeletions => "refusing to delete too many items",
+            ErrorKind::DuplicateUid => "multiple items share the same UID",
             ErrorKind::Uncategorised => "uncategorised error",
         }
     }
@@ -103,6 +117,12 @@ impl Error {
             source: Some(source.into()),
         }
     }
+
+    /// Returns the kind of this error.
+    #[must_use]
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
 }
 
 impl From<ErrorKind> for Error {
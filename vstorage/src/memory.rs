@@ -0,0 +1,696 @@
+// Copyright 2023 Hugo Osvaldo Barrera
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+//! A storage which keeps all data in memory.
+//!
+//! Mostly useful for testing code which uses storages (including the synchronisation logic in
+//! [`sync`](crate::sync)), and for embedding in applications which need a scratch storage.
+//!
+//! - The `href` for a collection is the value with which it was created.
+//! - The `href` for a new item is its [`ident`](Item::ident); a suffix is appended if that is
+//!   already taken.
+//! - Etags are sequential numbers, shared across all collections in the storage. Performing the
+//!   same operations on two new storages always yields the same etags.
+//!
+//! Faults can be injected to simulate misbehaving storages; see [`MemoryStorage::fail_write`],
+//! [`MemoryStorage::return_stale_etag`] and [`MemoryStorage::hide_item`]. Storages which omit
+//! items from listings on purpose can be simulated with [`MemoryStorage::exclude_item`].
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use async_trait::async_trait;
+
+use crate::base::{Collection, Item, ItemChanges, ItemRef, Storage};
+use crate::{Error, ErrorKind, Etag, Href, Result};
+
+/// A storage which keeps all data in memory.
+///
+/// Cloning a `MemoryStorage` returns a new handle to the same data. This allows keeping a handle
+/// to inspect it or inject faults while another one is being used elsewhere.
+///
+/// Supports [`Storage::changed_items`], and collections have a tag which changes with their
+/// content.
+pub struct MemoryStorage<I: Item> {
+    inner: Arc<Mutex<Inner<I>>>,
+}
+
+struct Inner<I: Item> {
+    collections: BTreeMap<Href, MemoryCollection<I>>,
+    /// The last etag that was issued. Also used as a sync token.
+    last_etag: u64,
+    /// Amount of write operations until one fails.
+    fail_write_in: Option<usize>,
+    /// Items for which an outdated etag is returned, as `(collection, item)`.
+    stale_etags: BTreeSet<(Href, Href)>,
+    /// Items omitted when listing a collection, as `(collection, item)`.
+    hidden: BTreeSet<(Href, Href)>,
+    /// Items omitted when listing a collection but reported as unlisted, as `(collection, item)`.
+    excluded: BTreeSet<(Href, Href)>,
+}
+
+struct MemoryCollection<I: Item> {
+    items: BTreeMap<Href, MemoryItem>,
+    properties: Vec<(I::CollectionProperty, String)>,
+    /// Items which have been modified or deleted, and the etag issued when that happened.
+    changes: BTreeMap<Href, u64>,
+    /// Etag issued at the time of the last change to this collection.
+    version: u64,
+}
+
+struct MemoryItem {
+    raw: String,
+    etag: u64,
+    /// The etag before the last modification, if any.
+    previous_etag: Option<u64>,
+}
+
+impl<I: Item> MemoryStorage<I> {
+    /// Create a new empty storage.
+    #[must_use]
+    pub fn new() -> Self {
+        MemoryStorage {
+            inner: Arc::new(Mutex::new(Inner {
+                collections: BTreeMap::new(),
+                last_etag: 0,
+                fail_write_in: None,
+                stale_etags: BTreeSet::new(),
+                hidden: BTreeSet::new(),
+                excluded: BTreeSet::new(),
+            })),
+        }
+    }
+
+    /// Make the `n`th write operation from now on fail, where `1` is the next one.
+    ///
+    /// Write operations are those which create, modify or delete a collection, an item or a
+    /// property. The failing operation has no effect and returns an error of kind
+    /// [`ErrorKind::Io`]. Only one failure is injected; the following writes succeed.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is zero.
+    pub fn fail_write(&self, n: usize) {
+        assert!(n > 0, "writes are counted starting from one");
+        self.lock().fail_write_in = Some(n);
+    }
+
+    /// Return an outdated etag for an item when listing or reading it.
+    ///
+    /// The outdated etag is the one the item had before its last modification. Updating or
+    /// deleting the item with it fails with [`ErrorKind::PreconditionFailed`].
+    pub fn return_stale_etag(&self, collection: &str, href: &str) {
+        let mut inner = self.lock();
+        inner
+            .stale_etags
+            .insert((collection.to_string(), href.to_string()));
+    }
+
+    /// Omit an item when listing a collection.
+    ///
+    /// This simulates servers which fail to report some items. The item can still be read,
+    /// updated and deleted using its `href`.
+    pub fn hide_item(&self, collection: &str, href: &str) {
+        let mut inner = self.lock();
+        inner
+            .hidden
+            .insert((collection.to_string(), href.to_string()));
+    }
+
+    /// Omit an item when listing a collection, but report it via [`Storage::unlisted_items`].
+    ///
+    /// This simulates storages which only list some items on purpose, such as a CalDav storage
+    /// with a time window which the item has moved out of. Like for such storages,
+    /// [`Storage::changed_items`] is unsupported for collections with excluded items.
+    pub fn exclude_item(&self, collection: &str, href: &str) {
+        let mut inner = self.lock();
+        inner
+            .excluded
+            .insert((collection.to_string(), href.to_string()));
+    }
+
+    /// Remove all injected faults and exclusions.
+    pub fn clear_faults(&self) {
+        let mut inner = self.lock();
+        inner.fail_write_in = None;
+        inner.stale_etags.clear();
+        inner.hidden.clear();
+        inner.excluded.clear();
+    }
+
+    /// Returns the raw content of an item, ignoring any injected faults.
+    ///
+    /// Returns `None` if the item does not exist.
+    #[must_use]
+    pub fn raw_item(&self, collection: &str, href: &str) -> Option<String> {
+        let inner = self.lock();
+        let item = inner.collections.get(collection)?.items.get(href)?;
+        Some(item.raw.clone())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<I>> {
+        // A panic while holding the lock cannot leave data in an inconsistent state.
+        self.inner
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+impl<I: Item> Default for MemoryStorage<I> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I: Item> Clone for MemoryStorage<I> {
+    fn clone(&self) -> Self {
+        MemoryStorage {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<I: Item> Inner<I> {
+    /// Accounts for a write operation, failing if a fault has been injected.
+    fn write(&mut self) -> Result<()> {
+        match self.fail_write_in {
+            Some(1) => {
+                self.fail_write_in = None;
+                Err(Error::new(ErrorKind::Io, "injected write failure"))
+            }
+            Some(n) => {
+                self.fail_write_in = Some(n - 1);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn next_etag(&mut self) -> u64 {
+        self.last_etag += 1;
+        self.last_etag
+    }
+
+    fn collection(&self, href: &str) -> Result<&MemoryCollection<I>> {
+        self.collections
+            .get(href)
+            .ok_or_else(|| Error::new(ErrorKind::DoesNotExist, "collection does not exist"))
+    }
+
+    fn collection_mut(&mut self, href: &str) -> Result<&mut MemoryCollection<I>> {
+        self.collections
+            .get_mut(href)
+            .ok_or_else(|| Error::new(ErrorKind::DoesNotExist, "collection does not exist"))
+    }
+
+    /// The etag reported for an item, taking injected faults into account.
+    fn reported_etag(&self, collection: &str, href: &str, item: &MemoryItem) -> Etag {
+        let key = (collection.to_string(), href.to_string());
+        let etag = if self.stale_etags.contains(&key) {
+            item.previous_etag.unwrap_or(0)
+        } else {
+            item.etag
+        };
+        Etag::from(etag.to_string())
+    }
+
+    /// Whether an item is omitted when listing a collection.
+    fn is_hidden(&self, collection: &str, href: &str) -> bool {
+        let key = (collection.to_string(), href.to_string());
+        self.hidden.contains(&key) || self.excluded.contains(&key)
+    }
+
+    /// Fails unless an item exists at `href` and its etag matches.
+    fn verify_etag(&self, collection: &str, href: &str, etag: &Etag) -> Result<()> {
+        let item = self
+            .collection(collection)?
+            .items
+            .get(href)
+            .ok_or_else(|| Error::new(ErrorKind::DoesNotExist, "item does not exist"))?;
+        if *etag != Etag::from(item.etag.to_string()) {
+            return Err(Error::new(ErrorKind::PreconditionFailed, "wrong etag"));
+        }
+        Ok(())
+    }
+}
+
+impl<I: Item> MemoryCollection<I> {
+    fn record_change(&mut self, href: &str, etag: u64) {
+        self.changes.insert(href.to_string(), etag);
+        self.version = etag;
+    }
+}
+
+#[async_trait]
+impl<I: Item> Storage<I> for MemoryStorage<I>
+where
+    I::CollectionProperty: PartialEq,
+{
+    async fn check(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn discover_collections(&self) -> Result<Vec<Collection>> {
+        let inner = self.lock();
+        let collections = inner
+            .collections
+            .iter()
+            .map(|(href, collection)| {
+                Collection::new(href.clone())
+                    .with_sync_support(true)
+                    .with_tag(Some(collection.version.to_string()))
+            })
+            .collect();
+        Ok(collections)
+    }
+
+    async fn create_collection(&mut self, href: &str) -> Result<Collection> {
+        let mut inner = self.lock();
+        if inner.collections.contains_key(href) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "collection already exists",
+            ));
+        }
+        inner.write()?;
+        let version = inner.next_etag();
+        inner.collections.insert(
+            href.to_string(),
+            MemoryCollection {
+                items: BTreeMap::new(),
+                properties: Vec::new(),
+                changes: BTreeMap::new(),
+                version,
+            },
+        );
+
+        Ok(Collection::new(href.to_string()).with_sync_support(true))
+    }
+
+    async fn destroy_collection(&mut self, href: &str) -> Result<()> {
+        let mut inner = self.lock();
+        if !inner.collection(href)?.items.is_empty() {
+            return Err(ErrorKind::CollectionNotEmpty.into());
+        }
+        inner.write()?;
+        inner.collections.remove(href);
+        Ok(())
+    }
+
+    fn open_collection(&self, href: &str) -> Result<Collection> {
+        Ok(Collection::new(href.to_string()).with_sync_support(true))
+    }
+
+    async fn get_collection_property(
+        &self,
+        collection: &Collection,
+        property: I::CollectionProperty,
+    ) -> Result<Option<String>> {
+        let inner = self.lock();
+        let value = inner
+            .collection(collection.href())?
+            .properties
+            .iter()
+            .find(|(p, _)| *p == property)
+            .map(|(_, value)| value.clone());
+        Ok(value)
+    }
+
+    async fn set_collection_property(
+        &mut self,
+        collection: &Collection,
+        property: I::CollectionProperty,
+        value: &str,
+    ) -> Result<()> {
+        let mut inner = self.lock();
+        inner.collection(collection.href())?;
+        inner.write()?;
+        let properties = &mut inner.collection_mut(collection.href())?.properties;
+        match properties.iter_mut().find(|(p, _)| *p == property) {
+            Some((_, existing)) => *existing = value.to_string(),
+            None => properties.push((property, value.to_string())),
+        }
+        Ok(())
+    }
+
+    async fn list_items(&self, collection: &Collection) -> Result<Vec<ItemRef>> {
+        let inner = self.lock();
+        let href = collection.href();
+        let items = inner
+            .collection(href)?
+            .items
+            .iter()
+            .filter(|(item_href, _)| !inner.is_hidden(href, item_href))
+            .map(|(item_href, item)| ItemRef {
+                href: item_href.clone(),
+                etag: inner.reported_etag(href, item_href, item),
+            })
+            .collect();
+        Ok(items)
+    }
+
+    async fn changed_items(
+        &self,
+        collection: &Collection,
+        sync_token: Option<&str>,
+    ) -> Result<ItemChanges> {
+        let inner = self.lock();
+        let href = collection.href();
+        let memory_collection = inner.collection(href)?;
+        if inner.excluded.iter().any(|(c, _)| c == href) {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        let since = match sync_token {
+            Some(token) => token
+                .parse::<u64>()
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?,
+            None => 0,
+        };
+
+        let mut changed = Vec::new();
+        let mut deleted = Vec::new();
+        for (item_href, etag) in &memory_collection.changes {
+            if *etag <= since || inner.is_hidden(href, item_href) {
+                continue;
+            }
+            match memory_collection.items.get(item_href) {
+                Some(item) => changed.push(ItemRef {
+                    href: item_href.clone(),
+                    etag: inner.reported_etag(href, item_href, item),
+                }),
+                None if sync_token.is_some() => deleted.push(item_href.clone()),
+                None => {}
+            }
+        }
+
+        Ok(ItemChanges {
+            changed,
+            deleted,
+            sync_token: inner.last_etag.to_string(),
+        })
+    }
+
+    async fn unlisted_items(&self, collection: &Collection) -> Result<Vec<ItemRef>> {
+        let inner = self.lock();
+        let href = collection.href();
+        let items = inner
+            .collection(href)?
+            .items
+            .iter()
+            .filter(|(item_href, _)| {
+                inner
+                    .excluded
+                    .contains(&(href.to_string(), (*item_href).clone()))
+            })
+            .map(|(item_href, item)| ItemRef {
+                href: item_href.clone(),
+                etag: inner.reported_etag(href, item_href, item),
+            })
+            .collect();
+        Ok(items)
+    }
+
+    async fn get_item(&self, collection: &Collection, href: &str) -> Result<(I, Etag)> {
+        let inner = self.lock();
+        let item = inner
+            .collection(collection.href())?
+            .items
+            .get(href)
+            .ok_or_else(|| Error::new(ErrorKind::DoesNotExist, "item does not exist"))?;
+        let etag = inner.reported_etag(collection.href(), href, item);
+        Ok((I::from(item.raw.clone()), etag))
+    }
+
+    async fn get_many_items(
+        &self,
+        collection: &Collection,
+        hrefs: &[&str],
+    ) -> Result<Vec<(Href, I, Etag)>> {
+        let mut items = Vec::with_capacity(hrefs.len());
+        for href in hrefs {
+            let (item, etag) = self.get_item(collection, href).await?;
+            items.push((String::from(*href), item, etag));
+        }
+        Ok(items)
+    }
+
+    async fn get_all_items(&self, collection: &Collection) -> Result<Vec<(Href, I, Etag)>> {
+        let inner = self.lock();
+        let href = collection.href();
+        let items = inner
+            .collection(href)?
+            .items
+            .iter()
+            .filter(|(item_href, _)| !inner.is_hidden(href, item_href))
+            .map(|(item_href, item)| {
+                let etag = inner.reported_etag(href, item_href, item);
+                (item_href.clone(), I::from(item.raw.clone()), etag)
+            })
+            .collect();
+        Ok(items)
+    }
+
+    async fn add_item(&mut self, collection: &Collection, item: &I) -> Result<ItemRef> {
+        let mut inner = self.lock();
+        inner.collection(collection.href())?;
+        inner.write()?;
+        let etag = inner.next_etag();
+        let memory_collection = inner.collection_mut(collection.href())?;
+
+        let mut href = item.ident();
+        if memory_collection.items.contains_key(&href) {
+            href = format!("{href}-{etag}");
+        }
+        memory_collection.items.insert(
+            href.clone(),
+            MemoryItem {
+                raw: item.as_str().to_string(),
+                etag,
+                previous_etag: None,
+            },
+        );
+        memory_collection.record_change(&href, etag);
+
+        Ok(ItemRef {
+            href,
+            etag: Etag::from(etag.to_string()),
+        })
+    }
+
+    async fn update_item(
+        &mut self,
+        collection: &Collection,
+        href: &str,
+        etag: &Etag,
+        item: &I,
+    ) -> Result<Etag> {
+        let mut inner = self.lock();
+        inner.verify_etag(collection.href(), href, etag)?;
+        inner.write()?;
+        let new_etag = inner.next_etag();
+
+        let memory_collection = inner.collection_mut(collection.href())?;
+        let existing = memory_collection
+            .items
+            .get_mut(href)
+            .expect("existence was checked above");
+        existing.raw = item.as_str().to_string();
+        existing.previous_etag = Some(existing.etag);
+        existing.etag = new_etag;
+        memory_collection.record_change(href, new_etag);
+
+        Ok(Etag::from(new_etag.to_string()))
+    }
+
+    async fn delete_item(
+        &mut self,
+        collection: &Collection,
+        href: &str,
+        etag: &Etag,
+    ) -> Result<()> {
+        let mut inner = self.lock();
+        inner.verify_etag(collection.href(), href, etag)?;
+        inner.write()?;
+        let version = inner.next_etag();
+
+        let memory_collection = inner.collection_mut(collection.href())?;
+        memory_collection.items.remove(href);
+        memory_collection.record_change(href, version);
+
+        Ok(())
+    }
+
+    fn collection_id(&self, collection: &Collection) -> Result<String> {
+        Ok(collection.href().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::{CalendarProperty, IcsItem, Item, Storage};
+    use crate::{ErrorKind, Etag};
+
+    use super::MemoryStorage;
+
+    fn event(uid: &str, summary: &str) -> IcsItem {
+        IcsItem::from(format!(
+            "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:{uid}\r\nSUMMARY:{summary}\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n"
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let mut storage = MemoryStorage::<IcsItem>::new();
+        let collection = storage.create_collection("calendar").await.unwrap();
+        storage
+            .set_collection_property(&collection, CalendarProperty::DisplayName, "Calendar")
+            .await
+            .unwrap();
+        let displayname = storage
+            .get_collection_property(&collection, CalendarProperty::DisplayName)
+            .await
+            .unwrap();
+        assert_eq!(displayname.as_deref(), Some("Calendar"));
+
+        let first = storage
+            .add_item(&collection, &event("one", "First"))
+            .await
+            .unwrap();
+        assert_eq!(first.href, "one");
+        assert_eq!(first.etag, Etag::from("2"));
+        let duplicate = storage
+            .add_item(&collection, &event("one", "Duplicate"))
+            .await
+            .unwrap();
+        assert_ne!(duplicate.href, first.href);
+
+        let etag = storage
+            .update_item(
+                &collection,
+                &first.href,
+                &first.etag,
+                &event("one", "Updated"),
+            )
+            .await
+            .unwrap();
+        let (item, read_etag) = storage.get_item(&collection, &first.href).await.unwrap();
+        assert!(item.as_str().contains("SUMMARY:Updated"));
+        assert_eq!(read_etag, etag);
+
+        let err = storage
+            .delete_item(&collection, &first.href, &first.etag)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PreconditionFailed);
+        storage
+            .delete_item(&collection, &first.href, &etag)
+            .await
+            .unwrap();
+        assert_eq!(storage.list_items(&collection).await.unwrap().len(), 1);
+
+        let err = storage.destroy_collection("calendar").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::CollectionNotEmpty);
+    }
+
+    #[tokio::test]
+    async fn test_changed_items() {
+        let mut storage = MemoryStorage::<IcsItem>::new();
+        let collection = storage.create_collection("calendar").await.unwrap();
+        let one = storage
+            .add_item(&collection, &event("one", "One"))
+            .await
+            .unwrap();
+        storage
+            .add_item(&collection, &event("two", "Two"))
+            .await
+            .unwrap();
+
+        let changes = storage.changed_items(&collection, None).await.unwrap();
+        assert_eq!(changes.changed.len(), 2);
+        assert!(changes.deleted.is_empty());
+
+        storage
+            .delete_item(&collection, &one.href, &one.etag)
+            .await
+            .unwrap();
+        storage
+            .add_item(&collection, &event("three", "Three"))
+            .await
+            .unwrap();
+        let changes = storage
+            .changed_items(&collection, Some(&changes.sync_token))
+            .await
+            .unwrap();
+        assert_eq!(changes.changed.len(), 1);
+        assert_eq!(changes.changed[0].href, "three");
+        assert_eq!(changes.deleted, vec!["one".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_fault_injection() {
+        let mut storage = MemoryStorage::<IcsItem>::new();
+        let handle = storage.clone();
+        let collection = storage.create_collection("calendar").await.unwrap();
+
+        handle.fail_write(2);
+        let one = storage
+            .add_item(&collection, &event("one", "One"))
+            .await
+            .unwrap();
+        let err = storage
+            .add_item(&collection, &event("two", "Two"))
+            .await
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Io);
+        assert!(handle.raw_item("calendar", "two").is_none());
+        storage
+            .add_item(&collection, &event("two", "Two"))
+            .await
+            .unwrap();
+
+        handle.hide_item("calendar", "two");
+        let listed = storage.list_items(&collection).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].href, "one");
+        assert!(storage.get_item(&collection, "two").await.is_ok());
+        assert!(storage
+            .unlisted_items(&collection)
+            .await
+            .unwrap()
+            .is_empty());
+
+        handle.exclude_item("calendar", "one");
+        assert!(storage.list_items(&collection).await.unwrap().is_empty());
+        let unlisted = storage.unlisted_items(&collection).await.unwrap();
+        assert_eq!(unlisted.len(), 1);
+        assert_eq!(unlisted[0].href, "one");
+        let err = storage
+            .changed_items(&collection, None)
+            .await
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+
+        let etag = storage
+            .update_item(&collection, &one.href, &one.etag, &event("one", "Updated"))
+            .await
+            .unwrap();
+        handle.return_stale_etag("calendar", "one");
+        let (_, stale) = storage.get_item(&collection, &one.href).await.unwrap();
+        assert_eq!(stale, one.etag);
+        let err = storage
+            .delete_item(&collection, &one.href, &stale)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PreconditionFailed);
+
+        handle.clear_faults();
+        assert_eq!(storage.list_items(&collection).await.unwrap().len(), 2);
+        storage
+            .delete_item(&collection, &one.href, &etag)
+            .await
+            .unwrap();
+    }
+}
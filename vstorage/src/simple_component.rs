@@ -2,7 +2,10 @@
 //
 // SPDX-License-Identifier: EUPL-1.2
 
-use std::{borrow::Cow, collections::HashMap};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
 
 /// A simple component model that only cares about the basic structure.
 ///
@@ -12,14 +15,14 @@ use std::{borrow::Cow, collections::HashMap};
 /// to be very tolerant with inputs, so as to allow operating on somewhat
 /// invalid inputs.
 ///
-/// # Known Issues
-///
-/// Works only with iCalendar, but not with vCard.
+/// Files with multiple `VCARD`s have multiple root components, and need to be parsed with
+/// [`Component::parse_many`].
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) struct Component<'a> {
     kind: &'a str,
     lines: Vec<&'a str>,
     subcomponents: Vec<Component<'a>>,
+    /// The `UID` of this component, ignoring those of its subcomponents (e.g.: a `VALARM`).
     uid: Option<Cow<'a, str>>,
 }
 
@@ -59,29 +62,23 @@ impl<'a> Component<'a> {
 
     /// Parse a component from a raw string input.
     pub(crate) fn parse(input: &str) -> Result<Component, ComponentError> {
-        let mut root: Option<Component> = None;
+        let mut roots = Self::parse_many(input)?;
+        match roots.len() {
+            0 => Err(ComponentError::EmptyInput),
+            1 => Ok(roots.remove(0)),
+            _ => Err(ComponentError::MultipleRootComponents),
+        }
+    }
+
+    /// Parse all root components from a raw string input.
+    ///
+    /// Unlike [`Component::parse`], this accepts multiple root components (e.g.: a file with
+    /// several `VCARD`s). Returns an empty `Vec` for empty input.
+    pub(crate) fn parse_many(input: &str) -> Result<Vec<Component<'_>>, ComponentError> {
+        let mut roots = Vec::new();
         let mut stack = Vec::new();
-        let mut uid = Option::<Cow<'_, str>>::None;
-        // Indicates whether we're reading a multiline UID.
-        // I.e.: true if and only if the last line was part of the UID.
-        let mut reading_uid = false;
 
         for line in input.lines() {
-            if let Some(u) = line.strip_prefix("UID:") {
-                uid = Some(Cow::from(u));
-                reading_uid = true;
-            } else if reading_uid {
-                if let Some(cont_uid) = line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')) {
-                    uid = uid.map(|u| {
-                        let mut u = u.into_owned();
-                        u.push_str(cont_uid);
-                        Cow::from(u)
-                    });
-                } else {
-                    reading_uid = false;
-                }
-            }
-
             if let Some(kind) = line.strip_prefix("BEGIN:") {
                 stack.push(Component::new(kind));
             } else if let Some(kind) = line.strip_prefix("END:") {
@@ -90,15 +87,15 @@ impl<'a> Component<'a> {
                     return Err(ComponentError::UnbalancedInput);
                 }
 
-                component.uid = uid.take();
+                // Found the same way as `Item::uid`, so that both always agree.
+                component.uid = component.property("UID").map(Cow::from);
 
                 if let Some(top) = stack.last_mut() {
                     top.subcomponents.push(component);
-                } else if root.replace(component).is_some() {
-                    return Err(ComponentError::MultipleRootComponents);
+                } else {
+                    roots.push(component);
                 }
             } else {
-                // Hint: Lines starting with `UID:` also get pushed here.
                 stack
                     .last_mut()
                     .ok_or(ComponentError::DataOutsideBeginEnd)?
@@ -107,38 +104,84 @@ impl<'a> Component<'a> {
             }
         }
 
-        if let Some(root) = root {
-            Ok(root)
-        } else if stack.is_empty() {
-            Err(ComponentError::EmptyInput)
+        if stack.is_empty() {
+            Ok(roots)
         } else {
             Err(ComponentError::UnterminatedComponent)
         }
     }
 
+    /// Parses input with any amount of root components and breaks it up into individual ones.
+    ///
+    /// See [`Component::parse_many`] and [`Component::into_split_collection`].
+    pub(crate) fn split_collection(input: &str) -> Result<Vec<Component<'_>>, ComponentError> {
+        let mut items = Vec::new();
+        for root in Component::parse_many(input)? {
+            items.append(&mut root.into_split_collection()?);
+        }
+        Ok(items)
+    }
+
     // Breaks up a component collection into individual components.
     //
-    // For a calendar with multiple `VEVENT`s and `VTIMEZONE`, it will return individual `VEVENT`
-    // with the `VTIMEZONE` duplicated into each one, making them fully standalone components.
+    // For a calendar with multiple `VEVENT`s and `VTIMEZONE`s, it will return individual `VEVENT`s
+    // along with the `VTIMEZONE`s that they reference, making them fully standalone components.
+    // Each one is wrapped in a `VCALENDAR` with the same `VERSION`, `PRODID` and `CALSCALE` as the
+    // original one. Components without a `UID` are each returned as a separate item.
     pub(crate) fn into_split_collection(
         self: Component<'a>,
     ) -> Result<Vec<Component<'a>>, ComponentError> {
         let mut inline = Vec::new();
         let mut items_with_uid = HashMap::new();
+        let mut cards = Vec::new();
         let mut items_without_uid = Vec::new();
 
-        self.split_inner(&mut inline, &mut items_with_uid, &mut items_without_uid)?;
+        let properties = if self.kind == "VCALENDAR" {
+            standalone_properties(&self.lines)
+        } else {
+            Vec::new()
+        };
+        self.split_inner(
+            &mut inline,
+            &mut items_with_uid,
+            &mut cards,
+            &mut items_without_uid,
+        )?;
+
+        let wrapped_without_uid = items_without_uid.into_iter().map(|component| {
+            if component.kind == "VCARD" {
+                return component;
+            }
+            Component {
+                kind: "VCALENDAR",
+                lines: Vec::new(),
+                subcomponents: vec![component],
+                uid: None,
+            }
+        });
 
         let items_with_timezones = items_with_uid
             .into_values()
+            .chain(wrapped_without_uid)
             .map(|mut wrapper| {
-                for entry in &mut *wrapper.subcomponents {
-                    // Clone here because `append` empties the passed input.
-                    entry.subcomponents.append(&mut (inline.clone()));
-                    // FIXME: this copies all timezones into all components. I can do better.
+                if wrapper.kind == "VCALENDAR" {
+                    wrapper.lines = properties.clone();
+                    let referenced = wrapper
+                        .subcomponents
+                        .iter()
+                        .flat_map(|c| crate::util::referenced_timezones(&c.to_string()))
+                        .collect::<HashSet<_>>();
+                    let mut timezones = inline
+                        .iter()
+                        .filter(|tz| tz.tzid().is_some_and(|tzid| referenced.contains(tzid)))
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    timezones.append(&mut wrapper.subcomponents);
+                    wrapper.subcomponents = timezones;
                 }
                 wrapper
             })
+            .chain(cards)
             .collect();
 
         Ok(items_with_timezones)
@@ -146,13 +189,14 @@ impl<'a> Component<'a> {
 
     /// Split components inside this one recursively.
     ///
-    /// Subcomponents are split into three groups:
+    /// Subcomponents are split into four groups:
     ///
-    /// - `inline`: those that must be copied inline (e.g.: `VTIMEZONE`)
-    /// - `items`: items with a UID (which is the key for the `HashMap`.
+    /// - `inline`: those that must be copied into each item that uses them (e.g.: `VTIMEZONE`)
+    /// - `items`: calendar items with a UID (which is the key for the `HashMap`.
+    /// - `cards`: `VCARD`s with a UID. These are never grouped, so several may share a UID.
     /// - `without_uid`: items which as missing a UID.
     ///
-    /// Both `items` and `without_uid` are free-standing items for [`Collection`]s.
+    /// `items`, `cards` and `without_uid` are free-standing items for [`Collection`]s.
     ///
     /// Calendar components will be put inside their own wrapper (e.g.: a `VEVENT` will be wrapped
     /// inside its own `VCALENDAR`.
@@ -162,6 +206,7 @@ impl<'a> Component<'a> {
         self: Component<'a>,
         inline: &mut Vec<Component<'a>>,
         items: &mut HashMap<Cow<'a, str>, Component<'a>>,
+        cards: &mut Vec<Component<'a>>,
         without_uid: &mut Vec<Component<'a>>,
     ) -> Result<(), ComponentError> {
         match self.kind {
@@ -188,19 +233,16 @@ impl<'a> Component<'a> {
                     }
                 }
             }
-            "VCARD" => match &self.uid {
-                Some(uid) => {
-                    if items.insert(uid.clone(), self).is_some() {
-                        todo!("vcard with duplicate UID found!"); // FIXME!
-                    }
-                }
-                None => {
+            "VCARD" => {
+                if self.uid.is_some() {
+                    cards.push(self);
+                } else {
                     without_uid.push(self);
                 }
-            },
+            }
             "VCALENDAR" => {
                 for component in self.subcomponents {
-                    Self::split_inner(component, inline, items, without_uid)?;
+                    Self::split_inner(component, inline, items, cards, without_uid)?;
                 }
             }
             kind => return Err(ComponentError::UnknownKind(kind.to_string())),
@@ -208,6 +250,87 @@ impl<'a> Component<'a> {
 
         Ok(())
     }
+
+    /// Removes all components with the given UID from a collection of root components.
+    ///
+    /// This is the inverse of [`Component::add_to_collection`]. Returns `true` if any component
+    /// was removed. Timezones are kept, since other components may still refer to them.
+    pub(crate) fn remove_from_collection(collection: &mut Vec<Component<'a>>, uid: &str) -> bool {
+        let mut removed = false;
+        collection.retain(|root| {
+            let matches = root.uid.as_deref() == Some(uid);
+            removed |= matches;
+            !matches
+        });
+        for calendar in collection.iter_mut().filter(|c| c.kind == "VCALENDAR") {
+            calendar.subcomponents.retain(|c| {
+                let matches = c.uid.as_deref() == Some(uid);
+                removed |= matches;
+                !matches
+            });
+        }
+
+        removed
+    }
+
+    /// Adds a standalone item to a collection of root components.
+    ///
+    /// The components of a calendar item are merged into the first `VCALENDAR` in the collection,
+    /// skipping timezones which are already present. Any other item is added as a new root
+    /// component.
+    pub(crate) fn add_to_collection(collection: &mut Vec<Component<'a>>, item: Component<'a>) {
+        let calendar = match item.kind {
+            "VCALENDAR" => collection.iter_mut().find(|c| c.kind == "VCALENDAR"),
+            _ => None,
+        };
+        let Some(calendar) = calendar else {
+            collection.push(item);
+            return;
+        };
+
+        for component in item.subcomponents {
+            let duplicate = component.kind == "VTIMEZONE"
+                && calendar
+                    .subcomponents
+                    .iter()
+                    .any(|c| c.kind == "VTIMEZONE" && c.tzid() == component.tzid());
+            if !duplicate {
+                calendar.subcomponents.push(component);
+            }
+        }
+    }
+
+    /// Returns the value of a property of this component (ignoring those of subcomponents).
+    pub(crate) fn property(&self, name: &str) -> Option<String> {
+        crate::util::property_value(&self.lines.join("\r\n"), name)
+    }
+
+    /// Returns the `TZID` of a `VTIMEZONE`.
+    fn tzid(&self) -> Option<&str> {
+        self.lines
+            .iter()
+            .find_map(|line| line.strip_prefix("TZID:"))
+    }
+}
+
+/// Returns the lines of a `VCALENDAR` which must be copied into each item split from it.
+///
+/// Properties folded across multiple lines are kept whole.
+fn standalone_properties<'a>(lines: &[&'a str]) -> Vec<&'a str> {
+    let mut keep = false;
+    lines
+        .iter()
+        .filter(|line| {
+            if !line.starts_with([' ', '\t']) {
+                let name = line.split([':', ';']).next().unwrap_or_default();
+                keep = ["VERSION", "PRODID", "CALSCALE"]
+                    .iter()
+                    .any(|p| p.eq_ignore_ascii_case(name));
+            }
+            keep
+        })
+        .copied()
+        .collect()
 }
 
 impl ToString for Component<'_> {
@@ -261,7 +384,7 @@ mod test {
             "END:STANDARD",
             "END:VTIMEZONE",
             "BEGIN:VEVENT",
-            "DTSTART:19970714T170000Z",
+            "DTSTART;TZID=Europe/Rome:19970714T170000",
             "DTEND:19970715T035959Z",
             "SUMMARY:Bastille Day Party",
             "X-SOMETHING:r",
@@ -320,7 +443,7 @@ mod test {
                     Component {
                         kind: "VEVENT",
                         lines: vec!(
-                            "DTSTART:19970714T170000Z",
+                            "DTSTART;TZID=Europe/Rome:19970714T170000",
                             "DTEND:19970715T035959Z",
                             "SUMMARY:Bastille Day Party",
                             "X-SOMETHING:r",
@@ -346,53 +469,28 @@ mod test {
             }
         ); // end assert
 
+        // Only the first event uses the timezone.
+        let timezone = component.subcomponents[0].clone();
         let mut actual_split = Component::into_split_collection(component).unwrap();
         let mut expected_split = vec![
             Component {
                 kind: "VCALENDAR",
                 lines: vec![],
-                subcomponents: vec![Component {
-                    kind: "VEVENT",
-                    lines: vec![
-                        "DTSTART:19970714T170000Z",
-                        "DTEND:19970715T035959Z",
-                        "SUMMARY:Bastille Day Party",
-                        "X-SOMETHING:r",
-                        "UID:11bb6bed-c29b-4999-a627-12dee35f8395",
-                    ],
-                    subcomponents: vec![Component {
-                        kind: "VTIMEZONE",
-                        lines: vec!["TZID:Europe/Rome", "X-LIC-LOCATION:Europe/Rome"],
-                        subcomponents: vec![
-                            Component {
-                                kind: "DAYLIGHT",
-                                lines: vec![
-                                    "TZOFFSETFROM:+0100",
-                                    "TZOFFSETTO:+0200",
-                                    "TZNAME:CEST",
-                                    "DTSTART:19700329T020000",
-                                    "RRULE:FREQ=YEARLY;BYDAY=-1SU;BYMONTH=3",
-                                ],
-                                subcomponents: vec![],
-                                uid: None,
-                            },
-                            Component {
-                                kind: "STANDARD",
-                                lines: vec![
-                                    "TZOFFSETFROM:+0200",
-                                    "TZOFFSETTO:+0100",
-                                    "TZNAME:CET",
-                                    "DTSTART:19701025T030000",
-                                    "RRULE:FREQ=YEARLY;BYDAY=-1SU;BYMONTH=10",
-                                ],
-                                subcomponents: vec![],
-                                uid: None,
-                            },
+                subcomponents: vec![
+                    timezone,
+                    Component {
+                        kind: "VEVENT",
+                        lines: vec![
+                            "DTSTART;TZID=Europe/Rome:19970714T170000",
+                            "DTEND:19970715T035959Z",
+                            "SUMMARY:Bastille Day Party",
+                            "X-SOMETHING:r",
+                            "UID:11bb6bed-c29b-4999-a627-12dee35f8395",
                         ],
-                        uid: None,
-                    }],
-                    uid: Some("11bb6bed-c29b-4999-a627-12dee35f8395".into()),
-                }],
+                        subcomponents: vec![],
+                        uid: Some("11bb6bed-c29b-4999-a627-12dee35f8395".into()),
+                    },
+                ],
                 uid: None,
             },
             Component {
@@ -407,37 +505,7 @@ mod test {
                         "X-SOMETHING:s",
                         "UID:b8d52b8b-dd6b-4ef9-9249-0ad7c28f9e5a",
                     ],
-                    subcomponents: vec![Component {
-                        kind: "VTIMEZONE",
-                        lines: vec!["TZID:Europe/Rome", "X-LIC-LOCATION:Europe/Rome"],
-                        subcomponents: vec![
-                            Component {
-                                kind: "DAYLIGHT",
-                                lines: vec![
-                                    "TZOFFSETFROM:+0100",
-                                    "TZOFFSETTO:+0200",
-                                    "TZNAME:CEST",
-                                    "DTSTART:19700329T020000",
-                                    "RRULE:FREQ=YEARLY;BYDAY=-1SU;BYMONTH=3",
-                                ],
-                                subcomponents: vec![],
-                                uid: None,
-                            },
-                            Component {
-                                kind: "STANDARD",
-                                lines: vec![
-                                    "TZOFFSETFROM:+0200",
-                                    "TZOFFSETTO:+0100",
-                                    "TZNAME:CET",
-                                    "DTSTART:19701025T030000",
-                                    "RRULE:FREQ=YEARLY;BYDAY=-1SU;BYMONTH=10",
-                                ],
-                                subcomponents: vec![],
-                                uid: None,
-                            },
-                        ],
-                        uid: None,
-                    }],
+                    subcomponents: vec![],
                     uid: Some("b8d52b8b-dd6b-4ef9-9249-0ad7c28f9e5a".into()),
                 }],
                 uid: None,
@@ -527,4 +595,100 @@ mod test {
             "horrible-example"
         );
     }
+
+    #[test]
+    fn test_uid_after_subcomponent() {
+        use super::Component;
+
+        let calendar = vec![
+            "BEGIN:VCALENDAR",
+            "BEGIN:VEVENT",
+            "UID:with-alarm",
+            "BEGIN:VALARM",
+            "ACTION:DISPLAY",
+            "END:VALARM",
+            "END:VEVENT",
+            "END:VCALENDAR",
+        ]
+        .join("\r\n");
+
+        let calendar = Component::parse(&calendar).unwrap();
+        let event = &calendar.subcomponents[0];
+        assert_eq!(event.uid.as_deref(), Some("with-alarm"));
+        assert_eq!(event.subcomponents[0].uid, None);
+    }
+
+    #[test]
+    fn test_uid_in_subcomponent() {
+        use super::Component;
+
+        let calendar = vec![
+            "BEGIN:VCALENDAR",
+            "BEGIN:VEVENT",
+            "UID;X-PARAM=x:event-",
+            " uid",
+            "BEGIN:VALARM",
+            "UID:alarm-uid",
+            "ACTION:DISPLAY",
+            "END:VALARM",
+            "END:VEVENT",
+            "END:VCALENDAR",
+        ]
+        .join("\r\n");
+
+        let mut roots = Component::parse_many(&calendar).unwrap();
+        let event = &roots[0].subcomponents[0];
+        assert_eq!(event.uid.as_deref(), Some("event-uid"));
+        assert_eq!(event.subcomponents[0].uid.as_deref(), Some("alarm-uid"));
+
+        assert!(!Component::remove_from_collection(&mut roots, "alarm-uid"));
+        assert!(Component::remove_from_collection(&mut roots, "event-uid"));
+        assert!(roots[0].subcomponents.is_empty());
+    }
+
+    #[test]
+    fn test_split_keeps_properties_and_items_without_uid() {
+        use super::Component;
+
+        let calendar = vec![
+            "BEGIN:VCALENDAR",
+            "VERSION:2.0",
+            "PRODID:-//Example Corp//Some Very Long Product Name Which Does Not Fit In A ",
+            " Single Line//EN",
+            "X-WR-CALNAME:Holidays",
+            "METHOD:PUBLISH",
+            "BEGIN:VEVENT",
+            "UID:with-uid",
+            "END:VEVENT",
+            "BEGIN:VEVENT",
+            "SUMMARY:Without UID",
+            "END:VEVENT",
+            "END:VCALENDAR",
+        ]
+        .join("\r\n");
+
+        let calendar = Component::parse(&calendar).unwrap();
+        assert_eq!(
+            calendar.property("X-WR-CALNAME").as_deref(),
+            Some("Holidays")
+        );
+
+        let mut split = calendar.into_split_collection().unwrap();
+        split.sort();
+        assert_eq!(split.len(), 2);
+        for item in &split {
+            assert_eq!(item.kind, "VCALENDAR");
+            assert_eq!(
+                item.lines,
+                [
+                    "VERSION:2.0",
+                    "PRODID:-//Example Corp//Some Very Long Product Name Which Does Not Fit In A ",
+                    " Single Line//EN",
+                ]
+            );
+            assert_eq!(item.subcomponents.len(), 1);
+        }
+        assert_eq!(split[0].subcomponents[0].uid, None);
+        assert_eq!(split[1].subcomponents[0].uid.as_deref(), Some("with-uid"));
+    }
 }
@@ -0,0 +1,777 @@
+// Copyright 2023 Hugo Osvaldo Barrera
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+//! Implements reading/writing entries from a single icalendar or vcard file.
+//!
+//! - The `href` for an item is its UID.
+//! - The `href` for a collection is the part of its filename matched by the `*` in
+//!   [`SinglefileDefinition::path`]. If the path has no `*`, it is the filename without its
+//!   extension.
+//!
+//! The file is split into items the same way as for [`webcal`](crate::webcal), and the etag of
+//! each item is a digest of its exact content. Items without a UID are kept in the file when it is
+//! rewritten, but are not exposed.
+//!
+//! Any change rewrites the whole file. The new content is written into a hidden temporary file
+//! next to it, which is then given the same permissions and moved into place. If the file was
+//! modified by someone else in the meantime, the operation fails with
+//! [`ErrorKind::PreconditionFailed`].
+#![allow(clippy::module_name_repetitions)]
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::Metadata;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use tokio::fs::{metadata, read_dir, read_to_string, remove_file, rename, OpenOptions};
+
+use crate::base::{Collection, Definition, Item, ItemRef, Storage};
+use crate::filesystem::{copy_permissions, discard, etag_for_metadata, write_temp_file};
+use crate::simple_component::Component;
+use crate::util::digest;
+use crate::{Error, ErrorKind, Etag, Href, Result};
+
+/// A storage where each collection is a single file.
+///
+/// Either a single file, or all files matching a pattern in a directory, each of them being a
+/// separate [`Collection`].
+pub struct SinglefileStorage<I: Item> {
+    /// The directory containing the file(s).
+    dir: PathBuf,
+    filename: Filename,
+    i: PhantomData<I>,
+}
+
+/// The name of the file(s) for a storage.
+enum Filename {
+    /// A single file; contains the name of its only collection.
+    Fixed { name: String, collection: Href },
+    /// A pattern with a single `*`, split into the text before and after it.
+    Pattern { prefix: String, suffix: String },
+}
+
+#[async_trait]
+impl<I: Item> Storage<I> for SinglefileStorage<I> {
+    async fn check(&self) -> Result<()> {
+        let meta = metadata(&self.dir)
+            .await
+            .map_err(|e| Error::new(ErrorKind::DoesNotExist, e))?;
+        if !meta.is_dir() {
+            return Err(Error::from(ErrorKind::NotAStorage));
+        }
+
+        if let Filename::Fixed { name, .. } = &self.filename {
+            match metadata(self.dir.join(name)).await {
+                Ok(meta) if !meta.is_file() => return Err(Error::from(ErrorKind::NotAStorage)),
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Error::from(e)),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn discover_collections(&self) -> Result<Vec<Collection>> {
+        let mut collections = Vec::new();
+        match &self.filename {
+            Filename::Fixed { name, collection } => match metadata(self.dir.join(name)).await {
+                Ok(meta) => collections.push(
+                    Collection::new(collection.clone())
+                        .with_tag(Some(etag_for_metadata(&meta).to_string())),
+                ),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Error::from(e)),
+            },
+            Filename::Pattern { .. } => {
+                let mut entries = read_dir(&self.dir).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    let Some(href) = entry.file_name().to_str().and_then(|n| self.href_for(n))
+                    else {
+                        continue;
+                    };
+                    let meta = metadata(entry.path()).await?;
+                    if meta.is_file() {
+                        collections.push(
+                            Collection::new(href)
+                                .with_tag(Some(etag_for_metadata(&meta).to_string())),
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(collections)
+    }
+
+    /// Creates a new empty file for the collection.
+    async fn create_collection(&mut self, href: &str) -> Result<Collection> {
+        let path = self.collection_path(href)?;
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+
+        self.open_collection(href)
+    }
+
+    /// Deletes the file for the collection.
+    ///
+    /// Fails with [`ErrorKind::CollectionNotEmpty`] if the file contains any items.
+    async fn destroy_collection(&mut self, href: &str) -> Result<()> {
+        let path = self.collection_path(href)?;
+        if !split::<I>(&read_to_string(&path).await?)?.is_empty() {
+            return Err(Error::from(ErrorKind::CollectionNotEmpty));
+        }
+        remove_file(path).await.map_err(Error::from)
+    }
+
+    fn open_collection(&self, href: &str) -> Result<Collection> {
+        self.collection_path(href)?;
+        Ok(Collection::new(href.to_string()))
+    }
+
+    async fn list_items(&self, collection: &Collection) -> Result<Vec<ItemRef>> {
+        let (raw, _) = self.read(collection).await?;
+        let refs = split::<I>(&raw)?
+            .into_iter()
+            .map(|item| ItemRef {
+                href: item.ident(),
+                etag: etag_of(&item),
+            })
+            .collect();
+
+        Ok(refs)
+    }
+
+    async fn get_item(&self, collection: &Collection, href: &str) -> Result<(I, Etag)> {
+        let (raw, _) = self.read(collection).await?;
+        let item = split::<I>(&raw)?
+            .into_iter()
+            .find(|item| item.ident() == href)
+            .ok_or_else(|| Error::from(ErrorKind::DoesNotExist))?;
+
+        let etag = etag_of(&item);
+        Ok((item, etag))
+    }
+
+    async fn get_many_items(
+        &self,
+        collection: &Collection,
+        hrefs: &[&str],
+    ) -> Result<Vec<(Href, I, Etag)>> {
+        let (raw, _) = self.read(collection).await?;
+        let mut items = split::<I>(&raw)?
+            .into_iter()
+            .map(|item| (item.ident(), item))
+            .collect::<HashMap<_, _>>();
+
+        hrefs
+            .iter()
+            .map(|href| {
+                let item = items.remove(*href).ok_or_else(|| {
+                    Error::new(ErrorKind::DoesNotExist, format!("no item {href}"))
+                })?;
+                let etag = etag_of(&item);
+                Ok((String::from(*href), item, etag))
+            })
+            .collect()
+    }
+
+    /// Fetch all items in the collection.
+    ///
+    /// Reads the file only once.
+    async fn get_all_items(&self, collection: &Collection) -> Result<Vec<(Href, I, Etag)>> {
+        let (raw, _) = self.read(collection).await?;
+        let items = split::<I>(&raw)?
+            .into_iter()
+            .map(|item| {
+                let etag = etag_of(&item);
+                (item.ident(), item, etag)
+            })
+            .collect();
+
+        Ok(items)
+    }
+
+    /// Unsupported for this storage type.
+    async fn set_collection_property(
+        &mut self,
+        _collection: &Collection,
+        _: I::CollectionProperty,
+        _: &str,
+    ) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "setting metadata for single-file storages is not supported",
+        ))
+    }
+
+    /// Always returns `None`; properties are not stored for this storage type.
+    async fn get_collection_property(
+        &self,
+        _collection: &Collection,
+        _: I::CollectionProperty,
+    ) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Adds an item to the collection's file.
+    ///
+    /// The item must have a UID, and no other item in the collection may have the same one.
+    async fn add_item(&mut self, collection: &Collection, item: &I) -> Result<ItemRef> {
+        let href = item.uid().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "items without a UID cannot be added to a single-file storage",
+            )
+        })?;
+        let (raw, before) = self.read(collection).await?;
+        let data = rewrite(&raw, None, Some(item))?;
+        let etag = etag_in::<I>(&data, &href)?;
+        self.save(collection, &data, &before).await?;
+
+        Ok(ItemRef { href, etag })
+    }
+
+    async fn update_item(
+        &mut self,
+        collection: &Collection,
+        href: &str,
+        etag: &Etag,
+        item: &I,
+    ) -> Result<Etag> {
+        if item.uid().as_deref() != Some(href) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the UID of an item cannot be changed in a single-file storage",
+            ));
+        }
+        let (raw, before) = self.read(collection).await?;
+        let data = rewrite(&raw, Some((href, etag)), Some(item))?;
+        let etag = etag_in::<I>(&data, href)?;
+        self.save(collection, &data, &before).await?;
+
+        Ok(etag)
+    }
+
+    async fn delete_item(
+        &mut self,
+        collection: &Collection,
+        href: &str,
+        etag: &Etag,
+    ) -> Result<()> {
+        let (raw, before) = self.read(collection).await?;
+        let data = rewrite::<I>(&raw, Some((href, etag)), None)?;
+        self.save(collection, &data, &before).await
+    }
+
+    fn collection_id(&self, collection: &Collection) -> Result<String> {
+        Ok(collection.href().to_string())
+    }
+}
+
+impl<I: Item> SinglefileStorage<I> {
+    /// Returns the path of the file for the collection with the given `href`.
+    fn collection_path(&self, href: &str) -> Result<PathBuf> {
+        match &self.filename {
+            Filename::Fixed { name, collection } if collection == href => Ok(self.dir.join(name)),
+            Filename::Fixed { collection, .. } => Err(Error::new(
+                ErrorKind::DoesNotExist,
+                format!("this storage only contains the '{collection}' collection"),
+            )),
+            Filename::Pattern { prefix, suffix } => {
+                if href.is_empty() || href.starts_with('.') || href.contains('/') {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("'{href}' is not a valid collection name"),
+                    ));
+                }
+                Ok(self.dir.join(format!("{prefix}{href}{suffix}")))
+            }
+        }
+    }
+
+    /// Returns the `href` of the collection stored in the file with the given name, if any.
+    fn href_for(&self, filename: &str) -> Option<Href> {
+        let Filename::Pattern { prefix, suffix } = &self.filename else {
+            return None;
+        };
+        let href = filename
+            .strip_prefix(prefix.as_str())?
+            .strip_suffix(suffix.as_str())?;
+        if href.is_empty() || href.starts_with('.') {
+            return None;
+        }
+        Some(href.to_string())
+    }
+
+    /// Reads the file for a collection, along with its metadata at the time of reading.
+    async fn read(&self, collection: &Collection) -> Result<(String, Metadata)> {
+        let path = self.collection_path(collection.href())?;
+        let meta = metadata(&path).await?;
+        let raw = read_to_string(&path).await?;
+
+        Ok((raw, meta))
+    }
+
+    /// Replaces the file for a collection with `data`.
+    ///
+    /// `before` is the file's metadata from when it was read; if it has changed since, fails with
+    /// [`ErrorKind::PreconditionFailed`].
+    async fn save(&self, collection: &Collection, data: &str, before: &Metadata) -> Result<()> {
+        let path = self.collection_path(collection.href())?;
+        let temp = write_temp_file(&self.dir, data.as_bytes()).await?;
+
+        // Narrows the window for lost updates, but cannot eliminate it entirely.
+        let unchanged = match metadata(&path).await {
+            Ok(after) => etag_for_metadata(&after) == etag_for_metadata(before),
+            Err(_) => false,
+        };
+        if !unchanged {
+            discard(&temp).await;
+            return Err(Error::new(
+                ErrorKind::PreconditionFailed,
+                "file was modified concurrently",
+            ));
+        }
+        // The new file is created with default permissions; keep those of the previous one.
+        let moved = match copy_permissions(&path, &temp).await {
+            Ok(()) => rename(&temp, &path).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = moved {
+            discard(&temp).await;
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns new content for a file, with an existing item removed and/or a new one added.
+///
+/// The item being removed is identified by its `href`, and must match the given etag.
+fn rewrite<I: Item>(raw: &str, remove: Option<(&str, &Etag)>, add: Option<&I>) -> Result<String> {
+    let current = split::<I>(raw)?;
+    if let Some((href, etag)) = remove {
+        let existing = current
+            .iter()
+            .find(|i| i.ident() == href)
+            .ok_or_else(|| Error::from(ErrorKind::DoesNotExist))?;
+        if etag_of(existing) != *etag {
+            return Err(Error::new(ErrorKind::PreconditionFailed, "wrong etag"));
+        }
+    }
+    if let Some(item) = add {
+        let replaced = remove.map(|(href, _)| href);
+        if current
+            .iter()
+            .any(|i| i.ident() == item.ident() && Some(i.ident().as_str()) != replaced)
+        {
+            return Err(Error::from(ErrorKind::DuplicateUid));
+        }
+    }
+
+    let mut roots =
+        Component::parse_many(raw).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    if let Some((href, _)) = remove {
+        Component::remove_from_collection(&mut roots, href);
+    }
+    if let Some(item) = add {
+        let component =
+            Component::parse(item.as_str()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        Component::add_to_collection(&mut roots, component);
+    }
+
+    Ok(roots.iter().map(ToString::to_string).collect())
+}
+
+/// Returns the etag for the item with the given `href` in the content of a file.
+///
+/// Items may look different once split from a file, so this is used instead of hashing an item
+/// before writing it.
+fn etag_in<I: Item>(raw: &str, href: &str) -> Result<Etag> {
+    split::<I>(raw)?
+        .into_iter()
+        .find(|i| i.ident() == href)
+        .map(|i| etag_of(&i))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "item is not a valid component"))
+}
+
+/// Returns the etag for an item.
+///
+/// Unlike [`Item::hash`], this changes with any edit (e.g.: to an `X-` property), so that no change
+/// goes unnoticed.
+fn etag_of<I: Item>(item: &I) -> Etag {
+    Etag::from(digest(item.as_str()))
+}
+
+/// Splits the content of a file into individual items, skipping those without a UID.
+///
+/// # Errors
+///
+/// If the content cannot be parsed, or if multiple items share the same UID.
+fn split<I: Item>(raw: &str) -> Result<Vec<I>> {
+    let mut items = Vec::new();
+    let mut seen = HashSet::new();
+    let components =
+        Component::split_collection(raw).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    for component in components {
+        let item = I::from(component.to_string());
+        // These cannot be addressed when rewriting the file, so are left untouched.
+        if item.uid().is_none() {
+            continue;
+        }
+        if !seen.insert(item.ident()) {
+            return Err(Error::new(
+                ErrorKind::DuplicateUid,
+                format!("multiple items with UID {}", item.ident()),
+            ));
+        }
+        items.push(item);
+    }
+
+    Ok(items)
+}
+
+/// Definition for a [`SinglefileStorage`].
+#[derive(serde::Deserialize, Debug)]
+pub struct SinglefileDefinition<I: Item> {
+    /// The path to the file.
+    ///
+    /// The filename may contain a single `*`, in which case every matching file in the directory
+    /// is a separate collection, e.g.: `~/calendars/*.ics`.
+    pub path: PathBuf,
+    i: PhantomData<I>,
+}
+
+impl<I: Item> SinglefileDefinition<I> {
+    #[must_use]
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            i: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<I: Item + 'static> Definition<I> for SinglefileDefinition<I> {
+    /// Create a new storage instance.
+    ///
+    /// # Errors
+    ///
+    /// If the path has no valid filename, or a `*` anywhere other than once in its filename.
+    async fn storage(self) -> Result<Box<dyn Storage<I>>> {
+        let invalid = |msg| Error::new(ErrorKind::InvalidInput, msg);
+
+        let name = self
+            .path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .ok_or_else(|| invalid("path must end in a valid filename"))?;
+        let dir = match self.path.parent() {
+            Some(parent) if parent.as_os_str().is_empty() => PathBuf::from("."),
+            Some(parent) => parent.to_path_buf(),
+            None => return Err(invalid("path must end in a valid filename")),
+        };
+        if dir.to_string_lossy().contains('*') {
+            return Err(invalid("only the filename may contain a '*'"));
+        }
+
+        let filename = match name.split_once('*') {
+            Some((_, suffix)) if suffix.contains('*') => {
+                return Err(invalid("filename may contain only one '*'"));
+            }
+            Some((prefix, suffix)) => Filename::Pattern {
+                prefix: prefix.to_string(),
+                suffix: suffix.to_string(),
+            },
+            None => Filename::Fixed {
+                name: name.to_string(),
+                collection: self
+                    .path
+                    .file_stem()
+                    .and_then(OsStr::to_str)
+                    .unwrap_or(name)
+                    .to_string(),
+            },
+        };
+
+        Ok(Box::from(SinglefileStorage {
+            dir,
+            filename,
+            i: PhantomData,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::base::{Definition, IcsItem, Item, VcardItem};
+    use crate::ErrorKind;
+
+    use super::SinglefileDefinition;
+
+    fn calendar() -> String {
+        [
+            "BEGIN:VCALENDAR",
+            "VERSION:2.0",
+            "BEGIN:VTIMEZONE",
+            "TZID:Europe/Rome",
+            "END:VTIMEZONE",
+            "BEGIN:VEVENT",
+            "UID:first",
+            "SUMMARY:First",
+            "BEGIN:VALARM",
+            "ACTION:DISPLAY",
+            "END:VALARM",
+            "END:VEVENT",
+            "BEGIN:VEVENT",
+            "SUMMARY:Without UID",
+            "END:VEVENT",
+            "BEGIN:VEVENT",
+            "UID:second",
+            "SUMMARY:Second",
+            "END:VEVENT",
+            "END:VCALENDAR",
+            "",
+        ]
+        .join("\r\n")
+    }
+
+    fn event(uid: &str, summary: &str) -> IcsItem {
+        IcsItem::from(
+            [
+                "BEGIN:VCALENDAR",
+                "VERSION:2.0",
+                "BEGIN:VEVENT",
+                &format!("UID:{uid}"),
+                &format!("SUMMARY:{summary}"),
+                "END:VEVENT",
+                "END:VCALENDAR",
+                "",
+            ]
+            .join("\r\n"),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_read_and_write_items() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("calendar.ics");
+        std::fs::write(&path, calendar()).unwrap();
+
+        let mut storage = SinglefileDefinition::<IcsItem>::new(path.clone())
+            .storage()
+            .await
+            .unwrap();
+        storage.check().await.unwrap();
+        let collections = storage.discover_collections().await.unwrap();
+        assert_eq!(collections.len(), 1);
+        assert_eq!(collections[0].href(), "calendar");
+        let collection = storage.open_collection("calendar").unwrap();
+
+        let mut refs = storage.list_items(&collection).await.unwrap();
+        refs.sort_by(|a, b| a.href.cmp(&b.href));
+        let hrefs = refs.iter().map(|r| r.href.as_str()).collect::<Vec<_>>();
+        assert_eq!(hrefs, ["first", "second"]);
+
+        let (item, etag) = storage.get_item(&collection, "first").await.unwrap();
+        assert!(item.as_str().contains("BEGIN:VALARM"));
+        assert_eq!(etag, refs[0].etag);
+
+        let added = storage
+            .add_item(&collection, &event("third", "Third"))
+            .await
+            .unwrap();
+        assert_eq!(added.href, "third");
+        let (_, etag) = storage.get_item(&collection, "third").await.unwrap();
+        assert_eq!(etag, added.etag);
+
+        let etag = storage
+            .update_item(
+                &collection,
+                "first",
+                &refs[0].etag,
+                &event("first", "Renamed"),
+            )
+            .await
+            .unwrap();
+        let (item, current) = storage.get_item(&collection, "first").await.unwrap();
+        assert!(item.as_str().contains("SUMMARY:Renamed"));
+        assert_eq!(etag, current);
+
+        let err = storage
+            .delete_item(&collection, "second", &refs[0].etag)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PreconditionFailed);
+        storage
+            .delete_item(&collection, "second", &refs[1].etag)
+            .await
+            .unwrap();
+
+        let raw = std::fs::read_to_string(&path).unwrap();
+        assert!(raw.contains("VERSION:2.0"));
+        assert!(raw.contains("SUMMARY:Without UID"));
+        assert_eq!(raw.matches("BEGIN:VTIMEZONE").count(), 1);
+        assert!(!raw.contains("UID:second"));
+        assert_eq!(raw.matches("BEGIN:VCALENDAR").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_item_with_uid_in_alarm() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("calendar.ics");
+        let with_alarm = |summary: &str| {
+            [
+                "BEGIN:VCALENDAR",
+                "BEGIN:VEVENT",
+                "UID:event",
+                &format!("SUMMARY:{summary}"),
+                "BEGIN:VALARM",
+                "UID:alarm",
+                "ACTION:DISPLAY",
+                "END:VALARM",
+                "END:VEVENT",
+                "END:VCALENDAR",
+                "",
+            ]
+            .join("\r\n")
+        };
+        std::fs::write(&path, with_alarm("Original")).unwrap();
+
+        let mut storage = SinglefileDefinition::<IcsItem>::new(path.clone())
+            .storage()
+            .await
+            .unwrap();
+        let collection = storage.open_collection("calendar").unwrap();
+        let (_, etag) = storage.get_item(&collection, "event").await.unwrap();
+
+        let etag = storage
+            .update_item(
+                &collection,
+                "event",
+                &etag,
+                &IcsItem::from(with_alarm("Edited")),
+            )
+            .await
+            .unwrap();
+        let raw = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(raw.matches("BEGIN:VEVENT").count(), 1);
+        assert!(raw.contains("SUMMARY:Edited"));
+
+        storage
+            .delete_item(&collection, "event", &etag)
+            .await
+            .unwrap();
+        assert!(storage.list_items(&collection).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_etag_changes_with_any_edit() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("calendar.ics");
+        std::fs::write(&path, calendar()).unwrap();
+
+        let storage = SinglefileDefinition::<IcsItem>::new(path.clone())
+            .storage()
+            .await
+            .unwrap();
+        let collection = storage.open_collection("calendar").unwrap();
+        let (_, before) = storage.get_item(&collection, "second").await.unwrap();
+
+        // Changes which don't alter an item's hash still alter its etag.
+        let edited = calendar().replace("SUMMARY:Second", "SUMMARY:Second\r\nX-MOZ-SNOOZE:1");
+        std::fs::write(&path, edited).unwrap();
+        let (item, after) = storage.get_item(&collection, "second").await.unwrap();
+        assert!(item.as_str().contains("X-MOZ-SNOOZE"));
+        assert_ne!(before, after);
+    }
+
+    #[tokio::test]
+    async fn test_write_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("calendar.ics");
+        std::fs::write(&path, calendar()).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        let mut storage = SinglefileDefinition::<IcsItem>::new(path.clone())
+            .storage()
+            .await
+            .unwrap();
+        let collection = storage.open_collection("calendar").unwrap();
+        storage
+            .add_item(&collection, &event("third", "Third"))
+            .await
+            .unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[tokio::test]
+    async fn test_pattern_collections() {
+        let dir = tempdir().unwrap();
+        let contact = |uid: &str| {
+            [
+                "BEGIN:VCARD",
+                "VERSION:3.0",
+                &format!("UID:{uid}"),
+                "END:VCARD",
+                "",
+            ]
+            .join("\r\n")
+        };
+        std::fs::write(
+            dir.path().join("work.vcf"),
+            contact("alice") + &contact("bob"),
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not a collection").unwrap();
+
+        let mut storage = SinglefileDefinition::<VcardItem>::new(dir.path().join("*.vcf"))
+            .storage()
+            .await
+            .unwrap();
+        let collections = storage.discover_collections().await.unwrap();
+        let hrefs = collections.iter().map(|c| c.href()).collect::<Vec<_>>();
+        assert_eq!(hrefs, ["work"]);
+        let work = storage.open_collection("work").unwrap();
+        assert_eq!(storage.list_items(&work).await.unwrap().len(), 2);
+
+        let home = storage.create_collection("home").await.unwrap();
+        assert!(storage.list_items(&home).await.unwrap().is_empty());
+        let added = storage
+            .add_item(&home, &VcardItem::from(contact("carol")))
+            .await
+            .unwrap();
+        let raw = std::fs::read_to_string(dir.path().join("home.vcf")).unwrap();
+        assert_eq!(raw, contact("carol"));
+
+        let err = storage
+            .add_item(&home, &VcardItem::from(contact("carol")))
+            .await
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DuplicateUid);
+
+        let err = storage.destroy_collection("home").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::CollectionNotEmpty);
+        storage
+            .delete_item(&home, &added.href, &added.etag)
+            .await
+            .unwrap();
+        storage.destroy_collection("home").await.unwrap();
+        assert!(!dir.path().join("home.vcf").exists());
+    }
+}
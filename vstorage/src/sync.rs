@@ -9,11 +9,16 @@
 //! - Create a [`StoragePair`] instance, which has the state saved from the previous sync and the
 //!   two storages that are to be synchronised.
 //! - Create a [`Plan`][plan::Plan] which contains a list of actions to be executed to sync both
-//!   storages. A dry-run should be able to print the plan, although right now the only way to
-//!   inspect it is via `dbg!()`.
+//!   storages. The plan can be inspected (e.g.: for a dry-run) via
+//!   [`Plan::operations`][plan::Plan::operations], or rendered via its `Display` or `Serialize`
+//!   implementations.
 //! - Run [`Plan::execute`][plan::Plan::execute]. This returns two opaque states that should be
 //!   serialised and used as input for the next synchronisation (mostly, this helps understand when
-//!   an item has change on one side vs where there is a conflict).
+//!   an item has change on one side vs where there is a conflict). A [`StatusStore`] can be used to
+//!   persist these states.
+//!
+//! Plans refuse to delete a large share of the items in a collection unless explicitly allowed;
+//! see [`DeletionGuard`][plan::DeletionGuard].
 //!
 //! The synchronization algorithm is based on [the algorithm from the original
 //! vdirsyncer][original-algo].
@@ -21,7 +26,10 @@
 //! [original-algo]: https://unterwaditzer.net/2016/sync-algorithm.html
 mod pair;
 pub mod plan;
+mod status;
 
 pub use pair::CollectionMapping;
 pub use pair::StoragePair;
 pub use pair::StorageState;
+pub use status::Side;
+pub use status::StatusStore;
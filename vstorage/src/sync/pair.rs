@@ -2,10 +2,13 @@
 //
 // SPDX-License-Identifier: EUPL-1.2
 
+use std::collections::{BTreeMap, BTreeSet};
+
+use log::debug;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    base::{Collection, Item, Storage},
+    base::{Collection, Item, ItemRef, Storage},
     Etag,
 };
 
@@ -95,6 +98,64 @@ impl<I: Item> StoragePair<'_, I> {
             current_state_b,
         })
     }
+
+    /// Assigns new UIDs to items which share their UID with another item in the same collection.
+    ///
+    /// Items with duplicate UIDs cannot be synchronised, and are otherwise reported as errors when
+    /// executing a plan. For each duplicate UID, the item with the lowest `href` keeps its UID, and
+    /// the others are updated with a new (deterministic) UID via [`Item::with_uid`].
+    ///
+    /// Returns the amount of items that were updated. This should be called before planning.
+    ///
+    /// # Errors
+    ///
+    /// If there are any errors reading or updating items. Items updated before an error occurred
+    /// remain updated.
+    pub async fn repair_duplicate_uids(&mut self) -> crate::Result<usize> {
+        let repaired_a = repair_duplicate_uids(self.storage_a, &mut self.current_state_a).await?;
+        let repaired_b = repair_duplicate_uids(self.storage_b, &mut self.current_state_b).await?;
+        Ok(repaired_a + repaired_b)
+    }
+}
+
+async fn repair_duplicate_uids<I: Item>(
+    storage: &mut dyn Storage<I>,
+    state: &mut StorageState,
+) -> crate::Result<usize> {
+    let mut repaired = 0;
+    for collection_state in &mut state.collections {
+        let collection = storage.open_collection(&collection_state.collection_href)?;
+        let duplicates = collection_state
+            .duplicate_uids()
+            .into_iter()
+            .map(|(uid, hrefs)| {
+                (
+                    uid.to_string(),
+                    hrefs.into_iter().map(String::from).collect(),
+                )
+            })
+            .collect::<Vec<(String, Vec<String>)>>();
+
+        for (uid, hrefs) in duplicates {
+            for href in hrefs.iter().skip(1) {
+                let item_state = collection_state
+                    .items
+                    .iter_mut()
+                    .find(|i| i.href == *href)
+                    .expect("duplicates are taken from this state");
+                let (item, _) = storage.get_item(&collection, href).await?;
+                let new_uid = crate::util::digest(format!("{uid}\r\n{href}"));
+                let item = item.with_uid(&new_uid);
+                item_state.etag = storage
+                    .update_item(&collection, href, &item_state.etag, &item)
+                    .await?;
+                item_state.uid = new_uid;
+                item_state.hash = item.hash();
+                repaired += 1;
+            }
+        }
+    }
+    Ok(repaired)
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
@@ -110,7 +171,7 @@ pub(crate) struct ItemState {
 /// Generally, this should be treated as opaque data and not modified by consumers of this library.
 /// It should, however, be serialised and saved into persistent storages between synchronisation
 /// operations.
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
 pub struct StorageState {
     collections: Vec<CollectionState>,
 }
@@ -158,7 +219,7 @@ impl StorageState {
                 }
             }) else {
                 // If a collection does not exist the there is no state for it.
-                continue
+                continue;
             };
 
             let previous = previous_state.find_collection_state(name);
@@ -183,6 +244,8 @@ impl StorageState {
                 collection_href: href,
                 collection_name: name,
                 items: Vec::new(),
+                sync_token: None,
+                tag: None,
             }
         });
     }
@@ -190,15 +253,50 @@ impl StorageState {
     pub(crate) fn remove_collection(&mut self, name: &str) {
         self.collections.retain(|c| c.collection_name != name);
     }
+
+    /// Replaces the state of a collection with its state in `other`.
+    ///
+    /// If the collection is not present in `other`, it is removed.
+    pub(crate) fn restore_collection(&mut self, name: &str, other: &StorageState) {
+        self.remove_collection(name);
+        if let Some(state) = other.find_collection_state(name) {
+            self.collections.push(state.clone());
+        }
+    }
+
+    /// Replaces the state of an item with its state in `other`.
+    ///
+    /// If the item is not present in `other`, it is removed. The collection's sync token and tag
+    /// are discarded, since they no longer reflect the state of its items.
+    pub(crate) fn restore_item(&mut self, collection: &str, uid: &str, other: &StorageState) {
+        let Some(state) = self.find_collection_state_mut(collection) else {
+            return;
+        };
+        state.items.retain(|item| item.uid != uid);
+        if let Some(previous) = other.find_collection_state(collection) {
+            let items = previous.items.iter().filter(|item| item.uid == uid);
+            state.items.extend(items.cloned());
+        }
+        state.sync_token = None;
+        state.tag = None;
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub(crate) struct CollectionState {
     // TODO: keep etag (to delete when empty).
     pub(crate) collection_href: String, // TODO: reference?
     pub(crate) collection_name: String, // TODO: reference?
     // TODO: keep the collection instance itself?
     pub(crate) items: Vec<ItemState>,
+    /// Token to list only items that changed since this state was determined.
+    ///
+    /// See [`Storage::changed_items`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sync_token: Option<String>,
+    /// Tag of the collection when this state was determined. See [`Collection::tag`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) tag: Option<String>,
 }
 
 impl CollectionState {
@@ -208,17 +306,35 @@ impl CollectionState {
         collection: &Collection,
         collection_name: String,
     ) -> crate::Result<Self> {
+        if let Some(previous) = previous_state {
+            if previous.tag.is_some() && previous.tag.as_deref() == collection.tag() {
+                // The collection has not changed; no need to list its items.
+                return Ok(CollectionState {
+                    collection_name,
+                    collection_href: collection.href().to_string(),
+                    ..previous.clone()
+                });
+            }
+        }
+
         let mut state = CollectionState {
             collection_name,
             // TODO: to_string here was a quick hack
             collection_href: collection.href().to_string(),
             items: Vec::new(),
+            sync_token: None,
+            tag: collection.tag().map(String::from),
         };
         let mut prefetch = Vec::new();
 
         // TODO: I could special case if previous_state is None and just get_all
 
-        for item_ref in storage.list_items(collection).await? {
+        let (mut item_refs, sync_token) = list_items(previous_state, storage, collection).await?;
+        if let Some(previous) = previous_state {
+            add_unlisted_items(previous, storage, collection, &mut item_refs).await?;
+        }
+        state.sync_token = sync_token;
+        for item_ref in item_refs {
             if let Some(ps) = previous_state {
                 if let Some(p) = ps.get_item_by_href(&item_ref.href) {
                     if p.etag == item_ref.etag {
@@ -246,9 +362,18 @@ impl CollectionState {
             )
             .await?;
         for (href, item, etag) in prefetched {
+            let uid = match item.uid() {
+                Some(uid) => uid,
+                // Items without a UID keep the identity that they had at the same location. If
+                // the hash were used, editing such an item would look like deleting it and
+                // creating a new one.
+                None => previous_state
+                    .and_then(|ps| ps.get_item_by_href(&href))
+                    .map_or_else(|| item.hash(), |p| p.uid.clone()),
+            };
             state.items.push(ItemState {
                 href,
-                uid: item.ident(),
+                uid,
                 etag,
                 hash: item.hash(),
             });
@@ -271,6 +396,98 @@ impl CollectionState {
     pub(crate) fn get_item_by_href(&self, href: &str) -> Option<&ItemState> {
         self.items.iter().find(|i| i.href == *href)
     }
+
+    /// Returns UIDs shared by more than one item, along with the (sorted) `href` of those items.
+    pub(crate) fn duplicate_uids(&self) -> BTreeMap<&str, Vec<&str>> {
+        let mut hrefs_by_uid = BTreeMap::<&str, Vec<&str>>::new();
+        for item in &self.items {
+            hrefs_by_uid.entry(&item.uid).or_default().push(&item.href);
+        }
+        hrefs_by_uid.retain(|_, hrefs| hrefs.len() > 1);
+        for hrefs in hrefs_by_uid.values_mut() {
+            hrefs.sort_unstable();
+        }
+        hrefs_by_uid
+    }
+}
+
+/// Enumerates all items in a collection, along with a token to list changes in future.
+///
+/// If the previous state has a token, only changed items are requested from the storage, and
+/// all other items are assumed to be unchanged. Otherwise, or if the storage fails to list
+/// changes (e.g.: due to an expired token), all items are listed.
+async fn list_items<I: Item>(
+    previous_state: Option<&CollectionState>,
+    storage: &dyn Storage<I>,
+    collection: &Collection,
+) -> crate::Result<(Vec<ItemRef>, Option<String>)> {
+    if let Some(previous) = previous_state {
+        if let Some(token) = &previous.sync_token {
+            match storage.changed_items(collection, Some(token)).await {
+                Ok(changes) => {
+                    let unchanged = previous
+                        .items
+                        .iter()
+                        .filter(|i| !changes.deleted.contains(&i.href))
+                        .filter(|i| !changes.changed.iter().any(|c| c.href == i.href))
+                        .map(|i| ItemRef {
+                            href: i.href.clone(),
+                            etag: i.etag.clone(),
+                        })
+                        .collect::<Vec<_>>();
+                    let mut item_refs = changes.changed;
+                    item_refs.extend(unchanged);
+                    return Ok((item_refs, Some(changes.sync_token)));
+                }
+                Err(err) => debug!("Listing changes failed, listing all items: {err}"),
+            }
+        }
+    }
+
+    match storage.changed_items(collection, None).await {
+        Ok(changes) => Ok((changes.changed, Some(changes.sync_token))),
+        Err(_) => Ok((storage.list_items(collection).await?, None)),
+    }
+}
+
+/// Adds items from the previous state which the storage omitted from its listing on purpose.
+///
+/// These items are added with their previous etag, so they are considered unchanged rather than
+/// deleted. See [`Storage::unlisted_items`].
+async fn add_unlisted_items<I: Item>(
+    previous: &CollectionState,
+    storage: &dyn Storage<I>,
+    collection: &Collection,
+    item_refs: &mut Vec<ItemRef>,
+) -> crate::Result<()> {
+    let listed = item_refs
+        .iter()
+        .map(|r| r.href.as_str())
+        .collect::<BTreeSet<_>>();
+    let missing = previous
+        .items
+        .iter()
+        .filter(|i| !listed.contains(i.href.as_str()))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let unlisted = storage.unlisted_items(collection).await?;
+    let unlisted = unlisted
+        .iter()
+        .map(|r| r.href.as_str())
+        .collect::<BTreeSet<_>>();
+    let kept = missing
+        .into_iter()
+        .filter(|i| unlisted.contains(i.href.as_str()))
+        .map(|i| ItemRef {
+            href: i.href.clone(),
+            etag: i.etag.clone(),
+        })
+        .collect::<Vec<_>>();
+    item_refs.extend(kept);
+    Ok(())
 }
 
 /// A transition that has occurred to a pair of items or collections.
@@ -6,10 +6,16 @@
 
 use crate::base::{Item, Storage};
 use crate::sync::pair::{Change, CollectionState, StoragePair, StorageState};
+use crate::util::{content_lines, split_line, unfold};
+use crate::{Error, ErrorKind};
 use itertools::Itertools;
 use log::trace;
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::fmt::Display;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::AsyncWriteExt;
 
 use super::pair::{CollectionMapping, ItemState};
 
@@ -39,6 +45,12 @@ impl SynchronizationError {
     pub fn resource(&self) -> &SyncResource {
         &self.resource
     }
+
+    /// The kind of the underlying error, if it originated in this crate.
+    #[must_use]
+    pub fn kind(&self) -> Option<ErrorKind> {
+        self.error.downcast_ref::<Error>().map(Error::kind)
+    }
 }
 
 impl Display for SynchronizationError {
@@ -59,15 +71,128 @@ impl std::error::Error for SynchronizationError {
 }
 
 /// An action to executing when synchronising.
-#[derive(PartialEq, Debug, Clone)]
+#[derive(PartialEq, Debug, Clone, Serialize)]
 pub enum Action {
-    // TODO: keep href of items that need to be acted upon?
     NoOp,
     CopyToA,
     CopyToB,
     DeleteInA,
     DeleteInB,
-    Conflict, // TODO: content might still match on both sides
+    /// The item has been modified on both sides.
+    ///
+    /// Resolved according to the plan's [`ConflictResolution`].
+    Conflict,
+}
+
+/// Strategy used to resolve conflicts.
+///
+/// A conflict occurs when an item has been modified in both storages since the last
+/// synchronisation. If both versions are identical, no conflict is reported at all.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ConflictResolution {
+    /// Leave both versions untouched and report a [`SynchronizationError`] for the item.
+    #[default]
+    Abort,
+    /// Keep the version from `storage_a`, overwriting the one in `storage_b`.
+    KeepA,
+    /// Keep the version from `storage_b`, overwriting the one in `storage_a`.
+    KeepB,
+    /// Keep the most recently modified version.
+    ///
+    /// Items are compared using their `LAST-MODIFIED` (for icalendar) or `REV` (for vcard)
+    /// properties, which must be in UTC. If either item lacks these (or has a value in local time),
+    /// or both have the same value, the conflict is reported as with
+    /// [`ConflictResolution::Abort`].
+    Newest,
+    /// Run an external command to merge both versions.
+    ///
+    /// The first element is the command to run, and the rest are arguments. Two additional
+    /// arguments are appended: paths to files containing the version from each storage. The
+    /// command must edit these files so that both have the same content (e.g.: `vimdiff`). The
+    /// result is then saved into both storages.
+    Command(Vec<String>),
+}
+
+/// Safety check that prevents executing plans that would delete too many items.
+///
+/// If a storage briefly returns an empty listing (e.g.: due to a server glitch or an unmounted
+/// directory), all its items appear to have been deleted, and deleting them from the other storage
+/// is planned. When the deletions planned for a collection exceed these limits, its plan is not
+/// executed and a [`SynchronizationError`] of kind [`ErrorKind::TooManyDeletions`] is reported
+/// instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeletionGuard {
+    /// Maximum share of the items in a collection which may be deleted from one storage, from
+    /// `0.0` to `1.0`. `None` disables this check.
+    pub max_deletion_ratio: Option<f64>,
+    /// Minimum amount of items that a collection must have had for
+    /// [`max_deletion_ratio`](Self::max_deletion_ratio) to apply.
+    ///
+    /// In small collections, routine deletions easily exceed any ratio (e.g.: deleting one of two
+    /// items).
+    pub min_items: usize,
+    /// Abort if a collection that previously had items is now empty in one storage and that
+    /// would result in deleting items from the other storage.
+    pub abort_when_emptied: bool,
+}
+
+impl Default for DeletionGuard {
+    fn default() -> Self {
+        DeletionGuard {
+            max_deletion_ratio: Some(0.5),
+            min_items: 10,
+            abort_when_emptied: true,
+        }
+    }
+}
+
+impl DeletionGuard {
+    /// A guard which allows any amount of deletions.
+    ///
+    /// Use this to explicitly proceed with a synchronisation that was previously aborted.
+    #[must_use]
+    pub fn disabled() -> Self {
+        DeletionGuard {
+            max_deletion_ratio: None,
+            min_items: 0,
+            abort_when_emptied: false,
+        }
+    }
+
+    /// Checks the deletions planned on one side of a collection.
+    ///
+    /// `previous` is the amount of items in the side where deletions would happen, and
+    /// `other_previous` and `other_current` are the amounts of items on the other side.
+    fn check(
+        &self,
+        deletions: usize,
+        previous: usize,
+        other_previous: usize,
+        other_current: usize,
+    ) -> Result<(), String> {
+        if deletions == 0 {
+            return Ok(());
+        }
+        if self.abort_when_emptied && other_previous > 0 && other_current == 0 {
+            return Err(format!(
+                "collection is now empty on the other side; {deletions} item(s) would be deleted"
+            ));
+        }
+        if let Some(max) = self
+            .max_deletion_ratio
+            .filter(|_| previous >= self.min_items)
+        {
+            #[allow(clippy::cast_precision_loss)] // Item counts are never this large.
+            let ratio = deletions as f64 / previous.max(1) as f64;
+            if ratio > max {
+                return Err(format!(
+                    "{deletions} of {previous} item(s) would be deleted, exceeding the limit of {:.0}%",
+                    max * 100.0
+                ));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Action {
@@ -95,6 +220,7 @@ impl Action {
         storage_b: &mut dyn Storage<I>,
         state_a: Option<&mut CollectionState>,
         state_b: Option<&mut CollectionState>,
+        conflict_resolution: &ConflictResolution,
     ) -> Result<(), Box<dyn std::error::Error>> {
         match self {
             Action::NoOp => {}
@@ -134,13 +260,195 @@ impl Action {
                 )
                 .await?;
             }
-            Action::Conflict => todo!("conflict resolution"),
+            Action::Conflict => {
+                resolve_conflict(
+                    conflict_resolution,
+                    state_a.ok_or("state a is missing")?,
+                    state_b.ok_or("state b is missing")?,
+                    storage_a,
+                    storage_b,
+                    uid,
+                )
+                .await?;
+            }
         }
 
         Ok(())
     }
 }
 
+async fn resolve_conflict<I: Item>(
+    resolution: &ConflictResolution,
+    state_a: &mut CollectionState,
+    state_b: &mut CollectionState,
+    storage_a: &mut dyn Storage<I>,
+    storage_b: &mut dyn Storage<I>,
+    uid: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match resolution {
+        ConflictResolution::Abort => Err("item was modified in both storages".into()),
+        ConflictResolution::KeepA => copy_item(state_a, state_b, storage_a, storage_b, uid).await,
+        ConflictResolution::KeepB => copy_item(state_b, state_a, storage_b, storage_a, uid).await,
+        ConflictResolution::Newest => {
+            let item_a = fetch_item(state_a, storage_a, uid).await?;
+            let item_b = fetch_item(state_b, storage_b, uid).await?;
+            match (
+                last_modified(item_a.as_str()),
+                last_modified(item_b.as_str()),
+            ) {
+                (Some(a), Some(b)) if a > b => write_item(state_b, storage_b, uid, &item_a).await,
+                (Some(a), Some(b)) if a < b => write_item(state_a, storage_a, uid, &item_b).await,
+                _ => Err("item was modified in both storages and cannot determine newest".into()),
+            }
+        }
+        ConflictResolution::Command(command) => {
+            let item_a = fetch_item(state_a, storage_a, uid).await?;
+            let item_b = fetch_item(state_b, storage_b, uid).await?;
+            let merged = merge_with_command(command, &item_a, &item_b).await?;
+            if merged.hash() != item_a.hash() {
+                write_item(state_a, storage_a, uid, &merged).await?;
+            }
+            if merged.hash() != item_b.hash() {
+                write_item(state_b, storage_b, uid, &merged).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Returns the most recent `LAST-MODIFIED` or `REV` value in a raw item.
+///
+/// Only properties of events, todos, journals and vcards are considered (e.g.: the
+/// `LAST-MODIFIED` of a `VTIMEZONE` is ignored). Values are normalised into the basic format
+/// (e.g.: `20230102T030405Z`), so that two values can be compared as strings.
+///
+/// Returns `None` if any value is not a UTC date-time, since those cannot be compared reliably.
+fn last_modified(raw: &str) -> Option<String> {
+    let mut stack = Vec::new();
+    let mut latest = None;
+    for line in content_lines(raw) {
+        let line = unfold(line);
+        let Some((name, params, value)) = split_line(&line) else {
+            continue;
+        };
+        if name.eq_ignore_ascii_case("BEGIN") {
+            stack.push(value.to_ascii_uppercase());
+            continue;
+        }
+        if name.eq_ignore_ascii_case("END") {
+            stack.pop();
+            continue;
+        }
+        let in_item = matches!(
+            stack.last().map(String::as_str),
+            Some("VEVENT" | "VTODO" | "VJOURNAL" | "VCARD")
+        );
+        if !in_item
+            || !(name.eq_ignore_ascii_case("LAST-MODIFIED") || name.eq_ignore_ascii_case("REV"))
+        {
+            continue;
+        }
+
+        let value = value
+            .chars()
+            .filter(|c| *c != '-' && *c != ':')
+            .collect::<String>();
+        if params.to_ascii_uppercase().contains("TZID=") || !is_utc_date_time(&value) {
+            return None;
+        }
+        if latest.as_ref() < Some(&value) {
+            latest = Some(value);
+        }
+    }
+    latest
+}
+
+/// Returns true if `value` is a UTC date-time in the basic format (e.g.: `20230102T030405Z`).
+fn is_utc_date_time(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    bytes.len() == 16
+        && bytes[8] == b'T'
+        && bytes[15] == b'Z'
+        && bytes[..8].iter().all(u8::is_ascii_digit)
+        && bytes[9..15].iter().all(u8::is_ascii_digit)
+}
+
+/// Counter used to generate unique file names for conflicting items.
+static MERGE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Merge two conflicting items using an external command.
+///
+/// See [`ConflictResolution::Command`].
+async fn merge_with_command<I: Item>(
+    command: &[String],
+    item_a: &I,
+    item_b: &I,
+) -> Result<I, Box<dyn std::error::Error>> {
+    let (program, args) = command
+        .split_first()
+        .ok_or("conflict resolution command is empty")?;
+
+    let prefix = format!(
+        "vdirsyncer-conflict-{}-{}",
+        std::process::id(),
+        MERGE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let path_a = std::env::temp_dir().join(format!("{prefix}-a"));
+    let path_b = std::env::temp_dir().join(format!("{prefix}-b"));
+
+    let result = async {
+        write_new_file(&path_a, item_a.as_str()).await?;
+        write_new_file(&path_b, item_b.as_str()).await?;
+
+        let status = tokio::process::Command::new(program)
+            .args(args)
+            .arg(&path_a)
+            .arg(&path_b)
+            .status()
+            .await?;
+        if !status.success() {
+            return Err(format!("conflict resolution command failed: {status}").into());
+        }
+
+        let merged_a = I::from(tokio::fs::read_to_string(&path_a).await?);
+        let merged_b = I::from(tokio::fs::read_to_string(&path_b).await?);
+        if merged_a.hash() != merged_b.hash() {
+            return Err("conflict resolution command did not produce identical items".into());
+        }
+        Ok(merged_a)
+    }
+    .await;
+
+    // Files may not exist if writing failed.
+    tokio::fs::remove_file(&path_a).await.ok();
+    tokio::fs::remove_file(&path_b).await.ok();
+
+    result
+}
+
+/// Writes a file, failing if it already exists.
+async fn write_new_file(path: &Path, data: &str) -> std::io::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .await?;
+    file.write_all(data.as_bytes()).await
+}
+
+async fn fetch_item<I: Item>(
+    state: &CollectionState,
+    storage: &dyn Storage<I>,
+    uid: &str,
+) -> Result<I, Box<dyn std::error::Error>> {
+    let collection = storage.open_collection(&state.collection_href)?;
+
+    let item_state = state.get_item_by_uid(uid).ok_or("item is missing")?;
+    let (item, _) = storage.get_item(&collection, &item_state.href).await?;
+
+    Ok(item)
+}
+
 async fn copy_item<I: Item>(
     src_state: &CollectionState,
     dst_state: &mut CollectionState,
@@ -148,23 +456,29 @@ async fn copy_item<I: Item>(
     dst_storage: &mut dyn Storage<I>,
     uid: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let col_a = src_storage.open_collection(&src_state.collection_href)?;
-
-    let item_state = src_state.get_item_by_uid(uid).ok_or("item is missing")?;
-    let (item, _) = src_storage.get_item(&col_a, &item_state.href).await?;
+    let item = fetch_item(src_state, src_storage, uid).await?;
+    write_item(dst_state, dst_storage, uid, &item).await
+}
 
+/// Writes an item into a collection, updating the existing one with the same UID (if any).
+async fn write_item<I: Item>(
+    dst_state: &mut CollectionState,
+    dst_storage: &mut dyn Storage<I>,
+    uid: &str,
+    item: &I,
+) -> Result<(), Box<dyn std::error::Error>> {
     let col = dst_storage.open_collection(&dst_state.collection_href)?;
 
     if let Some(dst_item_state) = dst_state.get_item_by_uid_mut(uid) {
         trace!("Updating {uid}");
         let new_etag = dst_storage
-            .update_item(&col, &dst_item_state.href, &dst_item_state.etag, &item)
+            .update_item(&col, &dst_item_state.href, &dst_item_state.etag, item)
             .await?;
         dst_item_state.etag = new_etag;
         dst_item_state.hash = item.hash();
     } else {
         trace!("Creating {uid}");
-        let new_ref = dst_storage.add_item(&col, &item).await?;
+        let new_ref = dst_storage.add_item(&col, item).await?;
         dst_state.items.push(ItemState {
             href: new_ref.href,
             uid: uid.to_string(),
@@ -202,9 +516,50 @@ async fn delete_item<I: Item>(
 #[derive(Debug)]
 pub struct Plan {
     collection_plans: Vec<CollectionPlan>,
+    conflict_resolution: ConflictResolution,
+    deletion_guard: DeletionGuard,
 }
 
 impl Plan {
+    /// Returns the operations that will be executed by this plan.
+    ///
+    /// Items and collections which require no changes are omitted. Operations are returned in the
+    /// same order in which they would be executed.
+    pub fn operations(&self) -> impl Iterator<Item = PlannedOperation<'_>> {
+        self.collection_plans.iter().flat_map(|cp| {
+            let collection_operation = (cp.collection_action != Action::NoOp).then(|| {
+                PlannedOperation::new(
+                    cp.mapping.name(),
+                    None,
+                    &cp.collection_action,
+                    cp.href_a.as_deref(),
+                    cp.href_b.as_deref(),
+                )
+            });
+            // Collections are created before copying items into them, and deleted after deleting
+            // the items inside them.
+            let (before, after) = match cp.collection_action {
+                Action::DeleteInA | Action::DeleteInB => (None, collection_operation),
+                _ => (collection_operation, None),
+            };
+            let items = cp
+                .item_actions
+                .iter()
+                .filter(|(_, item)| item.action != Action::NoOp)
+                .map(|(uid, item)| {
+                    PlannedOperation::new(
+                        cp.mapping.name(),
+                        Some(uid),
+                        &item.action,
+                        item.href_a.as_deref(),
+                        item.href_b.as_deref(),
+                    )
+                });
+
+            before.into_iter().chain(items).chain(after)
+        })
+    }
+
     /// Create a plan to synchronise both storages.
     ///
     /// Compares the previous and current state of both storages and calculate all actions required
@@ -234,7 +589,29 @@ impl Plan {
             collection_plans.push(plan);
         }
 
-        Plan { collection_plans }
+        Plan {
+            collection_plans,
+            conflict_resolution: ConflictResolution::default(),
+            deletion_guard: DeletionGuard::default(),
+        }
+    }
+
+    /// Set the strategy used to resolve conflicts when executing this plan.
+    ///
+    /// Defaults to [`ConflictResolution::Abort`].
+    #[must_use]
+    pub fn with_conflict_resolution(mut self, conflict_resolution: ConflictResolution) -> Plan {
+        self.conflict_resolution = conflict_resolution;
+        self
+    }
+
+    /// Set the limits used to prevent mass deletions when executing this plan.
+    ///
+    /// Defaults to [`DeletionGuard::default`].
+    #[must_use]
+    pub fn with_deletion_guard(mut self, deletion_guard: DeletionGuard) -> Plan {
+        self.deletion_guard = deletion_guard;
+        self
     }
 
     /// Executes a synchronization plan.
@@ -254,6 +631,32 @@ impl Plan {
         let storage_b = &mut pair.storage_b;
 
         for cp in &self.collection_plans {
+            for (uid, (action, description)) in &cp.duplicate_uids {
+                // Keep the previous state so that changes are synchronised once this is fixed.
+                final_state
+                    .state_a
+                    .restore_item(cp.mapping.name_a(), uid, pair.previous_state_a);
+                final_state
+                    .state_b
+                    .restore_item(cp.mapping.name_b(), uid, pair.previous_state_b);
+                final_state.errors.push(SynchronizationError {
+                    action: action.clone(),
+                    resource: SyncResource::Item { uid: uid.clone() },
+                    error: Box::new(Error::new(ErrorKind::DuplicateUid, description.clone())),
+                });
+            }
+            if let Err(err) = cp.check_deletions(&self.deletion_guard) {
+                // Keep the previous state so that the same deletions are detected next time.
+                final_state
+                    .state_a
+                    .restore_collection(cp.mapping.name_a(), pair.previous_state_a);
+                final_state
+                    .state_b
+                    .restore_collection(cp.mapping.name_b(), pair.previous_state_b);
+                final_state.errors.push(err);
+                continue;
+            }
+
             let mut delete_collection_in_a = false;
             let mut delete_collection_in_b = false;
             match cp.collection_action {
@@ -295,7 +698,7 @@ impl Plan {
                 }
             }
 
-            for (uid, action) in &cp.item_actions {
+            for (uid, ItemPlan { action, .. }) in &cp.item_actions {
                 // FIXME: I need to somehow move these two calls outside of the "for" loop.
                 let state_a = final_state
                     .state_a
@@ -305,9 +708,27 @@ impl Plan {
                     .find_collection_state_mut(cp.mapping.name_b());
 
                 if let Err(err) = action
-                    .execute_on_item(uid, *storage_a, *storage_b, state_a, state_b)
+                    .execute_on_item(
+                        uid,
+                        *storage_a,
+                        *storage_b,
+                        state_a,
+                        state_b,
+                        &self.conflict_resolution,
+                    )
                     .await
                 {
+                    // Keep the previous state so that the same action is planned next time.
+                    final_state.state_a.restore_item(
+                        cp.mapping.name_a(),
+                        uid,
+                        pair.previous_state_a,
+                    );
+                    final_state.state_b.restore_item(
+                        cp.mapping.name_b(),
+                        uid,
+                        pair.previous_state_b,
+                    );
                     final_state.errors.push(SynchronizationError {
                         action: action.clone(),
                         resource: SyncResource::Item {
@@ -411,12 +832,123 @@ impl FinalState {
     }
 }
 
+/// Renders a plan with one operation per line.
+impl Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for operation in self.operations() {
+            writeln!(f, "{operation}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders a plan as a sequence of [`PlannedOperation`]s.
+impl Serialize for Plan {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.operations())
+    }
+}
+
+/// A single operation that will be executed as part of a [`Plan`].
+#[derive(Debug, Serialize)]
+pub struct PlannedOperation<'a> {
+    /// Name of the collection, as specified in its [`CollectionMapping`].
+    pub collection: &'a str,
+    /// UID of the affected item. `None` if the operation applies to the collection itself.
+    pub uid: Option<&'a str>,
+    /// The action that will be executed.
+    pub action: &'a Action,
+    /// The `href` of the item or collection being copied.
+    ///
+    /// For conflicts, this is the `href` in `storage_a`.
+    pub source: Option<&'a str>,
+    /// The `href` of the item or collection that will be overwritten or deleted.
+    ///
+    /// `None` if a new item or collection will be created. For conflicts, this is the `href` in
+    /// `storage_b`.
+    pub destination: Option<&'a str>,
+}
+
+impl<'a> PlannedOperation<'a> {
+    fn new(
+        collection: &'a str,
+        uid: Option<&'a str>,
+        action: &'a Action,
+        href_a: Option<&'a str>,
+        href_b: Option<&'a str>,
+    ) -> Self {
+        let (source, destination) = match action {
+            Action::NoOp => (None, None),
+            Action::CopyToA => (href_b, href_a),
+            Action::CopyToB | Action::Conflict => (href_a, href_b),
+            Action::DeleteInA => (None, href_a),
+            Action::DeleteInB => (None, href_b),
+        };
+        PlannedOperation {
+            collection,
+            uid,
+            action,
+            source,
+            destination,
+        }
+    }
+}
+
+impl Display for PlannedOperation<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.uid {
+            Some(uid) => write!(f, "{}: {uid}: ", self.collection)?,
+            None => write!(f, "{}: ", self.collection)?,
+        };
+        let target = if self.uid.is_some() {
+            ""
+        } else {
+            " collection"
+        };
+        let source = self.source.unwrap_or("?");
+        match (self.action, self.destination) {
+            (Action::NoOp, _) => f.write_str("no changes"),
+            (Action::CopyToA, None) => write!(f, "create{target} in A (from {source})"),
+            (Action::CopyToB, None) => write!(f, "create{target} in B (from {source})"),
+            (Action::CopyToA, Some(dst)) => write!(f, "update{target} in A ({source} -> {dst})"),
+            (Action::CopyToB, Some(dst)) => write!(f, "update{target} in B ({source} -> {dst})"),
+            (Action::DeleteInA, dst) => write!(f, "delete{target} in A ({})", dst.unwrap_or("?")),
+            (Action::DeleteInB, dst) => write!(f, "delete{target} in B ({})", dst.unwrap_or("?")),
+            (Action::Conflict, dst) => {
+                write!(f, "resolve conflict ({source} <-> {})", dst.unwrap_or("?"))
+            }
+        }
+    }
+}
+
 /// A set of actions required to sync a collection between two storages.
 #[derive(Debug)]
 pub(crate) struct CollectionPlan {
     mapping: CollectionMapping,
     collection_action: Action,
-    item_actions: HashMap<String, Action>,
+    /// The `href` of the collection in `storage_a`, if it exists.
+    href_a: Option<String>,
+    /// The `href` of the collection in `storage_b`, if it exists.
+    href_b: Option<String>,
+    item_actions: BTreeMap<String, ItemPlan>,
+    /// Items that cannot be synchronised because their UID is not unique within a collection.
+    ///
+    /// Maps the UID to the action that would have been executed and a description of the items.
+    duplicate_uids: BTreeMap<String, (Action, String)>,
+    /// Amount of items in `storage_a` (previously, currently).
+    item_count_a: (usize, usize),
+    /// Amount of items in `storage_b` (previously, currently).
+    item_count_b: (usize, usize),
+}
+
+/// The action required to sync a single item between two storages.
+#[derive(Debug)]
+struct ItemPlan {
+    action: Action,
+    /// The `href` of the item in `storage_a`, if it exists.
+    href_a: Option<String>,
+    /// The `href` of the item in `storage_b`, if it exists.
+    href_b: Option<String>,
 }
 
 impl CollectionPlan {
@@ -450,29 +982,203 @@ impl CollectionPlan {
             all_items.extend(&s.items);
         }
 
-        let item_actions = all_items
-            .iter()
-            .map(|i| &i.uid)
-            .unique()
-            .map(|uid| {
-                let a_changed = Change::for_item(current_state_a, previous_state_a, uid);
-                let b_changed = Change::for_item(current_state_b, previous_state_b, uid);
+        let duplicates_a = current_state_a
+            .map(CollectionState::duplicate_uids)
+            .unwrap_or_default();
+        let duplicates_b = current_state_b
+            .map(CollectionState::duplicate_uids)
+            .unwrap_or_default();
+
+        let mut item_actions = BTreeMap::new();
+        let mut duplicate_uids = BTreeMap::new();
+        for uid in all_items.iter().map(|i| &i.uid).unique() {
+            let a_changed = Change::for_item(current_state_a, previous_state_a, uid);
+            let b_changed = Change::for_item(current_state_b, previous_state_b, uid);
 
-                let action = Action::from_changes(a_changed, b_changed);
-                trace!("For item {uid}, changes: {a_changed:?}, {b_changed:?}, action: {action:?}");
-                (uid.clone(), action)
-            })
-            .collect();
+            let mut action = Action::from_changes(a_changed, b_changed);
+            if action == Action::Conflict && same_content(current_state_a, current_state_b, uid) {
+                action = Action::NoOp;
+            }
+            trace!("For item {uid}, changes: {a_changed:?}, {b_changed:?}, action: {action:?}");
+
+            let duplicates = [
+                ("A", duplicates_a.get(uid.as_str())),
+                ("B", duplicates_b.get(uid.as_str())),
+            ];
+            let description = duplicates
+                .iter()
+                .filter_map(|(side, hrefs)| {
+                    hrefs.map(|hrefs| {
+                        format!("{} items in {side} ({})", hrefs.len(), hrefs.join(", "))
+                    })
+                })
+                .join("; ");
+            if !description.is_empty() {
+                duplicate_uids.insert(uid.clone(), (action, description));
+                continue;
+            }
 
-        let collection_action = Action::from_changes(
+            let item = ItemPlan {
+                action,
+                href_a: current_href(current_state_a, uid),
+                href_b: current_href(current_state_b, uid),
+            };
+            item_actions.insert(uid.clone(), item);
+        }
+
+        let mut collection_action = Action::from_changes(
             Change::for_collection(current_state_a, previous_state_a),
             Change::for_collection(current_state_b, previous_state_b),
         );
+        if collection_action == Action::Conflict {
+            // The collection was created on both sides; there's nothing to reconcile.
+            collection_action = Action::NoOp;
+        }
 
         CollectionPlan {
             mapping,
             collection_action,
+            href_a: current_state_a.map(|s| s.collection_href.clone()),
+            href_b: current_state_b.map(|s| s.collection_href.clone()),
             item_actions,
+            duplicate_uids,
+            item_count_a: (item_count(previous_state_a), item_count(current_state_a)),
+            item_count_b: (item_count(previous_state_b), item_count(current_state_b)),
+        }
+    }
+
+    /// Checks that this plan does not delete more items than allowed by `guard`.
+    fn check_deletions(&self, guard: &DeletionGuard) -> Result<(), SynchronizationError> {
+        let count = |action: Action| {
+            self.item_actions
+                .values()
+                .filter(|item| item.action == action)
+                .count()
+        };
+        let (prev_a, cur_a) = self.item_count_a;
+        let (prev_b, cur_b) = self.item_count_b;
+
+        let (action, result) = match guard.check(count(Action::DeleteInA), prev_a, prev_b, cur_b) {
+            Err(msg) => (Action::DeleteInA, Err(msg)),
+            Ok(()) => (
+                Action::DeleteInB,
+                guard.check(count(Action::DeleteInB), prev_b, prev_a, cur_a),
+            ),
+        };
+        result.map_err(|msg| SynchronizationError {
+            action,
+            resource: SyncResource::Collection {
+                name: self.mapping.name().to_string(),
+            },
+            error: Box::new(Error::new(ErrorKind::TooManyDeletions, msg)),
+        })
+    }
+}
+
+fn item_count(state: Option<&CollectionState>) -> usize {
+    state.map_or(0, |s| s.items.len())
+}
+
+/// Returns the current `href` for the item with the given `uid`, if any.
+fn current_href(state: Option<&CollectionState>, uid: &str) -> Option<String> {
+    state
+        .and_then(|s| s.get_item_by_uid(uid))
+        .map(|i| i.href.clone())
+}
+
+/// Returns true if the item with the given `uid` is present on both sides with the same content.
+fn same_content(
+    state_a: Option<&CollectionState>,
+    state_b: Option<&CollectionState>,
+    uid: &str,
+) -> bool {
+    let hash_a = state_a
+        .and_then(|s| s.get_item_by_uid(uid))
+        .map(|i| &i.hash);
+    let hash_b = state_b
+        .and_then(|s| s.get_item_by_uid(uid))
+        .map(|i| &i.hash);
+    hash_a.is_some() && hash_a == hash_b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{last_modified, DeletionGuard};
+
+    #[test]
+    fn test_deletion_guard_min_items() {
+        let guard = DeletionGuard::default();
+        // Small collections only abort when emptied.
+        assert!(guard.check(1, 2, 2, 1).is_ok());
+        assert!(guard.check(2, 3, 3, 1).is_ok());
+        assert!(guard.check(8, 9, 9, 1).is_ok());
+        assert!(guard.check(1, 1, 1, 0).is_err());
+        // The ratio applies from the minimum amount of items onwards.
+        assert!(guard.check(5, 10, 10, 5).is_ok());
+        assert!(guard.check(6, 10, 10, 4).is_err());
+
+        let guard = DeletionGuard {
+            min_items: 0,
+            ..DeletionGuard::default()
+        };
+        assert!(guard.check(2, 3, 3, 1).is_err());
+    }
+
+    #[test]
+    fn test_last_modified() {
+        let raw = [
+            "BEGIN:VCALENDAR",
+            "BEGIN:VEVENT",
+            "UID:test",
+            "LAST-MODIFIED:20230102T030405Z",
+            "END:VEVENT",
+            "BEGIN:VEVENT",
+            "UID:test",
+            "RECURRENCE-ID:20230110T100000Z",
+            "LAST-MODIFIED:20230105T030405Z",
+            "END:VEVENT",
+            "END:VCALENDAR",
+        ]
+        .join("\r\n");
+        assert_eq!(last_modified(&raw).as_deref(), Some("20230105T030405Z"));
+
+        let raw = [
+            "BEGIN:VCARD",
+            "UID:test",
+            "REV:2023-01-02T03:04:05Z",
+            "END:VCARD",
+        ]
+        .join("\r\n");
+        assert_eq!(last_modified(&raw).as_deref(), Some("20230102T030405Z"));
+
+        let raw = ["BEGIN:VCARD", "UID:test", "END:VCARD"].join("\r\n");
+        assert_eq!(last_modified(&raw), None);
+
+        // Folded lines are unfolded, and timezones are ignored.
+        let raw = [
+            "BEGIN:VCALENDAR",
+            "BEGIN:VTIMEZONE",
+            "TZID:Europe/Rome",
+            "LAST-MODIFIED:20240101T000000Z",
+            "END:VTIMEZONE",
+            "BEGIN:VEVENT",
+            "UID:test",
+            "LAST-MODIFIED:20230102",
+            " T030405Z",
+            "END:VEVENT",
+            "END:VCALENDAR",
+        ]
+        .join("\r\n");
+        assert_eq!(last_modified(&raw).as_deref(), Some("20230102T030405Z"));
+
+        // Values in local time cannot be compared.
+        for value in [
+            "LAST-MODIFIED:20230102T030405",
+            "LAST-MODIFIED;TZID=Europe/Rome:20230102T030405",
+            "REV:2023-01-02T03:04:05+02:00",
+        ] {
+            let raw = ["BEGIN:VCARD", "UID:test", value, "END:VCARD"].join("\r\n");
+            assert_eq!(last_modified(&raw), None, "{value}");
         }
     }
 }
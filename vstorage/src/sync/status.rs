@@ -0,0 +1,295 @@
+// Copyright 2023 Hugo Osvaldo Barrera
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+//! Persistent storage for the state of storages between synchronisations.
+//!
+//! Each [`StorageState`] is saved into its own file inside a status directory. Files are keyed by
+//! the name of the pair and the [`Side`] of the pair to which the storage belongs.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs::{create_dir_all, read, rename, File};
+
+use crate::filesystem::{discard, write_temp_file};
+use crate::sync::StorageState;
+use crate::{Error, ErrorKind, Result};
+
+/// Identifies files written by [`StatusStore`].
+const FORMAT: &str = "vdirsyncer-status";
+
+/// Version of the format written by this implementation.
+///
+/// Must be increased whenever the format of [`StorageState`] changes in a way that is not
+/// backwards compatible. Older versions should be migrated when loading.
+const VERSION: u32 = 1;
+
+/// One of the two storages in a [`StoragePair`](crate::sync::StoragePair).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    A,
+    B,
+}
+
+impl Side {
+    #[must_use]
+    const fn as_str(self) -> &'static str {
+        match self {
+            Side::A => "a",
+            Side::B => "b",
+        }
+    }
+}
+
+impl std::fmt::Display for Side {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Header present in all status files.
+#[derive(Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+    pair: String,
+    side: Side,
+}
+
+#[derive(Serialize)]
+struct StatusFileRef<'a> {
+    format: &'static str,
+    version: u32,
+    pair: &'a str,
+    side: Side,
+    state: &'a StorageState,
+}
+
+#[derive(Deserialize)]
+struct StatusFile {
+    state: StorageState,
+}
+
+/// A directory where the state of storages is saved between synchronisations.
+///
+/// Files are written atomically; a crash during a write never leaves a partially written status
+/// file behind.
+///
+/// Loading a file that is corrupt, was written by a newer version, or belongs to a different pair
+/// is an error. Treating such a file as an empty state would look like a first-time
+/// synchronisation and result in every item being duplicated.
+#[derive(Debug, Clone)]
+pub struct StatusStore {
+    path: PathBuf,
+}
+
+impl StatusStore {
+    /// Create a new instance for the given directory.
+    ///
+    /// The directory will be created when saving a state if it does not exist.
+    #[must_use]
+    pub fn new(path: PathBuf) -> Self {
+        StatusStore { path }
+    }
+
+    /// The directory where status files are stored.
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load the state for one side of a pair.
+    ///
+    /// Returns an empty state if none has been saved for this pair and side.
+    ///
+    /// # Errors
+    ///
+    /// - [`ErrorKind::InvalidInput`] if the name of the pair is not valid.
+    /// - [`ErrorKind::InvalidData`] if the file is corrupt, was written for another pair or by a
+    ///   newer (and unsupported) version.
+    /// - [`ErrorKind::Io`] for any other error reading the file.
+    pub async fn load(&self, pair: &str, side: Side) -> Result<StorageState> {
+        let path = self.file_path(pair, side)?;
+        let raw = match read(&path).await {
+            Ok(raw) => raw,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(StorageState::empty())
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let header = serde_json::from_slice::<Header>(&raw).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not a valid status file: {e}", path.display()),
+            )
+        })?;
+        if header.format != FORMAT {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not a status file", path.display()),
+            ));
+        }
+        if header.version > VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} has format version {}, but only up to {VERSION} is supported",
+                    path.display(),
+                    header.version,
+                ),
+            ));
+        }
+        if header.pair != pair || header.side != side {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} belongs to side {} of pair {}",
+                    path.display(),
+                    header.side,
+                    header.pair
+                ),
+            ));
+        }
+
+        // Migrations from older versions should happen here.
+        let file = serde_json::from_slice::<StatusFile>(&raw).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is corrupt: {e}", path.display()),
+            )
+        })?;
+
+        Ok(file.state)
+    }
+
+    /// Save the state for one side of a pair.
+    ///
+    /// The state is written into a temporary file which is then renamed into place. The temporary
+    /// file is removed if any step fails.
+    ///
+    /// # Errors
+    ///
+    /// - [`ErrorKind::InvalidInput`] if the name of the pair is not valid.
+    /// - [`ErrorKind::Io`] for any error writing the file.
+    pub async fn save(&self, pair: &str, side: Side, state: &StorageState) -> Result<()> {
+        let path = self.file_path(pair, side)?;
+        let raw = serde_json::to_vec(&StatusFileRef {
+            format: FORMAT,
+            version: VERSION,
+            pair,
+            side,
+            state,
+        })
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        create_dir_all(&self.path).await?;
+        let temp_path = write_temp_file(&self.path, &raw).await?;
+        if let Err(err) = rename(&temp_path, &path).await {
+            discard(&temp_path).await;
+            return Err(err.into());
+        }
+
+        // Persist the rename itself.
+        File::open(&self.path).await?.sync_all().await?;
+        Ok(())
+    }
+
+    fn file_path(&self, pair: &str, side: Side) -> Result<PathBuf> {
+        if pair.is_empty() || pair.starts_with('.') || pair.contains(std::path::is_separator) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid pair name: {pair}"),
+            ));
+        }
+        Ok(self.path.join(format!("{pair}.{side}.json")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sync::StorageState;
+    use crate::ErrorKind;
+
+    use super::{Side, StatusStore};
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StatusStore::new(dir.path().join("status"));
+
+        let mut state = StorageState::empty();
+        state.add_collection("work".into(), "/work/".into());
+
+        store.save("calendars", Side::A, &state).await.unwrap();
+        let loaded = store.load("calendars", Side::A).await.unwrap();
+        assert!(loaded.find_collection_state("work").is_some());
+
+        // Nothing has been saved for this side yet.
+        let loaded = store.load("calendars", Side::B).await.unwrap();
+        assert!(loaded.find_collection_state("work").is_none());
+
+        // No temporary files are left behind.
+        let files = std::fs::read_dir(store.path()).unwrap().count();
+        assert_eq!(files, 1);
+    }
+
+    #[tokio::test]
+    async fn test_refuse_corrupt_or_foreign_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StatusStore::new(dir.path().to_path_buf());
+
+        std::fs::write(dir.path().join("corrupt.a.json"), "{\"format\": \"vdir").unwrap();
+        let err = store.load("corrupt", Side::A).await.unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::InvalidData));
+
+        std::fs::write(dir.path().join("foreign.a.json"), "{}").unwrap();
+        let err = store.load("foreign", Side::A).await.unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::InvalidData));
+
+        let future = r#"{"format":"vdirsyncer-status","version":999,"pair":"future","side":"a"}"#;
+        std::fs::write(dir.path().join("future.a.json"), future).unwrap();
+        let err = store.load("future", Side::A).await.unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::InvalidData));
+
+        // A file copied over from another pair.
+        store
+            .save("contacts", Side::A, &StorageState::empty())
+            .await
+            .unwrap();
+        std::fs::copy(
+            dir.path().join("contacts.a.json"),
+            dir.path().join("calendars.b.json"),
+        )
+        .unwrap();
+        let err = store.load("calendars", Side::B).await.unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::InvalidData));
+    }
+
+    #[tokio::test]
+    async fn test_failed_save_leaves_no_temporary_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StatusStore::new(dir.path().to_path_buf());
+
+        // A non-empty directory cannot be replaced by renaming a file over it.
+        std::fs::create_dir_all(dir.path().join("calendars.a.json/child")).unwrap();
+        store
+            .save("calendars", Side::A, &StorageState::empty())
+            .await
+            .unwrap_err();
+
+        let files = std::fs::read_dir(store.path()).unwrap().count();
+        assert_eq!(files, 1);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_pair_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StatusStore::new(dir.path().to_path_buf());
+
+        let err = store.load("../escape", Side::A).await.unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::InvalidInput));
+    }
+}
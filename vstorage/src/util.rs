@@ -3,29 +3,401 @@
 // SPDX-License-Identifier: EUPL-1.2
 
 //! Miscellaneous helpers.
+use std::collections::{HashMap, HashSet};
+
 use sha2::{Digest, Sha256};
 
-/// Return the SHA256 hash of a string.
+/// Return the SHA256 hash of a string, as uppercase hexadecimal.
+pub(crate) fn digest<S: AsRef<str>>(input: S) -> String {
+    format!("{:X}", Sha256::digest(input.as_ref()))
+}
+
+/// Return the SHA256 hash of a normalised icalendar or vcard component.
+///
+/// Servers frequently rewrite components without changing their meaning, so two equivalent
+/// components must return the same hash even if:
 ///
-/// This string is expected to be an icalendar or vcard component.
+/// - Lines are folded differently.
+/// - Properties, parameters or subcomponents appear in a different order.
+/// - `PRODID`, `DTSTAMP` or non-standard (`X-`) properties differ.
+/// - Timezones have different names (`TZID`), but identical definitions.
+///
+/// Malformed input is hashed on a best-effort basis.
 pub(crate) fn hash<S: AsRef<str>>(input: S) -> String {
-    // TODO: See (in vdirsyncer-py): hash_item and normalize_item
-    let mut hasher = Sha256::new();
-    for line in input.as_ref().split_inclusive("\r\n") {
-        // Hint: continuation lines always start with a space.
-        if line.starts_with("PRODID") {
-            // These get continuously mutated and result in noise when determining if two
-            // components are equivalent.
+    let root = Component::parse(input.as_ref());
+    let mut timezones = HashMap::new();
+    root.collect_timezones(&mut timezones);
+    digest(root.canonical(&timezones))
+}
+
+/// A component with its properties unfolded, used for normalisation.
+#[derive(Clone)]
+struct Component {
+    name: String,
+    properties: Vec<String>,
+    components: Vec<Component>,
+}
+
+impl Component {
+    fn new(name: String) -> Self {
+        Component {
+            name,
+            properties: Vec::new(),
+            components: Vec::new(),
+        }
+    }
+
+    /// Parse raw content into an unnamed root component.
+    fn parse(raw: &str) -> Self {
+        let mut stack = vec![Component::new(String::new())];
+        for line in content_lines(raw) {
+            let line = unfold(line);
+            match split_line(&line) {
+                Some((name, _, value)) if name.eq_ignore_ascii_case("BEGIN") => {
+                    stack.push(Component::new(value.to_ascii_uppercase()));
+                }
+                Some((name, _, _)) if name.eq_ignore_ascii_case("END") && stack.len() > 1 => {
+                    let component = stack.pop().expect("stack has more than one component");
+                    stack
+                        .last_mut()
+                        .expect("root component is never popped")
+                        .components
+                        .push(component);
+                }
+                _ if line.is_empty() => {}
+                _ => stack
+                    .last_mut()
+                    .expect("root component is never popped")
+                    .properties
+                    .push(line),
+            }
+        }
+        // Close any unterminated components.
+        while stack.len() > 1 {
+            let component = stack.pop().expect("stack has more than one component");
+            stack
+                .last_mut()
+                .expect("root component is never popped")
+                .components
+                .push(component);
+        }
+        stack.pop().expect("root component is never popped")
+    }
+
+    /// Maps the `TZID` of each `VTIMEZONE` to a fingerprint of its definition.
+    fn collect_timezones(&self, timezones: &mut HashMap<String, String>) {
+        for component in &self.components {
+            if component.name != "VTIMEZONE" {
+                component.collect_timezones(timezones);
+                continue;
+            }
+            let is_tzid =
+                |p: &String| split_line(p).is_some_and(|(n, ..)| n.eq_ignore_ascii_case("TZID"));
+            let Some(tzid) = component.properties.iter().find(|p| is_tzid(p)) else {
+                continue;
+            };
+            let tzid = split_line(tzid).map_or("", |(_, _, value)| value);
+
+            let mut definition = component.clone();
+            definition.properties.retain(|p| !is_tzid(p));
+            timezones.insert(
+                tzid.to_string(),
+                digest(definition.canonical(&HashMap::new())),
+            );
+        }
+    }
+
+    /// Returns a canonical representation of this component.
+    ///
+    /// `VTIMEZONE` components are omitted; references to them are replaced by a fingerprint of
+    /// their definition.
+    fn canonical(&self, timezones: &HashMap<String, String>) -> String {
+        let mut properties = self
+            .properties
+            .iter()
+            .filter_map(|p| normalise_property(p, timezones))
+            .collect::<Vec<_>>();
+        properties.sort_unstable();
+        let mut components = self
+            .components
+            .iter()
+            .filter(|c| c.name != "VTIMEZONE")
+            .map(|c| c.canonical(timezones))
+            .collect::<Vec<_>>();
+        components.sort_unstable();
+
+        let mut result = String::new();
+        if !self.name.is_empty() {
+            result.push_str(&format!("BEGIN:{}\r\n", self.name));
+        }
+        for property in properties {
+            result.push_str(&property);
+            result.push_str("\r\n");
+        }
+        for component in components {
+            result.push_str(&component);
+        }
+        if !self.name.is_empty() {
+            result.push_str(&format!("END:{}\r\n", self.name));
+        }
+        result
+    }
+}
+
+/// Normalises an unfolded property, or returns `None` if it should be ignored for hashing.
+fn normalise_property(line: &str, timezones: &HashMap<String, String>) -> Option<String> {
+    let Some((name, params, value)) = split_line(line) else {
+        return Some(line.to_string());
+    };
+    let name = name.to_ascii_uppercase();
+    // These get continuously mutated and result in noise when determining if two
+    // components are equivalent.
+    if name == "PRODID" || name == "DTSTAMP" || name.starts_with("X-") {
+        return None;
+    }
+
+    let mut params = split_params(params)
+        .map(|param| {
+            let (key, val) = param.split_once('=').unwrap_or((param, ""));
+            let key = key.to_ascii_uppercase();
+            let val = match timezones.get(val.trim_matches('"')) {
+                Some(fingerprint) if key == "TZID" => fingerprint.as_str(),
+                _ => val,
+            };
+            format!(";{key}={val}")
+        })
+        .collect::<Vec<_>>();
+    params.sort_unstable();
+
+    Some(format!("{name}{}:{value}", params.concat()))
+}
+
+/// Iterates over parameters (as returned by [`split_line`]), ignoring quoted semicolons.
+fn split_params(params: &str) -> impl Iterator<Item = &str> {
+    let mut quoted = false;
+    params
+        .split(move |c| {
+            if c == '"' {
+                quoted = !quoted;
+            }
+            c == ';' && !quoted
+        })
+        .filter(|p| !p.is_empty())
+}
+
+/// Maximum length of a content line, in octets, excluding the line break.
+///
+/// See: https://www.rfc-editor.org/rfc/rfc5545#section-3.1
+const MAX_LINE_LENGTH: usize = 75;
+
+/// Iterates over the content lines of a component.
+///
+/// Each returned slice includes any continuation lines and the trailing line break (if any).
+pub(crate) fn content_lines(raw: &str) -> impl Iterator<Item = &str> {
+    let mut rest = raw;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let mut end = 0;
+        loop {
+            match rest[end..].find('\n') {
+                Some(pos) => end += pos + 1,
+                None => end = rest.len(),
+            }
+            if !rest[end..].starts_with([' ', '\t']) {
+                break;
+            }
+        }
+        let (line, remainder) = rest.split_at(end);
+        rest = remainder;
+        Some(line)
+    })
+}
+
+/// Unfolds a content line, removing line breaks and the whitespace of continuation lines.
+pub(crate) fn unfold(line: &str) -> String {
+    let mut unfolded = String::with_capacity(line.len());
+    for (i, physical) in line.split_inclusive('\n').enumerate() {
+        let physical = physical.trim_end_matches(['\r', '\n']);
+        if i == 0 {
+            unfolded.push_str(physical);
+        } else {
+            // The first character of a continuation line is either a space or a tab.
+            unfolded.push_str(&physical[1..]);
+        }
+    }
+    unfolded
+}
+
+/// Splits an unfolded content line into its name, its parameters and its value.
+///
+/// Parameters are returned as a single string, including their leading semicolon. Returns `None`
+/// if the line has no value.
+pub(crate) fn split_line(line: &str) -> Option<(&str, &str, &str)> {
+    let mut quoted = false;
+    let separator = line.char_indices().find_map(|(i, c)| match c {
+        '"' => {
+            quoted = !quoted;
+            None
+        }
+        ':' if !quoted => Some(i),
+        _ => None,
+    })?;
+    let (head, value) = (&line[..separator], &line[separator + 1..]);
+    let name_end = head.find(';').unwrap_or(head.len());
+    Some((&head[..name_end], &head[name_end..], value))
+}
+
+/// Folds a content line so that no physical line exceeds the maximum line length.
+///
+/// The returned line does not include a trailing line break.
+pub(crate) fn fold(line: &str, line_break: &str) -> String {
+    let mut folded = String::with_capacity(line.len());
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > MAX_LINE_LENGTH {
+            folded.push_str(line_break);
+            folded.push(' ');
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded
+}
+
+/// Returns the value of the first property with the given name.
+///
+/// Parameters are ignored and the name is matched case-insensitively.
+pub(crate) fn property_value(raw: &str, name: &str) -> Option<String> {
+    content_lines(raw).find_map(|line| {
+        let line = unfold(line);
+        match split_line(&line) {
+            Some((found, _, value)) if found.eq_ignore_ascii_case(name) => Some(value.to_string()),
+            _ => None,
+        }
+    })
+}
+
+/// Returns the values of all `TZID` parameters in a component, including its subcomponents.
+pub(crate) fn referenced_timezones(raw: &str) -> HashSet<String> {
+    let mut tzids = HashSet::new();
+    for line in content_lines(raw) {
+        let line = unfold(line);
+        let Some((_, params, _)) = split_line(&line) else {
             continue;
+        };
+        for param in split_params(params) {
+            match param.split_once('=') {
+                Some((key, value)) if key.eq_ignore_ascii_case("TZID") => {
+                    tzids.insert(value.trim_matches('"').to_string());
+                }
+                _ => {}
+            }
         }
-        hasher.update(line);
     }
-    format!("{:X}", hasher.finalize())
+    tzids
+}
+
+/// Returns a copy of a component with its `UID` replaced.
+///
+/// Only `UID` properties immediately inside a component named in `components` are considered.
+/// Those with a value of `old_uid` are replaced with `new_uid` (dropping any parameters). If
+/// `old_uid` is `None`, a `UID` is added to any such component which lacks one.
+pub(crate) fn replace_uid(
+    raw: &str,
+    components: &[&str],
+    old_uid: Option<&str>,
+    new_uid: &str,
+) -> String {
+    let mut result = String::with_capacity(raw.len() + new_uid.len());
+    // Names of the components being traversed, and whether they've been seen to have a UID.
+    let mut stack = Vec::<(String, bool)>::new();
+
+    for line in content_lines(raw) {
+        let line_break = if line.ends_with("\r\n") || !line.ends_with('\n') {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        let unfolded = unfold(line);
+        let Some((name, _, value)) = split_line(&unfolded) else {
+            result.push_str(line);
+            continue;
+        };
+        let in_target = stack.last().is_some_and(|(component, _)| {
+            components.iter().any(|c| c.eq_ignore_ascii_case(component))
+        });
+
+        if name.eq_ignore_ascii_case("BEGIN") {
+            stack.push((value.to_ascii_uppercase(), false));
+        } else if name.eq_ignore_ascii_case("END") {
+            if let Some((_, has_uid)) = stack.pop() {
+                if in_target && old_uid.is_none() && !has_uid {
+                    result.push_str(&fold(&format!("UID:{new_uid}"), line_break));
+                    result.push_str(line_break);
+                }
+            }
+        } else if in_target && name.eq_ignore_ascii_case("UID") {
+            if let Some((_, has_uid)) = stack.last_mut() {
+                *has_uid = true;
+            }
+            if old_uid == Some(value) {
+                result.push_str(&fold(&format!("UID:{new_uid}"), line_break));
+                if line.ends_with('\n') {
+                    result.push_str(line_break);
+                }
+                continue;
+            }
+        }
+        result.push_str(line);
+    }
+
+    result
+}
+
+/// Whether `text` matches a wildcard `pattern`.
+///
+/// In patterns, `*` matches any sequence of characters (including none), and `?` matches any
+/// single character. All other characters match themselves.
+pub(crate) fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*` seen, and the position in `text` that it was matched against.
+    let mut star = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the last `*` consume one more character and try again.
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
 }
 
 #[cfg(test)]
 mod test {
-    use crate::util::hash;
+    use crate::util::{
+        fold, hash, property_value, referenced_timezones, replace_uid, wildcard_match,
+    };
 
     #[test]
     fn compare_hashing_with_and_without_prodid() {
@@ -55,4 +427,118 @@ mod test {
 
         assert_eq!(hash(without_prodid), hash(with_prodid));
     }
+
+    #[test]
+    fn test_property_value() {
+        let raw = [
+            "BEGIN:VCARD",
+            "uid;VALUE=TEXT;X-NOTE=\"a:b\":hello",
+            " there",
+            "END:VCARD",
+        ]
+        .join("\r\n");
+        assert_eq!(property_value(&raw, "UID").as_deref(), Some("hellothere"));
+        assert_eq!(property_value(&raw, "REV"), None);
+    }
+
+    #[test]
+    fn test_referenced_timezones() {
+        let raw = [
+            "BEGIN:VEVENT",
+            "DTSTART;TZID=Europe/Rome:19970714T170000",
+            "DTEND;VALUE=DATE-TIME;tzid=\"America/Argentina/Buenos_A",
+            " ires\":19970715T035959",
+            "SUMMARY:TZID=Not/A/Parameter",
+            "END:VEVENT",
+        ]
+        .join("\r\n");
+        let mut tzids = referenced_timezones(&raw).into_iter().collect::<Vec<_>>();
+        tzids.sort();
+        assert_eq!(tzids, ["America/Argentina/Buenos_Aires", "Europe/Rome"]);
+    }
+
+    #[test]
+    fn test_fold() {
+        let long = format!("UID:{}", "x".repeat(100));
+        let folded = fold(&long, "\r\n");
+        let lines = folded.split("\r\n").collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].len(), 75);
+        assert!(lines[1].starts_with(' '));
+        assert_eq!(folded.replace("\r\n ", ""), long);
+    }
+
+    #[test]
+    fn test_replace_uid_adds_missing() {
+        let raw = ["BEGIN:VCARD", "FN:Someone", "END:VCARD", ""].join("\r\n");
+        let replaced = replace_uid(&raw, &["VCARD"], None, "new");
+        let expected = ["BEGIN:VCARD", "FN:Someone", "UID:new", "END:VCARD", ""].join("\r\n");
+        assert_eq!(replaced, expected);
+    }
+
+    #[test]
+    fn test_hash_normalisation() {
+        let original = [
+            "BEGIN:VCALENDAR",
+            "PRODID:client",
+            "BEGIN:VTIMEZONE",
+            "TZID:Europe/Berlin",
+            "BEGIN:STANDARD",
+            "DTSTART:19701025T030000",
+            "TZOFFSETFROM:+0200",
+            "TZOFFSETTO:+0100",
+            "END:STANDARD",
+            "END:VTIMEZONE",
+            "BEGIN:VEVENT",
+            "UID:11bb6bed-c29b-4999-a627-12dee35f8395",
+            "DTSTAMP:20230101T000000Z",
+            "DTSTART;TZID=Europe/Berlin:20230714T170000",
+            "SUMMARY:Bastille Day Party",
+            "END:VEVENT",
+            "END:VCALENDAR",
+        ]
+        .join("\r\n");
+        let rewritten = [
+            "BEGIN:VCALENDAR",
+            "PRODID:server",
+            "BEGIN:VEVENT",
+            "SUMMARY:Bastille Day",
+            "  Party",
+            "X-SERVER-ID:123",
+            "DTSTART;TZID=\"Berlin\":20230714T170000",
+            "UID:11bb6bed-c29b-4999-a627-12dee35f8395",
+            "DTSTAMP:20230606T000000Z",
+            "END:VEVENT",
+            "BEGIN:VTIMEZONE",
+            "TZID:Berlin",
+            "BEGIN:STANDARD",
+            "TZOFFSETTO:+0100",
+            "DTSTART:19701025T030000",
+            "TZOFFSETFROM:+0200",
+            "END:STANDARD",
+            "END:VTIMEZONE",
+            "END:VCALENDAR",
+        ]
+        .join("\r\n");
+        assert_eq!(hash(&original), hash(rewritten));
+
+        // Changes to the timezone definition are not ignored.
+        let other_timezone = original.replace("TZOFFSETTO:+0100", "TZOFFSETTO:+0000");
+        assert_ne!(hash(&original), hash(other_timezone));
+        let other_summary = original.replace("Bastille", "Other");
+        assert_ne!(hash(&original), hash(other_summary));
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*.ics", "event.ics"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("draft-?.ics", "draft-1.ics"));
+        assert!(wildcard_match("*draft*", "my-draft-event.ics"));
+        assert!(wildcard_match("a*b*c", "aXbYbZc"));
+        assert!(!wildcard_match("*.ics", "event.vcf"));
+        assert!(!wildcard_match("draft-?.ics", "draft-10.ics"));
+        assert!(!wildcard_match("a*b", "aXbY"));
+        assert!(!wildcard_match("", "a"));
+    }
 }
@@ -7,16 +7,28 @@
 //! Webcal is a de-facto standard, and is basically a single icalendar file hosted via http(s).
 //!
 //! See the [Webcal wikipedia page](https://en.wikipedia.org/wiki/Webcal).
+//!
+//! The remote resource is downloaded and parsed once, and later requests are conditional (using
+//! `If-None-Match` or `If-Modified-Since`). If the server replies that it has not been modified,
+//! the previously parsed items are reused.
 #![allow(clippy::module_name_repetitions)]
 
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
 use async_trait::async_trait;
-use http::{uri::Scheme, StatusCode, Uri};
-use hyper::{client::HttpConnector, Client};
+use http::{
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    uri::Scheme,
+    HeaderValue, Request, Response, StatusCode, Uri,
+};
+use hyper::{client::HttpConnector, Body, Client};
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 
 use crate::{
     base::{CalendarProperty, Collection, Definition, IcsItem, Item, ItemRef, Storage},
     simple_component::Component,
+    util::digest,
     Error, ErrorKind, Etag, Href, Result,
 };
 
@@ -31,6 +43,7 @@ use crate::{
 pub struct WebCalStorage {
     definition: WebCalDefinition,
     http_client: Client<HttpsConnector<HttpConnector>>,
+    resource: CachedResource<Feed>,
 }
 
 /// Definition for a [`WebCalStorage`].
@@ -48,33 +61,43 @@ impl Definition<IcsItem> for WebCalDefinition {
     ///
     /// Unlike other [`Storage`] implementations, this one allows only a single collection.
     async fn storage(self) -> Result<Box<dyn Storage<IcsItem>>> {
-        let proto = match &self.url.scheme().map(Scheme::as_str) {
-            Some("http") => HttpsConnectorBuilder::new()
-                .with_native_roots()
-                .https_or_http()
-                .enable_http1()
-                .build(),
-            Some("https") => HttpsConnectorBuilder::new()
-                .with_native_roots()
-                .https_only()
-                .enable_http1()
-                .build(),
-            // TODO: support webcal and webcals
-            Some(_) => {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    "URL scheme must be http or https",
-                ));
-            }
-            None => todo!(),
-        };
+        let http_client = http_client(&self.url)?;
         Ok(Box::from(WebCalStorage {
             definition: self,
-            http_client: Client::builder().build(proto),
+            http_client,
+            resource: CachedResource::new(),
         }))
     }
 }
 
+/// Returns an HTTP client for fetching the given URL.
+///
+/// # Errors
+///
+/// If the URL's scheme is not `http` or `https`.
+pub(crate) fn http_client(url: &Uri) -> Result<Client<HttpsConnector<HttpConnector>>> {
+    let proto = match url.scheme().map(Scheme::as_str) {
+        Some("http") => HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build(),
+        Some("https") => HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_only()
+            .enable_http1()
+            .build(),
+        // TODO: support webcal and webcals
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "URL scheme must be http or https",
+            ));
+        }
+    };
+    Ok(Client::builder().build(proto))
+}
+
 #[async_trait]
 impl Storage<IcsItem> for WebCalStorage {
     /// Checks that the remove resource exists and whether it looks like an icalendar resource.
@@ -128,27 +151,17 @@ impl Storage<IcsItem> for WebCalStorage {
 
     /// Enumerates items in this collection.
     ///
-    /// Note that, due to the nature of webcal, the whole collection needs to be retrieved. If some
-    /// items need to be read as well, it is generally best to use
-    /// [`WebCalStorage::get_all_items`] instead.
+    /// Note that, due to the nature of webcal, the whole collection needs to be retrieved (unless
+    /// it has not changed since it was last retrieved).
     async fn list_items(&self, _collection: &Collection) -> Result<Vec<ItemRef>> {
-        let raw = fetch_raw(&self.http_client, &self.definition.url).await?;
-
-        // TODO: it would be best if the parser could operate on a stream, although that might
-        //       complicate inlining VTIMEZONEs that are at the end.
-        let refs = Component::parse(&raw)
-            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
-            .into_split_collection()
-            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+        let refs = self
+            .feed()
+            .await?
+            .items
             .iter()
-            .map(|c| {
-                let item = IcsItem::from(c.to_string());
-                let hash = item.hash();
-
-                ItemRef {
-                    href: item.ident(),
-                    etag: hash.into(),
-                }
+            .map(|(href, _, etag)| ItemRef {
+                href: href.clone(),
+                etag: etag.clone(),
             })
             .collect();
 
@@ -157,85 +170,44 @@ impl Storage<IcsItem> for WebCalStorage {
 
     /// Returns a single item from the collection.
     ///
-    /// Note that, due to the nature of webcal, the whole collection needs to be retrieved. It is
-    /// strongly recommended to use [`WebCalStorage::get_all_items`] instead.
+    /// Note that, due to the nature of webcal, the whole collection needs to be retrieved (unless
+    /// it has not changed since it was last retrieved).
     async fn get_item(&self, _collection: &Collection, href: &str) -> Result<(IcsItem, Etag)> {
-        let raw = fetch_raw(&self.http_client, &self.definition.url).await?;
-
-        // TODO: it would be best if the parser could operate on a stream, although that might
-        //       complicate inlining VTIMEZONEs that are at the end.
-        let item = Component::parse(&raw)
-            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
-            .into_split_collection()
-            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+        self.feed()
+            .await?
+            .items
             .iter()
-            .find_map(|c| {
-                let item = IcsItem::from(c.to_string());
-                if item.ident() == href {
-                    Some(item)
-                } else {
-                    None
-                }
-            })
-            .ok_or_else(|| Error::from(ErrorKind::DoesNotExist))?;
-
-        let hash = item.hash();
-        Ok((item, hash.into()))
+            .find(|(h, _, _)| h == href)
+            .map(|(_, item, etag)| (item.clone(), etag.clone()))
+            .ok_or_else(|| Error::from(ErrorKind::DoesNotExist))
     }
 
     /// Returns multiple items from the collection.
     ///
-    /// Note that, due to the nature of webcal, the whole collection needs to be retrieved. It is
-    /// generally best to use [`WebCalStorage::get_all_items`] instead.
+    /// Note that, due to the nature of webcal, the whole collection needs to be retrieved (unless
+    /// it has not changed since it was last retrieved).
     async fn get_many_items(
         &self,
         _collection: &Collection,
         hrefs: &[&str],
     ) -> Result<Vec<(Href, IcsItem, Etag)>> {
-        let raw = fetch_raw(&self.http_client, &self.definition.url).await?;
-
-        // TODO: it would be best if the parser could operate on a stream, although that might
-        //       complicate inlining VTIMEZONEs that are at the end.
-
-        Component::parse(&raw)
-            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
-            .into_split_collection()
-            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+        let items = self
+            .feed()
+            .await?
+            .items
             .iter()
-            .filter_map(|c| {
-                let item = IcsItem::from(c.to_string());
-                if hrefs.contains(&(item.ident().as_ref())) {
-                    let hash = item.hash();
-                    Some(Ok((item.ident(), item, hash.into())))
-                } else {
-                    None
-                }
-            })
-            .collect()
+            .filter(|(href, _, _)| hrefs.contains(&href.as_str()))
+            .cloned()
+            .collect();
+
+        Ok(items)
     }
 
     /// Fetch all items in the collection.
     ///
     /// Performs a single HTTP(s) request to fetch all items.
     async fn get_all_items(&self, _collection: &Collection) -> Result<Vec<(Href, IcsItem, Etag)>> {
-        let raw = fetch_raw(&self.http_client, &self.definition.url).await?;
-
-        // TODO: it would be best if the parser could operate on a stream, although that might
-        //       complicate inlining VTIMEZONEs that are at the end.
-        let components = Component::parse(&raw)
-            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
-            .into_split_collection()
-            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
-
-        components
-            .iter()
-            .map(|c| {
-                let item = IcsItem::from(c.to_string());
-                let hash = item.hash();
-
-                Ok((item.ident(), item, hash.into()))
-            })
-            .collect()
+        Ok(self.feed().await?.items.clone())
     }
 
     /// Unsupported for this storage type.
@@ -273,17 +245,23 @@ impl Storage<IcsItem> for WebCalStorage {
         ))
     }
 
-    /// Unsupported for this storage type.
+    /// Returns properties of the remote calendar.
+    ///
+    /// These are taken from the non-standard (but widely used) `X-WR-CALNAME`, `X-WR-CALDESC` and
+    /// `X-APPLE-CALENDAR-COLOR` properties.
     async fn get_collection_property(
         &self,
         _collection: &Collection,
-        _: CalendarProperty,
+        property: CalendarProperty,
     ) -> Result<Option<String>> {
-        // TODO: return None?
-        Err(Error::new(
-            ErrorKind::Unsupported,
-            "getting metadata via webcal is not supported",
-        ))
+        let value = self
+            .feed()
+            .await?
+            .properties
+            .iter()
+            .find_map(|(p, value)| (*p == property).then(|| value.clone()));
+
+        Ok(value)
     }
 
     async fn delete_item(&mut self, _: &Collection, _: &str, _: &Etag) -> Result<()> {
@@ -302,33 +280,195 @@ impl Storage<IcsItem> for WebCalStorage {
     }
 }
 
+impl WebCalStorage {
+    /// Returns the parsed remote resource, fetching it only if it has changed.
+    async fn feed(&self) -> Result<Arc<Feed>> {
+        self.resource
+            .fetch(&self.http_client, &self.definition.url, Feed::parse)
+            .await
+    }
+}
+
+/// The parsed content of a webcal resource.
+struct Feed {
+    /// Individual items, along with their `href` and etag.
+    items: Vec<(Href, IcsItem, Etag)>,
+    properties: Vec<(CalendarProperty, String)>,
+}
+
+impl Feed {
+    /// Splits a calendar into individual items.
+    ///
+    /// Items without a UID are given one based on their hash, so their identity is stable as long
+    /// as they are not modified. Copies of such items share that UID, so only the first is kept.
+    fn parse(raw: &str) -> Result<Feed> {
+        // TODO: it would be best if the parser could operate on a stream, although that might
+        //       complicate inlining VTIMEZONEs that are at the end.
+        let calendar = Component::parse(raw).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        let properties = [
+            (CalendarProperty::DisplayName, "X-WR-CALNAME"),
+            (CalendarProperty::Description, "X-WR-CALDESC"),
+            (CalendarProperty::Colour, "X-APPLE-CALENDAR-COLOR"),
+        ]
+        .into_iter()
+        .filter_map(|(property, name)| Some((property, calendar.property(name)?)))
+        .collect();
+
+        let mut hrefs = HashSet::new();
+        let items = calendar
+            .into_split_collection()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+            .iter()
+            .filter_map(|c| {
+                let mut item = IcsItem::from(c.to_string());
+                if item.uid().is_none() {
+                    item = item.with_uid(&item.hash());
+                }
+                if !hrefs.insert(item.ident()) {
+                    return None;
+                }
+                // Unlike the hash, this changes with any edit (e.g.: to an `X-` property).
+                let etag = Etag::from(digest(item.as_str()));
+                Some((item.ident(), item, etag))
+            })
+            .collect();
+
+        Ok(Feed { items, properties })
+    }
+}
+
+/// A remote resource which is only parsed again when it has changed.
+///
+/// If the server returned an `ETag` or `Last-Modified` header, further requests are conditional,
+/// and a `304 Not Modified` response reuses the previous result. Otherwise, the resource is
+/// downloaded again, but only parsed if its content has changed.
+pub(crate) struct CachedResource<T> {
+    cached: Mutex<Option<Cached<T>>>,
+}
+
+struct Cached<T> {
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    /// Digest of the response body.
+    digest: String,
+    value: Arc<T>,
+}
+
+impl<T> CachedResource<T> {
+    pub(crate) fn new() -> Self {
+        CachedResource {
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Fetch the resource at `url` and parse it, unless it has not changed since the last call.
+    ///
+    /// # Errors
+    ///
+    /// If the request fails, the server returns an error, or if `parse` fails.
+    pub(crate) async fn fetch<F>(
+        &self,
+        client: &Client<HttpsConnector<HttpConnector>>,
+        url: &Uri,
+        parse: F,
+    ) -> Result<Arc<T>>
+    where
+        F: FnOnce(&str) -> Result<T>,
+    {
+        let mut request = Request::get(url.clone());
+        if let Some(cached) = self.lock().as_ref() {
+            if let Some(etag) = &cached.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let request = request
+            .body(Body::empty())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+        let response = client
+            .request(request)
+            .await
+            .map_err(|e| Error::new(ErrorKind::Io, e))?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return self
+                .lock()
+                .as_ref()
+                .map(|c| c.value.clone())
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        "unexpected response: 304 Not Modified",
+                    )
+                });
+        }
+        check_status(response.status())?;
+
+        let etag = response.headers().get(ETAG).cloned();
+        let last_modified = response.headers().get(LAST_MODIFIED).cloned();
+        let raw = read_body(response).await?;
+        let digest = digest(&raw);
+
+        let mut cached = self.lock();
+        let value = match cached.take() {
+            Some(previous) if previous.digest == digest => previous.value,
+            _ => Arc::new(parse(&raw)?),
+        };
+        *cached = Some(Cached {
+            etag,
+            last_modified,
+            digest,
+            value: value.clone(),
+        });
+
+        Ok(value)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<Cached<T>>> {
+        // A panic while holding the lock cannot leave data in an inconsistent state.
+        self.cached.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
 /// Helper method to fetch a URL and return its body as a String.
 ///
 /// Be warned! This swallows headers (including `Etag`!).
 #[inline]
-async fn fetch_raw(client: &Client<HttpsConnector<HttpConnector>>, url: &Uri) -> Result<String> {
+pub(crate) async fn fetch_raw(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    url: &Uri,
+) -> Result<String> {
     let response = client
         // TODO: upstream should impl IntoURL for &Uri
         .get(url.clone())
         .await
         .map_err(|e| Error::new(ErrorKind::Io, e))?;
 
-    match response.status() {
-        StatusCode::NOT_FOUND | StatusCode::GONE => {
-            return Err(Error::new(
-                ErrorKind::DoesNotExist,
-                "The remote resource does not exist.",
-            ))
-        }
-        StatusCode::OK => {}
-        code => {
-            return Err(Error::new(
-                ErrorKind::Io,
-                format!("request returned {code}"),
-            ))
-        }
+    check_status(response.status())?;
+    read_body(response).await
+}
+
+/// Returns an error if `status` is not `200 OK`.
+fn check_status(status: StatusCode) -> Result<()> {
+    match status {
+        StatusCode::NOT_FOUND | StatusCode::GONE => Err(Error::new(
+            ErrorKind::DoesNotExist,
+            "The remote resource does not exist.",
+        )),
+        StatusCode::OK => Ok(()),
+        code => Err(Error::new(
+            ErrorKind::Io,
+            format!("request returned {code}"),
+        )),
     }
+}
 
+/// Reads the body of a response as a String.
+async fn read_body(response: Response<Body>) -> Result<String> {
     // TODO: handle non-UTF-8 data (e.g.: Content-Type/charset).
     hyper::body::to_bytes(response)
         .await
@@ -339,9 +479,176 @@ async fn fetch_raw(client: &Client<HttpsConnector<HttpConnector>>, url: &Uri) ->
 
 #[cfg(test)]
 mod test {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
     use http::Uri;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::base::{Definition, Item};
+
+    /// Serves `body` with the given `etag`, replying to matching conditional requests with `304`.
+    ///
+    /// Returns the URL being served and the amount of full and `304` responses sent.
+    async fn serve(body: &'static str, etag: &'static str) -> (Uri, Arc<[AtomicUsize; 2]>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Uri::try_from(format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let counts = Arc::new([AtomicUsize::new(0), AtomicUsize::new(0)]);
+
+        let served = counts.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut buf = [0; 1024];
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8(request).unwrap().to_lowercase();
+
+                let response = if request.contains(&format!("if-none-match: {etag}")) {
+                    served[1].fetch_add(1, Ordering::SeqCst);
+                    format!(
+                        "HTTP/1.1 304 Not Modified\r\nETag: {etag}\r\nConnection: close\r\n\r\n"
+                    )
+                } else {
+                    served[0].fetch_add(1, Ordering::SeqCst);
+                    format!(
+                        "HTTP/1.1 200 OK\r\nETag: {etag}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                };
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (url, counts)
+    }
+
+    #[tokio::test]
+    async fn test_conditional_requests() {
+        use crate::webcal::WebCalDefinition;
+
+        let calendar = concat!(
+            "BEGIN:VCALENDAR\r\n",
+            "BEGIN:VEVENT\r\n",
+            "UID:first\r\n",
+            "END:VEVENT\r\n",
+            "BEGIN:VEVENT\r\n",
+            "UID:second\r\n",
+            "END:VEVENT\r\n",
+            "END:VCALENDAR\r\n",
+        );
+        let (url, counts) = serve(calendar, "\"v1\"").await;
+
+        let storage = WebCalDefinition {
+            url,
+            collection_name: "feed".to_string(),
+        }
+        .storage()
+        .await
+        .unwrap();
+        let collection = storage.open_collection("feed").unwrap();
+
+        let refs = storage.list_items(&collection).await.unwrap();
+        assert_eq!(refs.len(), 2);
+        let (item, etag) = storage.get_item(&collection, "second").await.unwrap();
+        assert_eq!(item.uid().as_deref(), Some("second"));
+        assert!(refs.iter().any(|r| r.href == "second" && r.etag == etag));
+        let all = storage.get_all_items(&collection).await.unwrap();
+        assert_eq!(all.len(), 2);
+
+        assert_eq!(counts[0].load(Ordering::SeqCst), 1);
+        assert_eq!(counts[1].load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_properties_and_items_without_uid() {
+        use crate::{base::CalendarProperty, webcal::WebCalDefinition};
+
+        let calendar = concat!(
+            "BEGIN:VCALENDAR\r\n",
+            "VERSION:2.0\r\n",
+            "X-WR-CALNAME:Holidays\r\n",
+            "X-APPLE-CALENDAR-COLOR:#FF0000\r\n",
+            "BEGIN:VEVENT\r\n",
+            "SUMMARY:Without UID\r\n",
+            "END:VEVENT\r\n",
+            "END:VCALENDAR\r\n",
+        );
+        let (url, _) = serve(calendar, "\"v1\"").await;
+
+        let storage = WebCalDefinition {
+            url,
+            collection_name: "feed".to_string(),
+        }
+        .storage()
+        .await
+        .unwrap();
+        let collection = storage.open_collection("feed").unwrap();
 
-    use crate::base::Definition;
+        let name = storage
+            .get_collection_property(&collection, CalendarProperty::DisplayName)
+            .await
+            .unwrap();
+        assert_eq!(name.as_deref(), Some("Holidays"));
+        let colour = storage
+            .get_collection_property(&collection, CalendarProperty::Colour)
+            .await
+            .unwrap();
+        assert_eq!(colour.as_deref(), Some("#FF0000"));
+        let description = storage
+            .get_collection_property(&collection, CalendarProperty::Description)
+            .await
+            .unwrap();
+        assert_eq!(description, None);
+
+        let items = storage.get_all_items(&collection).await.unwrap();
+        assert_eq!(items.len(), 1);
+        let (href, item, _) = &items[0];
+        assert_eq!(item.uid().as_ref(), Some(href));
+        assert!(item
+            .as_str()
+            .starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+
+        // Listing items returns the same synthetic UID.
+        let refs = storage.list_items(&collection).await.unwrap();
+        assert_eq!(&refs[0].href, href);
+    }
+
+    #[test]
+    fn test_etag_changes_with_any_edit() {
+        use crate::webcal::Feed;
+
+        let calendar = |extra: &str| {
+            format!(
+                "BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\nUID:first\r\n{extra}END:VEVENT\r\nEND:VCALENDAR\r\n"
+            )
+        };
+        let before = Feed::parse(&calendar("")).unwrap().items.remove(0);
+        let after = Feed::parse(&calendar("X-ALT-DESC:Edited\r\n"))
+            .unwrap()
+            .items
+            .remove(0);
+        assert_eq!(before.1.hash(), after.1.hash());
+        assert_ne!(before.2, after.2);
+    }
+
+    #[test]
+    fn test_copies_of_items_without_uid() {
+        use crate::webcal::Feed;
+
+        let event = "BEGIN:VEVENT\r\nSUMMARY:Without UID\r\nEND:VEVENT\r\n";
+        let calendar = format!("BEGIN:VCALENDAR\r\n{event}{event}END:VCALENDAR\r\n");
+        let feed = Feed::parse(&calendar).unwrap();
+        assert_eq!(feed.items.len(), 1);
+    }
 
     // FIXME: only run this test with a dedicated flag for networked test.
     // FIXME: use a webcal link hosted by me.
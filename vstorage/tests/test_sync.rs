@@ -5,12 +5,14 @@
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use std::boxed::Box;
 use std::{fmt::Write, path::PathBuf};
-use vstorage::sync::plan::Plan;
+use vstorage::sync::plan::{Action, ConflictResolution, DeletionGuard, Plan};
 use vstorage::sync::CollectionMapping;
 use vstorage::{
-    base::{Definition, IcsItem, Storage},
+    base::{Definition, IcsItem, Item, Storage},
     filesystem::FilesystemDefinition,
+    memory::MemoryStorage,
     sync::{StoragePair, StorageState},
+    ErrorKind,
 };
 
 fn random_string(len: usize) -> String {
@@ -145,3 +147,606 @@ async fn test_sync_simple_case() {
     std::fs::remove_dir_all(populated_path).unwrap();
     std::fs::remove_dir_all(empty_path).unwrap();
 }
+
+/// Replace the contents of a file, ensuring that it gets a new inode (and hence a new etag).
+fn replace_file(path: &std::path::Path, data: &str) {
+    let temp = path.with_extension("tmp");
+    std::fs::write(&temp, data).unwrap();
+    std::fs::rename(temp, path).unwrap();
+}
+
+/// Returns the path of the single item in a collection.
+fn single_item(collection: PathBuf) -> PathBuf {
+    let mut entries = std::fs::read_dir(collection)
+        .unwrap()
+        .map(|r| r.unwrap().path())
+        .collect::<Vec<_>>();
+    assert_eq!(entries.len(), 1);
+    entries.pop().unwrap()
+}
+
+#[tokio::test]
+async fn test_sync_conflict_resolution() {
+    let dir_a = tempfile::tempdir().unwrap();
+    let dir_b = tempfile::tempdir().unwrap();
+    let mut storage_a = create_empty_storage(dir_a.path().join("storage")).await;
+    let mut storage_b = create_empty_storage(dir_b.path().join("storage")).await;
+
+    let collection = storage_a.create_collection("calendar").await.unwrap();
+    let original = minimal_icalendar("Original").unwrap();
+    storage_a
+        .add_item(&collection, &original.clone().into())
+        .await
+        .unwrap();
+
+    let mappings = vec![CollectionMapping::Direct("calendar".to_string())];
+    let empty_state = StorageState::empty();
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut *storage_a,
+        &mut *storage_b,
+        &empty_state,
+        &empty_state,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let result = Plan::for_storage_pair(&pair).execute(&mut pair).await;
+    assert!(result.synchronised_ok());
+    let (state_a, state_b) = (result.state_a, result.state_b);
+
+    // Modify the item on both sides.
+    let path_a = single_item(dir_a.path().join("storage/calendar"));
+    let path_b = single_item(dir_b.path().join("storage/calendar"));
+    replace_file(&path_a, &original.replace("Original", "Changed in A"));
+    replace_file(&path_b, &original.replace("Original", "Changed in B"));
+
+    // By default, conflicts are reported and nothing is modified.
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut *storage_a,
+        &mut *storage_b,
+        &state_a,
+        &state_b,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let result = Plan::for_storage_pair(&pair).execute(&mut pair).await;
+    assert_eq!(result.errors.len(), 1);
+    assert!(std::fs::read_to_string(&path_b)
+        .unwrap()
+        .contains("Changed in B"));
+
+    // The conflict is reported again on the next run.
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut *storage_a,
+        &mut *storage_b,
+        &result.state_a,
+        &result.state_b,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let result = Plan::for_storage_pair(&pair).execute(&mut pair).await;
+    assert_eq!(result.errors.len(), 1);
+
+    // Keeping A overwrites B.
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut *storage_a,
+        &mut *storage_b,
+        &state_a,
+        &state_b,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let result = Plan::for_storage_pair(&pair)
+        .with_conflict_resolution(ConflictResolution::KeepA)
+        .execute(&mut pair)
+        .await;
+    assert!(result.synchronised_ok());
+    assert!(std::fs::read_to_string(&path_b)
+        .unwrap()
+        .contains("Changed in A"));
+}
+
+#[tokio::test]
+async fn test_sync_conflict_with_identical_content() {
+    let dir_a = tempfile::tempdir().unwrap();
+    let dir_b = tempfile::tempdir().unwrap();
+    let mut storage_a = create_empty_storage(dir_a.path().join("storage")).await;
+    let mut storage_b = create_empty_storage(dir_b.path().join("storage")).await;
+
+    // Both sides have the same item, but there is no previous state.
+    let item = minimal_icalendar("Same on both sides").unwrap();
+    let collection_a = storage_a.create_collection("calendar").await.unwrap();
+    storage_a
+        .add_item(&collection_a, &item.clone().into())
+        .await
+        .unwrap();
+    let collection_b = storage_b.create_collection("calendar").await.unwrap();
+    storage_b
+        .add_item(&collection_b, &item.into())
+        .await
+        .unwrap();
+
+    let mappings = vec![CollectionMapping::Direct("calendar".to_string())];
+    let empty_state = StorageState::empty();
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut *storage_a,
+        &mut *storage_b,
+        &empty_state,
+        &empty_state,
+        mappings,
+    )
+    .await
+    .unwrap();
+    let result = Plan::for_storage_pair(&pair).execute(&mut pair).await;
+    assert!(result.synchronised_ok());
+}
+
+#[tokio::test]
+async fn test_plan_operations() {
+    let populated_dir = tempfile::tempdir().unwrap();
+    let empty_dir = tempfile::tempdir().unwrap();
+    let mut populated = create_populated_storage(populated_dir.path().join("storage")).await;
+    let mut empty = create_empty_storage(empty_dir.path().join("storage")).await;
+
+    let mappings = vec![CollectionMapping::Direct("first-calendar".to_string())];
+    let empty_state = StorageState::empty();
+    let pair = StoragePair::<IcsItem>::new(
+        &mut *populated,
+        &mut *empty,
+        &empty_state,
+        &empty_state,
+        mappings,
+    )
+    .await
+    .unwrap();
+    let plan = Plan::for_storage_pair(&pair);
+
+    let operations = plan.operations().collect::<Vec<_>>();
+    assert_eq!(operations.len(), 3);
+    // The collection is created before items are copied into it.
+    assert_eq!(operations[0].uid, None);
+    assert_eq!(operations[0].action, &Action::CopyToB);
+    for operation in &operations[1..] {
+        assert!(operation.uid.is_some());
+        assert_eq!(operation.action, &Action::CopyToB);
+        assert!(operation.source.is_some());
+        assert_eq!(operation.destination, None);
+    }
+
+    let rendered = plan.to_string();
+    assert!(rendered.starts_with("first-calendar: create collection in B"));
+    assert_eq!(rendered.lines().count(), 3);
+
+    let json = serde_json::to_value(&plan).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 3);
+    assert_eq!(json[0]["collection"], "first-calendar");
+    assert_eq!(json[0]["action"], "CopyToB");
+}
+
+#[tokio::test]
+async fn test_deletion_guard() {
+    let dir_a = tempfile::tempdir().unwrap();
+    let dir_b = tempfile::tempdir().unwrap();
+    let mut storage_a = create_populated_storage(dir_a.path().join("storage")).await;
+    let mut storage_b = create_empty_storage(dir_b.path().join("storage")).await;
+
+    let mappings = vec![CollectionMapping::Direct("first-calendar".to_string())];
+    let empty_state = StorageState::empty();
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut *storage_a,
+        &mut *storage_b,
+        &empty_state,
+        &empty_state,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let result = Plan::for_storage_pair(&pair).execute(&mut pair).await;
+    assert!(result.synchronised_ok());
+    let (state_a, state_b) = (result.state_a, result.state_b);
+
+    // The collection in A appears empty (e.g.: due to a glitch).
+    let collection_a = dir_a.path().join("storage/first-calendar");
+    let backup = dir_a.path().join("backup");
+    std::fs::rename(&collection_a, &backup).unwrap();
+    std::fs::create_dir(&collection_a).unwrap();
+
+    let collection_b = dir_b.path().join("storage/first-calendar");
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut *storage_a,
+        &mut *storage_b,
+        &state_a,
+        &state_b,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let result = Plan::for_storage_pair(&pair).execute(&mut pair).await;
+    assert_eq!(result.errors.len(), 1);
+    assert_eq!(result.errors[0].kind(), Some(ErrorKind::TooManyDeletions));
+    assert_eq!(std::fs::read_dir(&collection_b).unwrap().count(), 2);
+    // The previous state is retained, so the next run detects the same deletions.
+    let state_a = result.state_a;
+    let state_b = result.state_b;
+
+    // Deleting half the items exceeds a stricter ratio too.
+    std::fs::remove_dir(&collection_a).unwrap();
+    std::fs::rename(&backup, &collection_a).unwrap();
+    let first = std::fs::read_dir(&collection_a).unwrap().next().unwrap();
+    std::fs::remove_file(first.unwrap().path()).unwrap();
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut *storage_a,
+        &mut *storage_b,
+        &state_a,
+        &state_b,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let plan = Plan::for_storage_pair(&pair);
+    let result = plan
+        .with_deletion_guard(DeletionGuard {
+            max_deletion_ratio: Some(0.4),
+            min_items: 0,
+            abort_when_emptied: true,
+        })
+        .execute(&mut pair)
+        .await;
+    assert_eq!(result.errors.len(), 1);
+    assert_eq!(std::fs::read_dir(&collection_b).unwrap().count(), 2);
+
+    // Explicitly overriding the guard proceeds with deletions.
+    let result = Plan::for_storage_pair(&pair)
+        .with_deletion_guard(DeletionGuard::disabled())
+        .execute(&mut pair)
+        .await;
+    assert!(result.synchronised_ok());
+    assert_eq!(std::fs::read_dir(&collection_b).unwrap().count(), 1);
+}
+
+#[tokio::test]
+async fn test_sync_duplicate_uids() {
+    let dir_a = tempfile::tempdir().unwrap();
+    let dir_b = tempfile::tempdir().unwrap();
+    let mut storage_a = create_empty_storage(dir_a.path().join("storage")).await;
+    let mut storage_b = create_empty_storage(dir_b.path().join("storage")).await;
+
+    let collection = storage_a.create_collection("calendar").await.unwrap();
+    let original = minimal_icalendar("Original").unwrap();
+    storage_a
+        .add_item(&collection, &original.clone().into())
+        .await
+        .unwrap();
+    let unique = minimal_icalendar("Unique").unwrap();
+    storage_a
+        .add_item(&collection, &unique.into())
+        .await
+        .unwrap();
+    // A copy of the first item, with the same UID but a different name.
+    std::fs::write(
+        dir_a.path().join("storage/calendar/copy.ics"),
+        original.replace("Original", "Copy"),
+    )
+    .unwrap();
+
+    let mappings = vec![CollectionMapping::Direct("calendar".to_string())];
+    let empty_state = StorageState::empty();
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut *storage_a,
+        &mut *storage_b,
+        &empty_state,
+        &empty_state,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let result = Plan::for_storage_pair(&pair).execute(&mut pair).await;
+    assert_eq!(result.errors.len(), 1);
+    assert_eq!(result.errors[0].kind(), Some(ErrorKind::DuplicateUid));
+    let collection_b = dir_b.path().join("storage/calendar");
+    assert_eq!(std::fs::read_dir(&collection_b).unwrap().count(), 1);
+
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut *storage_a,
+        &mut *storage_b,
+        &result.state_a,
+        &result.state_b,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(pair.repair_duplicate_uids().await.unwrap(), 1);
+    let result = Plan::for_storage_pair(&pair).execute(&mut pair).await;
+    assert!(result.synchronised_ok());
+    assert_eq!(std::fs::read_dir(&collection_b).unwrap().count(), 3);
+}
+
+#[tokio::test]
+async fn test_sync_duplicate_uids_with_edit_in_other_storage() {
+    let mut storage_a = MemoryStorage::<IcsItem>::new();
+    let mut storage_b = MemoryStorage::<IcsItem>::new();
+
+    let calendar = storage_a.create_collection("calendar").await.unwrap();
+    let original = minimal_icalendar("Original").unwrap();
+    storage_a
+        .add_item(&calendar, &original.clone().into())
+        .await
+        .unwrap();
+
+    let mappings = vec![CollectionMapping::Direct("calendar".to_string())];
+    let empty_state = StorageState::empty();
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut storage_a,
+        &mut storage_b,
+        &empty_state,
+        &empty_state,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let result = Plan::for_storage_pair(&pair).execute(&mut pair).await;
+    assert!(result.synchronised_ok());
+
+    // A copy with the same UID is created in A, and the item is edited in B.
+    let copy = storage_a
+        .add_item(&calendar, &original.replace("Original", "Copy").into())
+        .await
+        .unwrap();
+    let calendar_b = storage_b.open_collection("calendar").unwrap();
+    let (href_b, item, etag) = storage_b
+        .get_all_items(&calendar_b)
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let edited = item.as_str().replace("Original", "Edited");
+    storage_b
+        .update_item(&calendar_b, &href_b, &etag, &edited.into())
+        .await
+        .unwrap();
+
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut storage_a,
+        &mut storage_b,
+        &result.state_a,
+        &result.state_b,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let result = Plan::for_storage_pair(&pair).execute(&mut pair).await;
+    assert_eq!(result.errors.len(), 1);
+    assert_eq!(result.errors[0].kind(), Some(ErrorKind::DuplicateUid));
+
+    // Once the duplicate is removed, the edit is synchronised.
+    storage_a
+        .delete_item(&calendar, &copy.href, &copy.etag)
+        .await
+        .unwrap();
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut storage_a,
+        &mut storage_b,
+        &result.state_a,
+        &result.state_b,
+        mappings,
+    )
+    .await
+    .unwrap();
+    let result = Plan::for_storage_pair(&pair).execute(&mut pair).await;
+    assert!(result.synchronised_ok());
+    let items_a = storage_a.get_all_items(&calendar).await.unwrap();
+    assert_eq!(items_a.len(), 1);
+    assert!(items_a[0].1.as_str().contains("Edited"));
+}
+
+#[tokio::test]
+async fn test_sync_item_without_uid() {
+    let dir_a = tempfile::tempdir().unwrap();
+    let dir_b = tempfile::tempdir().unwrap();
+    let mut storage_a = create_empty_storage(dir_a.path().join("storage")).await;
+    let mut storage_b = create_empty_storage(dir_b.path().join("storage")).await;
+
+    storage_a.create_collection("calendar").await.unwrap();
+    let without_uid = minimal_icalendar("Original")
+        .unwrap()
+        .lines()
+        .filter(|line| !line.starts_with("UID:"))
+        .map(|line| format!("{line}\n"))
+        .collect::<String>();
+    let path_a = dir_a.path().join("storage/calendar/item.ics");
+    std::fs::write(&path_a, &without_uid).unwrap();
+
+    let mappings = vec![CollectionMapping::Direct("calendar".to_string())];
+    let empty_state = StorageState::empty();
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut *storage_a,
+        &mut *storage_b,
+        &empty_state,
+        &empty_state,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let result = Plan::for_storage_pair(&pair).execute(&mut pair).await;
+    assert!(result.synchronised_ok());
+
+    // Editing the item updates the copy instead of deleting it and creating a new one.
+    replace_file(&path_a, &without_uid.replace("Original", "Edited"));
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut *storage_a,
+        &mut *storage_b,
+        &result.state_a,
+        &result.state_b,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let plan = Plan::for_storage_pair(&pair);
+    let operations = plan.operations().collect::<Vec<_>>();
+    assert_eq!(operations.len(), 1);
+    assert_eq!(operations[0].action, &Action::CopyToB);
+    assert!(operations[0].destination.is_some());
+
+    let result = plan.execute(&mut pair).await;
+    assert!(result.synchronised_ok());
+    let path_b = single_item(dir_b.path().join("storage/calendar"));
+    assert!(std::fs::read_to_string(path_b).unwrap().contains("Edited"));
+}
+
+#[tokio::test]
+async fn test_sync_memory_storage_faults() {
+    let mut storage_a = MemoryStorage::<IcsItem>::new();
+    let mut storage_b = MemoryStorage::<IcsItem>::new();
+    let handle_b = storage_b.clone();
+
+    let calendar = storage_a.create_collection("calendar").await.unwrap();
+    for summary in ["One", "Two"] {
+        let item = minimal_icalendar(summary).unwrap().into();
+        storage_a.add_item(&calendar, &item).await.unwrap();
+    }
+
+    // The first write creates the collection; adding the first item fails.
+    handle_b.fail_write(2);
+    let mappings = vec![CollectionMapping::Direct("calendar".to_string())];
+    let empty_state = StorageState::empty();
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut storage_a,
+        &mut storage_b,
+        &empty_state,
+        &empty_state,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let result = Plan::for_storage_pair(&pair).execute(&mut pair).await;
+    assert_eq!(result.errors.len(), 1);
+    assert_eq!(result.errors[0].kind(), Some(ErrorKind::Io));
+    let calendar_b = storage_b.open_collection("calendar").unwrap();
+    assert_eq!(storage_b.list_items(&calendar_b).await.unwrap().len(), 1);
+
+    // The next run copies the missing item.
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut storage_a,
+        &mut storage_b,
+        &result.state_a,
+        &result.state_b,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let plan = Plan::for_storage_pair(&pair);
+    assert_eq!(plan.operations().count(), 1);
+    let result = plan.execute(&mut pair).await;
+    assert!(result.synchronised_ok());
+    let items_b = storage_b.list_items(&calendar_b).await.unwrap();
+    assert_eq!(items_b.len(), 2);
+
+    // A failed update is retried on the next run.
+    let (href_a, item, etag) = storage_a
+        .get_all_items(&calendar)
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let edited = item.as_str().replace("SUMMARY:", "SUMMARY:Edited ");
+    storage_a
+        .update_item(&calendar, &href_a, &etag, &edited.into())
+        .await
+        .unwrap();
+    handle_b.fail_write(1);
+    let mut state = (result.state_a, result.state_b);
+    for expected_errors in [1, 0] {
+        let mut pair = StoragePair::<IcsItem>::new(
+            &mut storage_a,
+            &mut storage_b,
+            &state.0,
+            &state.1,
+            mappings.clone(),
+        )
+        .await
+        .unwrap();
+        let plan = Plan::for_storage_pair(&pair);
+        assert_eq!(plan.operations().count(), 1);
+        let result = plan.execute(&mut pair).await;
+        assert_eq!(result.errors.len(), expected_errors);
+        state = (result.state_a, result.state_b);
+    }
+    let edited_b = items_b
+        .iter()
+        .filter_map(|item_ref| handle_b.raw_item("calendar", &item_ref.href))
+        .filter(|raw| raw.contains("Edited"))
+        .count();
+    assert_eq!(edited_b, 1);
+}
+
+#[tokio::test]
+async fn test_sync_item_moved_out_of_window() {
+    let mut storage_a = MemoryStorage::<IcsItem>::new();
+    let mut storage_b = MemoryStorage::<IcsItem>::new();
+    let handle_b = storage_b.clone();
+
+    let calendar = storage_a.create_collection("calendar").await.unwrap();
+    for summary in ["One", "Two"] {
+        let item = minimal_icalendar(summary).unwrap().into();
+        storage_a.add_item(&calendar, &item).await.unwrap();
+    }
+
+    let mappings = vec![CollectionMapping::Direct("calendar".to_string())];
+    let empty_state = StorageState::empty();
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut storage_a,
+        &mut storage_b,
+        &empty_state,
+        &empty_state,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let result = Plan::for_storage_pair(&pair).execute(&mut pair).await;
+    assert!(result.synchronised_ok());
+
+    // One item moves out of the window of storage B, and a new item is created there.
+    let calendar_b = storage_b.open_collection("calendar").unwrap();
+    let items_b = storage_b.list_items(&calendar_b).await.unwrap();
+    assert_eq!(items_b.len(), 2);
+    handle_b.exclude_item("calendar", &items_b[0].href);
+    let item = minimal_icalendar("Three").unwrap().into();
+    storage_b.add_item(&calendar_b, &item).await.unwrap();
+
+    // Only the new item is copied; the one outside the window is not deleted.
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut storage_a,
+        &mut storage_b,
+        &result.state_a,
+        &result.state_b,
+        mappings.clone(),
+    )
+    .await
+    .unwrap();
+    let plan = Plan::for_storage_pair(&pair);
+    assert_eq!(plan.operations().count(), 1);
+    let result = plan.execute(&mut pair).await;
+    assert!(result.synchronised_ok());
+    assert_eq!(storage_a.list_items(&calendar).await.unwrap().len(), 3);
+
+    // Deleting the item outside the window is still propagated.
+    storage_b
+        .delete_item(&calendar_b, &items_b[0].href, &items_b[0].etag)
+        .await
+        .unwrap();
+    let mut pair = StoragePair::<IcsItem>::new(
+        &mut storage_a,
+        &mut storage_b,
+        &result.state_a,
+        &result.state_b,
+        mappings,
+    )
+    .await
+    .unwrap();
+    let result = Plan::for_storage_pair(&pair).execute(&mut pair).await;
+    assert!(result.synchronised_ok());
+    assert_eq!(storage_a.list_items(&calendar).await.unwrap().len(), 2);
+}